@@ -0,0 +1,201 @@
+//! Command line tool applying the instrumentations of this crate to a wasm module.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
+use fvm_wasm_instrument::{
+    gas_metering::{self, CostTable, GasCounter, InjectOptions, InjectionReport, InstructionCost},
+    stack_limiter,
+};
+use serde::Serialize;
+
+/// Instrument a wasm module with gas metering and/or a stack height limit.
+#[derive(Parser)]
+#[clap(version)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Inject gas metering.
+    Gas {
+        #[clap(flatten)]
+        io: IoArgs,
+        #[clap(flatten)]
+        gas: GasArgs,
+    },
+    /// Inject the stack height limiter.
+    Stack {
+        #[clap(flatten)]
+        io: IoArgs,
+        #[clap(flatten)]
+        stack: StackArgs,
+    },
+    /// Inject gas metering, then the stack height limiter.
+    All {
+        #[clap(flatten)]
+        io: IoArgs,
+        #[clap(flatten)]
+        gas: GasArgs,
+        #[clap(flatten)]
+        stack: StackArgs,
+    },
+}
+
+#[derive(Args)]
+struct IoArgs {
+    /// Module to instrument, in the binary or the text format.
+    input: PathBuf,
+    /// Where to write the instrumented module, the standard output if omitted.
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+    /// Write the instrumented module in the text format.
+    #[clap(long)]
+    wat: bool,
+    /// Write a JSON report of the instrumentation to this file, `-` for the standard output,
+    /// which requires `--output` to write the module to a file.
+    #[clap(long)]
+    report: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct GasArgs {
+    /// Cost table in JSON, or TOML if the file ends with `.toml`. Every instruction costs 1
+    /// if omitted.
+    #[clap(short, long)]
+    rules: Option<PathBuf>,
+    /// Module from which the gas counter global, or the host function, is imported.
+    #[clap(long, default_value = "env")]
+    gas_module: String,
+    /// Define the gas counter in the module and export it under this name instead of importing
+    /// it.
+    #[clap(long, conflicts_with = "gas-module")]
+    export_gas_counter: Option<String>,
+    /// Charge gas by calling the host function with this name, imported from the gas module,
+    /// instead of keeping a gas counter in the module.
+    #[clap(long, conflicts_with = "export-gas-counter")]
+    gas_host_function: Option<String>,
+}
+
+#[derive(Args)]
+struct StackArgs {
+    /// Maximal stack height, in the units of the default stack cost model.
+    #[clap(short = 'l', long)]
+    stack_limit: u32,
+}
+
+/// Report written with `--report`.
+#[derive(Serialize)]
+struct Report {
+    input_size: usize,
+    output_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gas: Option<InjectionReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stack_limit: Option<u32>,
+}
+
+fn main() -> Result<()> {
+    let (io, gas, stack) = match Cli::parse().command {
+        Command::Gas { io, gas } => (io, Some(gas), None),
+        Command::Stack { io, stack } => (io, None, Some(stack)),
+        Command::All { io, gas, stack } => (io, Some(gas), Some(stack)),
+    };
+    let report_to_stdout = io.report.as_deref().map_or(false, is_stdout);
+    if report_to_stdout && io.output.as_deref().map_or(true, is_stdout) {
+        bail!("--report - requires --output to write the module to a file");
+    }
+
+    let input =
+        wat::parse_file(&io.input).with_context(|| format!("reading {}", io.input.display()))?;
+    let mut output = input.clone();
+
+    let gas_report = match gas {
+        Some(gas) => {
+            let rules = load_rules(gas.rules.as_deref())?;
+            let gas_counter = match (gas.export_gas_counter, gas.gas_host_function) {
+                (Some(name), _) => GasCounter::Export { name },
+                (None, Some(name)) => GasCounter::HostFunction {
+                    module: gas.gas_module,
+                    name,
+                },
+                (None, None) => GasCounter::Import {
+                    module: gas.gas_module,
+                },
+            };
+            let (instrumented, report) = gas_metering::inject_with_options(
+                &output,
+                &rules,
+                &InjectOptions::new(gas_counter),
+            )
+            .context("injecting gas metering")?;
+            output = instrumented;
+            Some(report)
+        }
+        None => None,
+    };
+    if let Some(stack) = &stack {
+        output = stack_limiter::inject(&output, stack.stack_limit)
+            .context("injecting the stack height limiter")?;
+    }
+
+    if let Some(path) = &io.report {
+        let report = Report {
+            input_size: input.len(),
+            output_size: output.len(),
+            gas: gas_report,
+            stack_limit: stack.map(|stack| stack.stack_limit),
+        };
+        let mut json = serde_json::to_vec_pretty(&report)?;
+        json.push(b'\n');
+        write_output(Some(path), &json)?;
+    }
+
+    if io.wat {
+        let text = wasmprinter::print_bytes(&output)?;
+        write_output(io.output.as_deref(), text.as_bytes())
+    } else {
+        write_output(io.output.as_deref(), &output)
+    }
+}
+
+fn load_rules(path: Option<&Path>) -> Result<CostTable> {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            return Ok(CostTable {
+                default: Some(InstructionCost::Fixed(1)),
+                ..CostTable::default()
+            })
+        }
+    };
+    let source = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let table = if path.extension().map_or(false, |ext| ext == "toml") {
+        CostTable::from_toml(&source)
+    } else {
+        CostTable::from_json(&source)
+    };
+    table.with_context(|| format!("parsing {}", path.display()))
+}
+
+/// Whether `path` stands for the standard output.
+fn is_stdout(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Writes `data` to the file at `path`, or to the standard output if `path` is `None` or `-`.
+fn write_output(path: Option<&Path>, data: &[u8]) -> Result<()> {
+    match path {
+        Some(path) if !is_stdout(path) => {
+            fs::write(path, data).with_context(|| format!("writing {}", path.display()))
+        }
+        _ => Ok(io::stdout().lock().write_all(data)?),
+    }
+}
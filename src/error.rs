@@ -0,0 +1,97 @@
+//! Error type shared by the instrumentation passes.
+
+use alloc::string::String;
+use core::fmt;
+
+/// Reasons why a module could not be instrumented.
+///
+/// The variants describe problems with the input module (or with the rules it is instrumented
+/// with) precisely enough to be reported back to the author of the module. Failures which are
+/// not covered by a dedicated variant, e.g. a malformed binary, are reported as
+/// [`InstrumentError::Other`].
+#[derive(Debug)]
+pub enum InstrumentError {
+    /// The gas rules refused to price an instruction used by the module.
+    ForbiddenInstruction {
+        /// Index of the offending function in the function index space (imports included).
+        func_index: u32,
+        /// Index of the offending instruction among the instructions of the function body, not
+        /// a byte offset.
+        op_index: usize,
+        /// The offending instruction.
+        op: String,
+        /// The error returned by the rules, e.g. why the instruction is forbidden.
+        reason: anyhow::Error,
+    },
+    /// An active data or element segment has an offset expression that can't be handled.
+    NonConstSegmentOffset,
+    /// The module already imports a global under the name of the gas counter.
+    DuplicateGasGlobal,
+    /// The global used as gas counter is not mutable.
+    ImmutableGasGlobal,
+    /// The module already has an export under the name requested for the gas counter.
+    DuplicateExport(String),
+    /// The module uses a wasm proposal that the instrumentation does not support.
+    UnsupportedProposal(String),
+    /// The static cost of a metered block in the given function, including the cost of its
+    /// charge, doesn't fit into an `i64`, or into a `u64` with an unsigned gas counter.
+    CostOverflow {
+        /// Index of the offending function in the function index space (imports included).
+        func_index: u32,
+    },
+    /// Any other error, e.g. a malformed module.
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for InstrumentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstrumentError::ForbiddenInstruction {
+                func_index,
+                op_index,
+                op,
+                reason,
+            } => write!(
+                f,
+                "instruction {} at index {} of function {} is forbidden: {}",
+                op, op_index, func_index, reason
+            ),
+            InstrumentError::NonConstSegmentOffset => {
+                write!(f, "segment offset must be a constant expression")
+            }
+            InstrumentError::DuplicateGasGlobal => write!(f, "expected 1 gas global"),
+            InstrumentError::ImmutableGasGlobal => write!(f, "gas global must be mutable"),
+            InstrumentError::DuplicateExport(name) => {
+                write!(f, "the module already exports {:?}", name)
+            }
+            InstrumentError::UnsupportedProposal(what) => {
+                write!(f, "unsupported proposal: {}", what)
+            }
+            InstrumentError::CostOverflow { func_index } => {
+                write!(f, "cost overflow in function {}", func_index)
+            }
+            InstrumentError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for InstrumentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InstrumentError::ForbiddenInstruction { reason, .. } => Some(reason.as_ref()),
+            InstrumentError::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for InstrumentError {
+    /// Internally errors are passed around as [`anyhow::Error`]. Recover the typed error if
+    /// there is one, otherwise wrap the error as [`InstrumentError::Other`].
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<InstrumentError>() {
+            Ok(e) => e,
+            Err(e) => InstrumentError::Other(e),
+        }
+    }
+}
@@ -0,0 +1,609 @@
+//! A data driven implementation of [`Rules`].
+
+use super::{operand_cost::OperandCost, InstructionCost, Rules};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+};
+use anyhow::{anyhow, Result};
+use wasmparser::Operator;
+
+/// Coarse grouping of operators, used to price many operators with a single [`CostTable`] entry.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum OperatorFamily {
+    /// Structured control flow, branches, calls and returns, including the exception handling
+    /// and tail call proposals.
+    Control,
+    /// `drop` and `select`.
+    Parametric,
+    /// Local and global variable accesses.
+    Variable,
+    /// Scalar memory loads.
+    Load,
+    /// Scalar memory stores.
+    Store,
+    /// `memory.size` and `memory.grow`.
+    Memory,
+    /// `memory.init`, `memory.copy`, `memory.fill` and `data.drop`.
+    BulkMemory,
+    /// Table instructions and `elem.drop`.
+    Table,
+    /// `ref.null`, `ref.is_null` and `ref.func`.
+    Reference,
+    /// Scalar constants.
+    Const,
+    /// Scalar arithmetic, comparisons and conversions.
+    Numeric,
+    /// Instructions of the SIMD and relaxed SIMD proposals.
+    Simd,
+    /// Instructions of the threads proposal.
+    Atomic,
+}
+
+impl OperatorFamily {
+    /// Returns the family the given operator belongs to.
+    pub fn of(op: &Operator) -> Self {
+        use wasmparser::Operator::*;
+
+        let (name, proposal) = operator_info(op);
+        match proposal {
+            "simd" | "relaxed_simd" => return OperatorFamily::Simd,
+            "threads" => return OperatorFamily::Atomic,
+            _ => {}
+        }
+
+        match op {
+            Unreachable
+            | Nop
+            | Block { .. }
+            | Loop { .. }
+            | If { .. }
+            | Else
+            | Try { .. }
+            | Catch { .. }
+            | Throw { .. }
+            | Rethrow { .. }
+            | End
+            | Br { .. }
+            | BrIf { .. }
+            | BrTable { .. }
+            | Return
+            | Call { .. }
+            | CallIndirect { .. }
+            | ReturnCall { .. }
+            | ReturnCallIndirect { .. }
+            | Delegate { .. }
+            | CatchAll => OperatorFamily::Control,
+            Drop | Select | TypedSelect { .. } => OperatorFamily::Parametric,
+            LocalGet { .. }
+            | LocalSet { .. }
+            | LocalTee { .. }
+            | GlobalGet { .. }
+            | GlobalSet { .. } => OperatorFamily::Variable,
+            MemorySize { .. } | MemoryGrow { .. } => OperatorFamily::Memory,
+            MemoryInit { .. } | MemoryCopy { .. } | MemoryFill { .. } | DataDrop { .. } => {
+                OperatorFamily::BulkMemory
+            }
+            TableInit { .. }
+            | TableCopy { .. }
+            | TableFill { .. }
+            | TableGet { .. }
+            | TableSet { .. }
+            | TableGrow { .. }
+            | TableSize { .. }
+            | ElemDrop { .. } => OperatorFamily::Table,
+            RefNull { .. } | RefIsNull | RefFunc { .. } => OperatorFamily::Reference,
+            I32Const { .. } | I64Const { .. } | F32Const { .. } | F64Const { .. } => {
+                OperatorFamily::Const
+            }
+            // SIMD and atomic memory accesses were handled above, what's left are the scalar ones.
+            _ if name.contains("_load") => OperatorFamily::Load,
+            _ if name.contains("_store") => OperatorFamily::Store,
+            _ => OperatorFamily::Numeric,
+        }
+    }
+
+    /// The name of the family as used in a [`CostTable`].
+    pub fn name(&self) -> &'static str {
+        match self {
+            OperatorFamily::Control => "control",
+            OperatorFamily::Parametric => "parametric",
+            OperatorFamily::Variable => "variable",
+            OperatorFamily::Load => "load",
+            OperatorFamily::Store => "store",
+            OperatorFamily::Memory => "memory",
+            OperatorFamily::BulkMemory => "bulk_memory",
+            OperatorFamily::Table => "table",
+            OperatorFamily::Reference => "reference",
+            OperatorFamily::Const => "const",
+            OperatorFamily::Numeric => "numeric",
+            OperatorFamily::Simd => "simd",
+            OperatorFamily::Atomic => "atomic",
+        }
+    }
+
+    const ALL: [OperatorFamily; 13] = [
+        OperatorFamily::Control,
+        OperatorFamily::Parametric,
+        OperatorFamily::Variable,
+        OperatorFamily::Load,
+        OperatorFamily::Store,
+        OperatorFamily::Memory,
+        OperatorFamily::BulkMemory,
+        OperatorFamily::Table,
+        OperatorFamily::Reference,
+        OperatorFamily::Const,
+        OperatorFamily::Numeric,
+        OperatorFamily::Simd,
+        OperatorFamily::Atomic,
+    ];
+}
+
+macro_rules! define_operator_info {
+    ($( @$proposal:ident $op:ident $({ $($arg:ident: $argty:ty),* })? => $visit:ident)*) => {
+        /// Returns the name of the operator (e.g. `i32_load8_s`) and the proposal it belongs to.
+        fn operator_info(op: &Operator) -> (&'static str, &'static str) {
+            match op {
+                $(
+                    Operator::$op { .. } => (
+                        stringify!($visit).trim_start_matches("visit_"),
+                        stringify!($proposal),
+                    ),
+                )*
+            }
+        }
+
+        /// Whether `name` is the name of an operator, as returned by `operator_info`.
+        fn is_operator_name(name: &str) -> bool {
+            $(stringify!($visit).trim_start_matches("visit_") == name)||*
+        }
+    };
+}
+
+wasmparser::for_each_operator!(define_operator_info);
+
+/// A price list implementing [`Rules`].
+///
+/// The cost of an operator is looked up in the following order:
+///
+/// 1. If the operator's name (e.g. `memory_grow`) or family (e.g. `simd`) is listed in
+///    `forbidden`, the operator is forbidden.
+/// 2. The entry for the operator's name in `opcodes`.
+/// 3. The entry for the operator's [`OperatorFamily`] in `families`.
+/// 4. `default`. If it isn't set, the operator is forbidden.
+///
+/// Operator names are the snake case names of the instructions with dots replaced by
+/// underscores, e.g. `i32_const` or `memory_copy`.
+///
+/// The calls to the functions listed in `imports` by module and name cost their entry on top of
+/// the cost of the `call` operator, see [`Rules::import_call_cost`].
+///
+/// With the `serde` feature enabled the table can be loaded from JSON or TOML, e.g.
+///
+/// ```toml
+/// version = 3
+/// default = 1
+/// forbidden = ["simd", "atomic"]
+/// gas_charge_cost = 10
+///
+/// [families]
+/// load = 4
+///
+/// [opcodes]
+/// memory_grow = [1, 8192]
+///
+/// [imports.vm]
+/// abort = 500
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct CostTable {
+    /// Version of the price list. It isn't interpreted by this crate.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub version: u32,
+    /// Cost of the operators which don't match any other entry.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub default: Option<InstructionCost>,
+    /// Costs by operator family name, see [`OperatorFamily::name`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub families: BTreeMap<String, InstructionCost>,
+    /// Costs by operator name. These take precedence over `families`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub opcodes: BTreeMap<String, InstructionCost>,
+    /// Names of the operators and operator families which are forbidden.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub forbidden: BTreeSet<String>,
+    /// See [`Rules::gas_charge_cost`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub gas_charge_cost: u64,
+    /// See [`Rules::linear_calc_cost`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub linear_calc_cost: u64,
+    /// Costs of calling imported functions by module and name, see [`Rules::import_call_cost`].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub imports: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl CostTable {
+    /// Checks that all the names used in `families`, `opcodes` and `forbidden` refer to existing
+    /// operators or operator families, so that a typo doesn't silently leave an operator unpriced.
+    ///
+    /// Also checks the costs against the bounds enforced by the injector, e.g. that the cost per
+    /// unit of a linear cost fits in 31 bits.
+    pub fn validate(&self) -> Result<()> {
+        let is_family_name = |name: &str| OperatorFamily::ALL.iter().any(|f| f.name() == name);
+
+        if let Some(name) = self.families.keys().find(|name| !is_family_name(name)) {
+            return Err(anyhow!("unknown operator family {}", name));
+        }
+        if let Some(name) = self.opcodes.keys().find(|name| !is_operator_name(name)) {
+            return Err(anyhow!("unknown operator {}", name));
+        }
+        if let Some(name) = self
+            .forbidden
+            .iter()
+            .find(|name| !is_operator_name(name) && !is_family_name(name))
+        {
+            return Err(anyhow!("unknown operator or operator family {}", name));
+        }
+
+        let costs = self
+            .default
+            .iter()
+            .map(|cost| ("default", cost))
+            .chain(
+                self.families
+                    .iter()
+                    .map(|(name, cost)| (name.as_str(), cost)),
+            )
+            .chain(
+                self.opcodes
+                    .iter()
+                    .map(|(name, cost)| (name.as_str(), cost)),
+            );
+        for (name, cost) in costs {
+            let (_, operand_cost) = OperandCost::split(cost.clone())
+                .map_err(|e| anyhow!("invalid cost of {}: {}", name, e))?;
+            let total_memory = operand_cost.map_or(false, |(cost, _)| cost.total_memory());
+            if total_memory && name != "memory_grow" {
+                return Err(anyhow!(
+                    "invalid cost of {}: only memory.grow can be priced by the total memory",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses and validates a table in JSON format.
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> Result<Self> {
+        let table: CostTable = serde_json::from_str(json)?;
+        table.validate()?;
+        Ok(table)
+    }
+
+    /// Parses and validates a table in TOML format.
+    #[cfg(feature = "serde")]
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        let table: CostTable = toml::from_str(toml)?;
+        table.validate()?;
+        Ok(table)
+    }
+}
+
+impl Rules for CostTable {
+    fn instruction_cost(&self, instruction: &Operator) -> Result<InstructionCost> {
+        let (name, _) = operator_info(instruction);
+        let family = OperatorFamily::of(instruction);
+
+        if self.forbidden.contains(name) || self.forbidden.contains(family.name()) {
+            return Err(anyhow!("{} is forbidden", name));
+        }
+
+        self.opcodes
+            .get(name)
+            .or_else(|| self.families.get(family.name()))
+            .or(self.default.as_ref())
+            .cloned()
+            .ok_or_else(|| anyhow!("{} is not priced", name))
+    }
+
+    fn gas_charge_cost(&self) -> u64 {
+        self.gas_charge_cost
+    }
+
+    fn linear_calc_cost(&self) -> u64 {
+        self.linear_calc_cost
+    }
+
+    fn import_call_cost(&self, module: &str, name: &str) -> u64 {
+        self.imports
+            .get(module)
+            .and_then(|names| names.get(name))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+    use std::num::NonZeroU32;
+    use wasmparser::MemArg;
+
+    fn memarg() -> MemArg {
+        MemArg {
+            align: 0,
+            max_align: 0,
+            offset: 0,
+            memory: 0,
+        }
+    }
+
+    fn table() -> CostTable {
+        CostTable {
+            version: 1,
+            default: Some(InstructionCost::Fixed(1)),
+            families: [("load".to_string(), InstructionCost::Fixed(4))]
+                .into_iter()
+                .collect(),
+            opcodes: [
+                ("i64_load".to_string(), InstructionCost::Fixed(5)),
+                (
+                    "memory_grow".to_string(),
+                    InstructionCost::Linear(1, NonZeroU32::new(100).unwrap()),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            forbidden: ["simd".to_string(), "memory_fill".to_string()]
+                .into_iter()
+                .collect(),
+            gas_charge_cost: 2,
+            linear_calc_cost: 3,
+            imports: [(
+                "vm".to_string(),
+                [("abort".to_string(), 500)].into_iter().collect(),
+            )]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    #[test]
+    fn lookup_order() {
+        let table = table();
+        let memarg = memarg();
+
+        assert_eq!(
+            table.instruction_cost(&Operator::I32Add).unwrap(),
+            InstructionCost::Fixed(1)
+        );
+        assert_eq!(
+            table
+                .instruction_cost(&Operator::I32Load { memarg })
+                .unwrap(),
+            InstructionCost::Fixed(4)
+        );
+        assert_eq!(
+            table
+                .instruction_cost(&Operator::I64Load { memarg })
+                .unwrap(),
+            InstructionCost::Fixed(5)
+        );
+        assert_eq!(
+            table
+                .instruction_cost(&Operator::MemoryGrow {
+                    mem: 0,
+                    mem_byte: 0
+                })
+                .unwrap(),
+            InstructionCost::Linear(1, NonZeroU32::new(100).unwrap())
+        );
+        assert!(table
+            .instruction_cost(&Operator::MemoryFill { mem: 0 })
+            .is_err());
+        assert!(table
+            .instruction_cost(&Operator::V128Load { memarg })
+            .is_err());
+
+        assert_eq!(table.import_call_cost("vm", "abort"), 500);
+        assert_eq!(table.import_call_cost("vm", "exit"), 0);
+        assert_eq!(table.import_call_cost("env", "abort"), 0);
+    }
+
+    #[test]
+    fn no_default() {
+        let table = CostTable {
+            default: None,
+            ..table()
+        };
+        assert!(table.instruction_cost(&Operator::I32Add).is_err());
+        assert_eq!(
+            table
+                .instruction_cost(&Operator::F32Load { memarg: memarg() })
+                .unwrap(),
+            InstructionCost::Fixed(4)
+        );
+    }
+
+    #[test]
+    fn families() {
+        assert_eq!(
+            OperatorFamily::of(&Operator::I32Const { value: 0 }),
+            OperatorFamily::Const
+        );
+        assert_eq!(
+            OperatorFamily::of(&Operator::I64AtomicLoad { memarg: memarg() }),
+            OperatorFamily::Atomic
+        );
+        assert_eq!(
+            OperatorFamily::of(&Operator::I32Store8 { memarg: memarg() }),
+            OperatorFamily::Store
+        );
+        assert_eq!(
+            OperatorFamily::of(&Operator::I32WrapI64),
+            OperatorFamily::Numeric
+        );
+        assert_eq!(
+            OperatorFamily::of(&Operator::DataDrop { data_index: 0 }),
+            OperatorFamily::BulkMemory
+        );
+    }
+
+    #[test]
+    fn validate_names() {
+        assert!(table().validate().is_ok());
+
+        let mut table = table();
+        table
+            .opcodes
+            .insert("i32_ad".to_string(), InstructionCost::Fixed(1));
+        assert!(table.validate().is_err());
+
+        let mut table = self::table();
+        table.forbidden.insert("vector".to_string());
+        assert!(table.validate().is_err());
+
+        let mut table = self::table();
+        table
+            .families
+            .insert("loads".to_string(), InstructionCost::Fixed(1));
+        assert!(table.validate().is_err());
+    }
+
+    #[test]
+    fn validate_costs() {
+        let linear = |cost_per| InstructionCost::Linear(1, NonZeroU32::new(cost_per).unwrap());
+
+        let mut table = table();
+        table
+            .opcodes
+            .insert("memory_copy".into(), linear(0x7fff_ffff));
+        assert!(table.validate().is_ok());
+        table
+            .opcodes
+            .insert("memory_copy".into(), linear(0x8000_0000));
+        assert!(table.validate().is_err());
+
+        let mut table = self::table();
+        table.default = Some(linear(u32::MAX));
+        assert!(table.validate().is_err());
+
+        let mut table = self::table();
+        table.opcodes.insert(
+            "memory_grow".into(),
+            InstructionCost::Piecewise {
+                base: 1,
+                segments: vec![(16, 10), (0, 1)],
+                total_memory: false,
+            },
+        );
+        assert!(table.validate().is_err());
+
+        // Only memory.grow can be priced by the total memory
+        let quadratic = InstructionCost::Quadratic {
+            base: 1,
+            linear: 0,
+            quadratic: 2,
+            total_memory: true,
+        };
+        let mut table = self::table();
+        table
+            .opcodes
+            .insert("memory_grow".into(), quadratic.clone());
+        assert!(table.validate().is_ok());
+        table.families.insert("memory".into(), quadratic);
+        assert!(table.validate().is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn from_json() {
+        let json = r#"{
+            "version": 1,
+            "default": 1,
+            "families": { "load": 4 },
+            "opcodes": {
+                "i64_load": 5,
+                "memory_grow": [1, 100]
+            },
+            "forbidden": ["simd", "memory_fill"],
+            "gas_charge_cost": 2,
+            "linear_calc_cost": 3,
+            "imports": { "vm": { "abort": 500 } }
+        }"#;
+        assert_eq!(CostTable::from_json(json).unwrap(), table());
+
+        let json = r#"{ "version": 1, "opcodes": { "call": [1, 100, 2] } }"#;
+        assert_eq!(
+            CostTable::from_json(json).unwrap().opcodes["call"],
+            InstructionCost::LinearOperand(1, NonZeroU32::new(100).unwrap(), 2)
+        );
+
+        let json = r#"{ "version": 1, "opcodes": {
+            "memory_grow": { "base": 1, "linear": 0, "quadratic": 2, "total_memory": true },
+            "memory_fill": { "base": 1, "segments": [[0, 1], [1024, 10]] }
+        } }"#;
+        let table = CostTable::from_json(json).unwrap();
+        assert_eq!(
+            table.opcodes["memory_grow"],
+            InstructionCost::Quadratic {
+                base: 1,
+                linear: 0,
+                quadratic: 2,
+                total_memory: true,
+            }
+        );
+        assert_eq!(
+            table.opcodes["memory_fill"],
+            InstructionCost::Piecewise {
+                base: 1,
+                segments: vec![(0, 1), (1024, 10)],
+                total_memory: false,
+            }
+        );
+
+        // A misspelled field isn't ignored
+        let json = r#"{ "version": 1, "opcodes": {
+            "memory_grow": { "base": 1, "linear": 0, "quadratic": 2, "totl_memory": true }
+        } }"#;
+        assert!(CostTable::from_json(json).is_err());
+        let json = r#"{ "version": 1, "opcodes": {
+            "memory_fill": { "base": 1, "segments": [[0, 1]], "total_memroy": true }
+        } }"#;
+        assert!(CostTable::from_json(json).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn from_toml() {
+        let toml = r#"
+            version = 1
+            default = 1
+            forbidden = ["simd", "memory_fill"]
+            gas_charge_cost = 2
+            linear_calc_cost = 3
+
+            [families]
+            load = 4
+
+            [opcodes]
+            i64_load = 5
+            memory_grow = [1, 100]
+
+            [imports.vm]
+            abort = 500
+        "#;
+        assert_eq!(CostTable::from_toml(toml).unwrap(), table());
+
+        assert!(CostTable::from_toml("[opcodes]\ni32_ad = 1").is_err());
+        assert!(CostTable::from_toml("defualt = 1").is_err());
+    }
+}
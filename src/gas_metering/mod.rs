@@ -4,24 +4,36 @@
 //! module into one that charges gas for code to be executed. See function documentation for usage
 //! and details.
 
-#[cfg(test)]
-pub mod validation;
-
-use crate::utils::{
-    copy_locals,
-    translator::{DefaultTranslator, Translator},
-    truncate_len_from_encoder, ModuleInfo,
+mod cost_table;
+mod operand_cost;
+mod superblock;
+mod upfront;
+mod validation;
+
+pub use cost_table::{CostTable, OperatorFamily};
+pub use validation::{verify, CostMismatch, PathCost};
+
+use crate::{
+    utils::{
+        copy_locals,
+        translator::{DefaultTranslator, Item, Translator},
+        truncate_len_from_encoder, ModuleInfo,
+    },
+    InstrumentError,
 };
-use alloc::{vec, vec::Vec};
+use alloc::{format, string::String, vec, vec::Vec};
 use anyhow::{anyhow, Result};
 use core::{cmp::min, mem};
+use operand_cost::{ChargeLocals, OperandCost};
 use std::num::NonZeroU32;
+use upfront::{Refund, RefundKind};
 use wasm_encoder::{
-    BlockType, ExportSection, Function, ImportSection, Instruction, SectionId, ValType,
+    BlockType, DataSection, ElementSection, EntityType, ExportKind, ExportSection, Function,
+    GlobalSection, ImportSection, Instruction, SectionId, ValType,
 };
 use wasmparser::{
-    CodeSectionReader, DataKind, DataSectionReader, ElementItem, ElementSectionReader,
-    ExportSectionReader, ExternalKind, FuncType, FunctionBody, FunctionSectionReader,
+    CodeSectionReader, DataKind, DataSectionReader, ElementKind, ElementSectionReader,
+    ExportSectionReader, FuncType, FunctionBody, FunctionSectionReader, GlobalSectionReader,
     ImportSectionReader, SectionReader, Type, TypeRef, TypeSectionReader,
 };
 
@@ -45,10 +57,138 @@ pub trait Rules {
     /// instructions cost of which can be statically determined (linearly priced
     /// ops proceded by a const). Added to gas_charge_cost on dynamic charges
     fn linear_calc_cost(&self) -> u64;
+
+    /// Returns the cost added to the cost of a `call` or `return_call` to the function imported
+    /// as `name` from `module`, e.g. to charge host functions when instrumenting the module.
+    fn import_call_cost(&self, _module: &str, _name: &str) -> u64 {
+        0
+    }
+}
+
+/// An interface that describes instruction costs depending on where the instructions are.
+///
+/// It is implemented by every [`Rules`], which ignore the context. Implement it instead of
+/// [`Rules`] to price instructions differently e.g. in loops or in some functions. The memory
+/// index or the alignment of a memory instruction are given by its operator.
+pub trait RulesExt {
+    /// Returns the cost for the passed `instruction` at the position described by `ctx`.
+    ///
+    /// Returning an error can be used as a way to indicate that an instruction
+    /// is forbidden
+    fn instruction_cost_in(
+        &self,
+        ctx: &InstrContext,
+        instruction: &Operator,
+    ) -> Result<InstructionCost>;
+
+    /// See [`Rules::gas_charge_cost`].
+    fn gas_charge_cost(&self) -> u64;
+
+    /// See [`Rules::linear_calc_cost`].
+    fn linear_calc_cost(&self) -> u64;
+
+    /// See [`Rules::import_call_cost`].
+    fn import_call_cost(&self, _module: &str, _name: &str) -> u64 {
+        0
+    }
+}
+
+impl<R: Rules + ?Sized> RulesExt for R {
+    fn instruction_cost_in(
+        &self,
+        _ctx: &InstrContext,
+        instruction: &Operator,
+    ) -> Result<InstructionCost> {
+        self.instruction_cost(instruction)
+    }
+
+    fn gas_charge_cost(&self) -> u64 {
+        Rules::gas_charge_cost(self)
+    }
+
+    fn linear_calc_cost(&self) -> u64 {
+        Rules::linear_calc_cost(self)
+    }
+
+    fn import_call_cost(&self, module: &str, name: &str) -> u64 {
+        Rules::import_call_cost(self, module, name)
+    }
+}
+
+/// The position of an instruction, see [`RulesExt::instruction_cost_in`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct InstrContext {
+    /// Index of the function among the functions defined by the module, i.e. its index in the
+    /// function index space of the original module minus the number of imported functions.
+    pub func_index: u32,
+    /// Index of the instruction in the function body.
+    pub offset: usize,
+    /// Number of `block`, `loop`, `if` and `try` instructions enclosing the instruction. An `end`
+    /// or an `else` is enclosed by the instruction it belongs to.
+    pub depth: usize,
+    /// Whether the instruction is enclosed by a `loop`.
+    pub in_loop: bool,
+}
+
+/// Tracks the control frames enclosing the instructions of a function body for their
+/// [`InstrContext`].
+#[derive(Debug, Default)]
+struct ContextTracker {
+    /// Whether each open control frame is a loop, the function body excluded.
+    frames: Vec<bool>,
+    open_loops: usize,
+}
+
+impl ContextTracker {
+    fn context(&self, func_index: u32, offset: usize) -> InstrContext {
+        InstrContext {
+            func_index,
+            offset,
+            depth: self.frames.len(),
+            in_loop: self.in_loop(),
+        }
+    }
+
+    fn in_loop(&self) -> bool {
+        self.open_loops > 0
+    }
+
+    /// Opens or closes the control frame of the instruction, if any.
+    fn update(&mut self, instruction: &Operator) {
+        match instruction {
+            Operator::Block { .. } | Operator::If { .. } | Operator::Try { .. } => {
+                self.frames.push(false)
+            }
+            Operator::Loop { .. } => {
+                self.frames.push(true);
+                self.open_loops += 1;
+            }
+            // `delegate` closes a `try` frame in place of its `end`
+            Operator::End | Operator::Delegate { .. } => {
+                if self.frames.pop() == Some(true) {
+                    self.open_loops -= 1;
+                }
+            }
+            _ => {}
+        }
+    }
 }
 
 /// Dynamic costs instructions.
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+///
+/// With the `serde` feature enabled, fixed costs are (de)serialized as a number, linear costs as a
+/// `[base, cost_per_unit]` pair or a `[base, cost_per_unit, depth]` triple, and the other costs as
+/// a map of their fields, e.g. `{ "base": 1, "segments": [[0, 1], [1024, 10]] }`.
+///
+/// More ways to price an instruction may be added, so matching on this enum needs a wildcard arm.
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[non_exhaustive]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(untagged, deny_unknown_fields)
+)]
 pub enum InstructionCost {
     /// Charge fixed amount per instruction.
     Fixed(u64),
@@ -59,7 +199,51 @@ pub enum InstructionCost {
     ///
     /// Note: in order to make overflows impossible, the second (cost per unit)
     /// value must be in range 0x1 ~ 0x7fff_ffff.
+    ///
+    /// The last item on the stack is an `i64` for `memory.grow` and `memory.fill` on a 64-bit
+    /// memory, and for `memory.copy` between 64-bit memories. These are always charged at runtime,
+    /// and their linear cost saturates at `i64::MAX`.
     Linear(u64, NonZeroU32),
+
+    /// Like [`InstructionCost::Linear`], but the cost per unit is multiplied by the operand at the
+    /// given depth below the top of the stack, e.g. `1` for the last argument but one of a call.
+    /// The operands above it are kept in temporary locals while the charge is computed. The
+    /// operand must be an `i32` or an `i64`, and it is always charged at runtime unless its depth
+    /// is `0`, which is the same as [`InstructionCost::Linear`].
+    ///
+    /// Besides the instructions supported by [`InstructionCost::Linear`], this can price calls by
+    /// their arguments and `call_indirect` by its table index.
+    LinearOperand(u64, NonZeroU32, u32),
+
+    /// Charge `base` plus `linear * n + quadratic * n * n` for the last item `n` on the stack,
+    /// which is charged at runtime unless it is pushed by an `i32.const`. The charge saturates at
+    /// `i64::MAX` instead of overflowing.
+    Quadratic {
+        base: u64,
+        linear: u32,
+        quadratic: u32,
+        /// Only for `memory.grow`: charge the difference between the cost of the total number of
+        /// pages of the memory after and before growing it, read with `memory.size` at runtime.
+        #[cfg_attr(feature = "serde", serde(default))]
+        total_memory: bool,
+    },
+
+    /// Charge `base` plus a piecewise linear cost of the last item `n` on the stack, which is
+    /// charged at runtime unless it is pushed by an `i32.const`. The charge saturates at
+    /// `i64::MAX` instead of overflowing.
+    ///
+    /// Each segment is the first unit it applies to and the cost per unit from there on, up to the
+    /// start of the next segment. The segments must be sorted by their first unit, and the units
+    /// before the first segment are free. For example `[(0, 1), (1024, 10)]` charges `1` for each
+    /// of the first 1024 units and `10` for each of the others.
+    Piecewise {
+        base: u64,
+        segments: Vec<(u64, u32)>,
+        /// Only for `memory.grow`: charge the units between the total number of pages of the
+        /// memory before and after growing it, read with `memory.size` at runtime.
+        #[cfg_attr(feature = "serde", serde(default))]
+        total_memory: bool,
+    },
 }
 
 /// A type that implements [`Rules`] so that every instruction costs the same.
@@ -165,18 +349,165 @@ struct MeteredBlock {
     cost: u64,
 }
 
+/// Summary of the instrumentation performed by [`inject_with_report`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct InjectionReport {
+    /// One entry per function defined in the module, in function index order. The injected gas
+    /// charging function is not included.
+    pub functions: Vec<FunctionReport>,
+    /// Index of the injected gas charging function in the function index space, or of the
+    /// imported host function with [`GasCounter::HostFunction`].
+    pub gas_func: u32,
+    /// Index of the gas counter global in the global index space, `None` with
+    /// [`GasCounter::HostFunction`].
+    pub gas_global: Option<u32>,
+    /// Size of the instrumented module minus the size of the original module, in bytes.
+    pub size_delta: i64,
+}
+
+/// Instrumentation summary of a single function, see [`InjectionReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FunctionReport {
+    /// Index of the function in the function index space (imports included).
+    pub func_index: u32,
+    /// Number of metered blocks, i.e. of static gas charges inserted into the function.
+    pub metered_blocks: usize,
+    /// Number of linearly priced instructions charged dynamically at runtime.
+    pub dynamic_charges: usize,
+    /// Number of counted loops whose iterations are charged at once before entering them, see
+    /// [`InjectOptions::hoist_loop_charges`].
+    pub hoisted_loops: usize,
+    /// Number of refunds of the gas charged when entering the function, see
+    /// [`InjectOptions::charge_upfront`].
+    pub refunds: usize,
+    /// Sum of the static charges of all metered blocks, including the cost of the charge calls
+    /// themselves.
+    pub static_cost: u64,
+}
+
 /// An instruction that requires Linear gas charge to be applied
 #[derive(Debug, Clone)]
 struct MeteredInstruction {
     /// Index of the instruction.
     pos: usize,
-    /// Cost per unit. Multiplied by top of stack to get the actual cost
-    unit_cost: u32,
+    /// Cost of the operand. For linear costs, the cost per unit is multiplied by the operand to
+    /// get the actual cost
+    cost: OperandCost,
+    /// Type of the operand the instruction is priced by, `i32` or `i64`.
+    operand_type: ValType,
+    /// Types of the operands above the one the instruction is priced by, from the bottom of the
+    /// stack, which are kept in temporary locals while the charge is computed.
+    spilled: Vec<ValType>,
+}
+
+/// A counted loop whose iterations are all charged before entering it, instead of charging every
+/// iteration at its start.
+///
+/// The loop body contains no control instructions and ends with
+///
+/// ```ignore
+/// local.get $counter
+/// i32.const 1
+/// i32.add
+/// local.tee $counter
+/// <bound>
+/// <condition>
+/// br_if 0
+/// ```
+///
+/// where `<bound>` is either a constant or a local, and neither the counter nor the bound local are
+/// written anywhere else in the body. The number of iterations only depends on the values of the
+/// counter and the bound when entering the loop.
+#[derive(Debug)]
+struct HoistedLoop {
+    /// Index of the `loop` instruction.
+    pos: usize,
+    /// Cost of a single iteration.
+    body_cost: u64,
+    /// Cost of the charge itself, charged once.
+    overhead: u64,
+    /// Index of the counter local, incremented by one at the end of every iteration.
+    counter: u32,
+    bound: LoopBound,
+    condition: LoopCondition,
+}
+
+/// The value a [`HoistedLoop`] counter is compared against.
+#[derive(Debug, Clone, Copy)]
+enum LoopBound {
+    Local(u32),
+    Const(i32),
+}
+
+/// The comparison of a [`HoistedLoop`] counter against its bound that continues the loop.
+#[derive(Debug, Clone, Copy)]
+enum LoopCondition {
+    LtU,
+    LtS,
+    Ne,
+}
+
+impl HoistedLoop {
+    /// Returns the instructions pushing the `i64` cost of all iterations of the loop plus the
+    /// overhead. Uses the `i64` local `temp_local`.
+    fn charge(&self, temp_local: u32) -> Vec<Instruction<'static>> {
+        use wasm_encoder::Instruction::*;
+        let bound = match self.bound {
+            LoopBound::Local(index) => LocalGet(index),
+            LoopBound::Const(value) => I32Const(value),
+        };
+        let mut instructions = match self.condition {
+            // The counter wraps around until it equals the bound, which takes
+            // ((bound - counter - 1) mod 2^32) + 1 iterations.
+            LoopCondition::Ne => vec![
+                bound,
+                LocalGet(self.counter),
+                I32Sub,
+                I32Const(1),
+                I32Sub,
+                I64ExtendI32U,
+            ],
+            // The first iteration always runs. Then the counter can't wrap around before reaching
+            // the bound, so there are max(bound - (counter + 1), 0) more iterations.
+            LoopCondition::LtU | LoopCondition::LtS => {
+                let extend = match self.condition {
+                    LoopCondition::LtU => I64ExtendI32U,
+                    _ => I64ExtendI32S,
+                };
+                vec![
+                    bound,
+                    extend.clone(),
+                    LocalGet(self.counter),
+                    I32Const(1),
+                    I32Add,
+                    extend,
+                    I64Sub,
+                    LocalTee(temp_local),
+                    I64Const(0),
+                    LocalGet(temp_local),
+                    I64Const(0),
+                    I64GtS,
+                    Select,
+                ]
+            }
+        };
+        instructions.extend([I64Const(1), I64Add, I64Const(self.body_cost as i64), I64Mul]);
+        if self.overhead > 0 {
+            instructions.extend([I64Const(self.overhead as i64), I64Add]);
+        }
+        instructions
+    }
 }
 
 /// Counter is used to manage state during the gas metering algorithm implemented by
 /// `inject_counter`.
 struct Counter {
+    /// Index of the function being metered, in the function index space. Only used for error
+    /// reporting.
+    func_index: u32,
+
     /// A stack of control blocks. This stack grows when new control blocks are opened with
     /// `block`, `loop`, and `if` and shrinks when control blocks are closed with `end`. The first
     /// block on the stack corresponds to the function body, not to any labelled block. Therefore
@@ -189,8 +520,9 @@ struct Counter {
 }
 
 impl Counter {
-    fn new() -> Counter {
+    fn new(func_index: u32) -> Counter {
         Counter {
+            func_index,
             stack: Vec::new(),
             finalized_blocks: Vec::new(),
         }
@@ -335,22 +667,27 @@ impl Counter {
 
     /// Increment the cost of the current block by the specified value.
     fn increment(&mut self, val: u64) -> Result<()> {
+        let func_index = self.func_index;
         let top_block = self.active_metered_block()?;
         top_block.cost = top_block
             .cost
             .checked_add(val)
-            .ok_or_else(|| anyhow!("add cost overflow"))?;
+            .ok_or(InstrumentError::CostOverflow { func_index })?;
         Ok(())
     }
 }
 
-fn determine_metered_blocks<R: Rules>(
+fn determine_metered_blocks<R: RulesExt>(
     func_body: &wasmparser::FunctionBody,
     rules: &R,
+    func_index: u32,
+    defined_index: u32,
+    types: &ModuleTypes,
+    br_table_default: bool,
 ) -> Result<(Vec<MeteredBlock>, Vec<MeteredInstruction>)> {
     use wasmparser::Operator::*;
 
-    let mut counter = Counter::new();
+    let mut counter = Counter::new(func_index);
     // Begin an implicit function (i.e. `func...end`) block.
     counter.begin_control_block(0, false);
 
@@ -360,6 +697,7 @@ fn determine_metered_blocks<R: Rules>(
     let mut last_const: Option<i32> = None;
 
     let mut metered_instrs = Vec::new();
+    let mut tracker = ContextTracker::default();
 
     let operators = func_body
         .get_operators_reader()
@@ -368,15 +706,31 @@ fn determine_metered_blocks<R: Rules>(
         .collect::<wasmparser::Result<Vec<Operator>>>()
         .unwrap();
     for (cursor, instruction) in operators.iter().enumerate() {
-        let instruction_cost = match rules.instruction_cost(instruction)? {
-            InstructionCost::Fixed(c) => c,
-            InstructionCost::Linear(base, cost_per) => {
-                // Enforce that cost per unit fits in 31 bits
-                if cost_per.get() >= 0x8000_0000 {
-                    return Err(anyhow!("cost per unit excedes the 0x80000000 limit"));
+        let ctx = tracker.context(defined_index, cursor);
+        tracker.update(instruction);
+        let cost = rules
+            .instruction_cost_in(&ctx, instruction)
+            .map_err(|reason| InstrumentError::ForbiddenInstruction {
+                func_index,
+                op_index: cursor,
+                op: format!("{:?}", instruction),
+                reason,
+            })?;
+        let (base, operand_cost) = OperandCost::split(cost)?;
+        let base = base
+            .checked_add(types.import_call_cost(rules, instruction))
+            .ok_or(InstrumentError::CostOverflow { func_index })?;
+        let instruction_cost = match operand_cost {
+            None => base,
+            Some((operand_cost, depth)) => {
+                let total_memory = operand_cost.total_memory();
+                if total_memory && !matches!(instruction, MemoryGrow { .. }) {
+                    return Err(anyhow!(
+                        "only memory.grow can be priced by the total memory"
+                    ));
                 }
 
-                if let Some(stack_top) = last_const {
+                if let Some(stack_top) = last_const.filter(|_| depth == 0 && !total_memory) {
                     if stack_top < 0 {
                         // See "NOTE(negative bulk instruction arg)" below
                         return Err(anyhow!(
@@ -384,29 +738,36 @@ fn determine_metered_blocks<R: Rules>(
                         ));
                     }
 
-                    // note: doesn't overflow because both sides are u32
-                    base + ((stack_top as u64) * (cost_per.get() as u64))
+                    operand_cost
+                        .static_cost(stack_top as u64)
+                        .and_then(|c| base.checked_add(c))
+                        .ok_or(InstrumentError::CostOverflow { func_index })?
                 } else {
-                    // Code in insert_metering_calls below needs to create temporary locals in order
-                    // to be able to duplicate stack items. For simplicity/performance that code
-                    // hard-codes i32 valtype. This is fine as all instructions for which dynamic
-                    // pricing makes sense have i32 stack top.
-                    //
-                    // If the need ever arises to support more value types, this check will need to
-                    // be removed, and the logic creating temporary locals in insert_metering_calls
-                    // will need to be made smarter.
-                    match instruction_stack_top_type(instruction)? {
-                        ValType::I32 => {},
-                        _ => return Err(anyhow!("linearly priced instructions with non-i32 stack top aren't supported yet")),
-                    };
+                    // Code in insert_metering_calls below needs to create temporary locals of the
+                    // type of the operand in order to be able to duplicate it, and of the types of
+                    // the operands above it in order to get to it.
+                    let mut spilled = instruction_operand_types(instruction, types)?;
+                    let operand_type = spilled
+                        .len()
+                        .checked_sub(depth as usize + 1)
+                        .map(|index| spilled.remove(index))
+                        .ok_or_else(|| anyhow!("instruction has no operand at depth {}", depth))?;
+                    if !matches!(operand_type, ValType::I32 | ValType::I64) {
+                        return Err(anyhow!("linearly priced operand is not an integer"));
+                    }
+                    spilled.drain(..spilled.len() - depth as usize);
 
                     metered_instrs.push(MeteredInstruction {
                         pos: cursor,
-                        unit_cost: cost_per.get(),
+                        cost: operand_cost,
+                        operand_type,
+                        spilled,
                     });
                     // linear part will get charged at runtime (this instruction will get replaced
                     // with a call to gas-charging func)
-                    base + rules.gas_charge_cost() + rules.linear_calc_cost()
+                    base.checked_add(rules.gas_charge_cost())
+                        .and_then(|c| c.checked_add(rules.linear_calc_cost()))
+                        .ok_or(InstrumentError::CostOverflow { func_index })?
                 }
             }
         };
@@ -465,7 +826,10 @@ fn determine_metered_blocks<R: Rules>(
                     .targets()
                     .collect::<wasmparser::Result<Vec<u32>>>()
                     .unwrap();
-                let target_indices = Vec::with_capacity(br_table_data.default() as usize)
+                // Version 0.4 ignored the default target, which is kept unless the blocks are
+                // merged to leave the charges of existing modules unchanged
+                let default = br_table_default.then(|| br_table_data.default());
+                let target_indices = default
                     .iter()
                     .chain(r.iter())
                     .map(|label| active_index.checked_sub(*label as usize))
@@ -499,6 +863,187 @@ fn determine_metered_blocks<R: Rules>(
     Ok((counter.finalized_blocks, metered_instrs))
 }
 
+/// Where the gas counter global of an instrumented module comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GasCounter {
+    /// Import the mutable `i64` global [`GAS_COUNTER_NAME`] from the given module.
+    Import {
+        /// Name of the module the gas counter is imported from.
+        module: String,
+    },
+    /// Define a mutable `i64` global, initialized to zero, and export it under the given name.
+    /// The host sets the available gas through the export before calling into the module and
+    /// reads the remaining gas afterwards.
+    Export {
+        /// Name under which the gas counter is exported.
+        name: String,
+    },
+    /// Don't keep a gas counter in the module, call the imported host function `module.name`
+    /// of type `[i64] -> []` with the amount of gas to charge instead. The host is responsible
+    /// for trapping when running out of gas.
+    HostFunction {
+        /// Name of the module the function is imported from.
+        module: String,
+        /// Name of the imported function.
+        name: String,
+    },
+}
+
+/// What the gas charging function does when the gas counter can't pay for a charge.
+///
+/// Only applies to the gas counters kept by the module, not to [`GasCounter::HostFunction`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutOfGas {
+    /// Execute `unreachable`.
+    Trap,
+    /// Call the imported host function `module.name` of type `[] -> []`, which should trap with
+    /// a distinct error. Execute `unreachable` if it returns.
+    HostFunction {
+        /// Name of the module the function is imported from.
+        module: String,
+        /// Name of the imported function.
+        name: String,
+    },
+    /// Set a mutable `i32` global, initialized to zero and exported under the given name, to
+    /// one, then execute `unreachable`. The host checks the global to tell an out of gas trap
+    /// from any other trap.
+    ExportStatus {
+        /// Name under which the status global is exported.
+        name: String,
+    },
+}
+
+impl Default for OutOfGas {
+    fn default() -> Self {
+        OutOfGas::Trap
+    }
+}
+
+/// How the gas charging function updates the gas counter.
+///
+/// Only applies to the gas counters kept by the module, not to [`GasCounter::HostFunction`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargeMode {
+    /// Subtract the charge, then run out of gas if the counter is negative. The counter is left
+    /// negative when running out of gas.
+    SubtractThenCheck,
+    /// Run out of gas without touching the counter if it is less than the charge, so that it
+    /// keeps its value from before the charge.
+    KeepOnFailure,
+    /// Set the counter to zero and run out of gas if it is less than the charge.
+    ZeroOnFailure,
+}
+
+impl Default for ChargeMode {
+    fn default() -> Self {
+        ChargeMode::SubtractThenCheck
+    }
+}
+
+/// How the gas charging function interprets the `i64` gas counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterType {
+    /// The counter is a signed integer, so at most `i64::MAX` gas can be available. A counter
+    /// that is negative before a charge always runs out of gas.
+    Signed,
+    /// The counter is an unsigned budget of up to `u64::MAX` gas, compared to the charge with
+    /// `i64.lt_u`. With [`ChargeMode::SubtractThenCheck`], the counter wraps around when running
+    /// out of gas.
+    Unsigned,
+}
+
+impl Default for CounterType {
+    fn default() -> Self {
+        CounterType::Signed
+    }
+}
+
+/// Whether the gas charges are inlined instead of calling the gas charging function.
+///
+/// Inlining saves a function call per charge at the cost of a larger module. Only applies to the
+/// gas counters kept by the module, not to [`GasCounter::HostFunction`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inlining {
+    /// Always call the gas charging function.
+    Never,
+    /// Inline the charges in loop bodies, and the static charges of metered blocks of at most
+    /// `max_block_len` instructions, counting up to the next charge. Call the gas charging
+    /// function everywhere else.
+    Heuristic {
+        /// Length of the longest metered block charged inline outside of loops.
+        max_block_len: usize,
+    },
+    /// Inline every charge.
+    Always,
+}
+
+impl Default for Inlining {
+    fn default() -> Self {
+        Inlining::Never
+    }
+}
+
+/// Configuration of [`inject_with_options`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct InjectOptions {
+    /// Where the gas counter comes from.
+    pub gas_counter: GasCounter,
+    /// What happens when running out of gas.
+    pub out_of_gas: OutOfGas,
+    /// Whether the counter is checked before or after subtracting a charge.
+    pub charge_mode: ChargeMode,
+    /// Whether the counter is a signed or an unsigned integer.
+    pub counter_type: CounterType,
+    /// Whether the gas charges are inlined.
+    pub inlining: Inlining,
+    /// Charge all iterations of simple counted loops once before entering them, instead of at the
+    /// start of every iteration. Loops are hoisted when their body is straight-line code ending
+    /// with `local.get $i; i32.const 1; i32.add; local.tee $i; <bound>; <cond>; br_if 0`, where
+    /// `<bound>` is a constant or a local, `<cond>` is `i32.lt_u`, `i32.lt_s` or `i32.ne`, and
+    /// `$i` and the bound are not written anywhere else in the body. Other loops are charged per
+    /// iteration.
+    ///
+    /// A trap in the middle of a hoisted loop consumes the gas of all its iterations.
+    pub hoist_loop_charges: bool,
+    /// Move the charge of a metered block into the charge of a dominating metered block whenever
+    /// it post-dominates that block and both are in the same loop, such as the code following a
+    /// `block` that is left by a forward branch. This reduces the number of charges without
+    /// changing the total charged on any path, but a trap consumes the gas of the merged blocks
+    /// that would have been executed after it.
+    ///
+    /// This also ends a metered block at the default target of a `br_table`, which is otherwise
+    /// ignored as in version 0.4, so that the code after a block left only through the default
+    /// target is no longer charged together with the code before the branch.
+    pub merge_blocks: bool,
+    /// Charge the most expensive path through each function without loops once when entering it,
+    /// instead of charging every metered block, and refund the difference wherever a cheaper path
+    /// branches off. Functions with loops or with a `br_table` whose targets don't cost the same
+    /// are metered as usual, as are all functions with [`GasCounter::HostFunction`], which has no
+    /// gas counter to refund.
+    ///
+    /// A function which can't pay for its most expensive path runs out of gas when entering it,
+    /// and the functions it calls don't get the gas refunded after they return.
+    pub charge_upfront: bool,
+}
+
+impl InjectOptions {
+    /// Create options for the given gas counter, with every other option set to the behavior of
+    /// [`inject`].
+    pub fn new(gas_counter: GasCounter) -> Self {
+        Self {
+            gas_counter,
+            out_of_gas: OutOfGas::default(),
+            charge_mode: ChargeMode::default(),
+            counter_type: CounterType::default(),
+            inlining: Inlining::default(),
+            hoist_loop_charges: false,
+            merge_blocks: false,
+            charge_upfront: false,
+        }
+    }
+}
+
 /// Transforms a given module into one that charges gas for code to be executed by proxy of an
 /// imported gas metering function.
 ///
@@ -531,23 +1076,131 @@ fn determine_metered_blocks<R: Rules>(
 ///
 /// This routine runs in time linear in the size of the input module.
 ///
-/// The function fails if the module contains any operation forbidden by gas rule set, returning
-/// the original module as an Err. Only one imported global is allowed per `gas_module_name`, the
-/// one corresponding to the gas spending measurement
-pub fn inject<R: Rules>(raw_wasm: &[u8], rules: &R, gas_module_name: &str) -> Result<Vec<u8>> {
-    // Injecting gas counting external
-    let mut module_info = ModuleInfo::new(raw_wasm)?;
-    add_gas_global_import(&mut module_info, gas_module_name)?;
+/// The function fails if the module contains any operation forbidden by gas rule set, reporting
+/// the offending function and instruction as [`InstrumentError::ForbiddenInstruction`]. Only one
+/// imported global is allowed per `gas_module_name`, the one corresponding to the gas spending
+/// measurement.
+///
+/// See [`inject_with_options`] to define and export the gas counter instead of importing it.
+pub fn inject<R: RulesExt>(
+    raw_wasm: &[u8],
+    rules: &R,
+    gas_module_name: &str,
+) -> Result<Vec<u8>, InstrumentError> {
+    inject_with_report(raw_wasm, rules, gas_module_name).map(|(wasm, _)| wasm)
+}
 
-    // calculate actual global index of the imported definition
-    //    (subtract all imports that are NOT globals)
-    let gas_global = module_info.imported_globals_count - 1;
-    let total_func = module_info.function_map.len() as u32;
+/// Same as [`inject`], but also returns an [`InjectionReport`] describing the inserted
+/// metering code.
+pub fn inject_with_report<R: RulesExt>(
+    raw_wasm: &[u8],
+    rules: &R,
+    gas_module_name: &str,
+) -> Result<(Vec<u8>, InjectionReport), InstrumentError> {
+    let options = InjectOptions::new(GasCounter::Import {
+        module: gas_module_name.into(),
+    });
+    inject_with_options(raw_wasm, rules, &options)
+}
 
-    // We'll push the gas counter fuction after all other functions
-    let gas_func = total_func;
+/// Same as [`inject_with_report`], but configured by `options`, e.g. to define and export the gas
+/// counter instead of importing it.
+///
+/// Fails with [`InstrumentError::DuplicateExport`] if the gas counter or the out of gas status
+/// should be exported under a name already exported by the module.
+pub fn inject_with_options<R: RulesExt>(
+    raw_wasm: &[u8],
+    rules: &R,
+    options: &InjectOptions,
+) -> Result<(Vec<u8>, InjectionReport), InstrumentError> {
+    inject_gas_counter(raw_wasm, rules, options).map_err(InstrumentError::from)
+}
 
-    let mut error = false;
+fn inject_gas_counter<R: RulesExt>(
+    raw_wasm: &[u8],
+    rules: &R,
+    options: &InjectOptions,
+) -> Result<(Vec<u8>, InjectionReport)> {
+    let mut module_info = ModuleInfo::new(raw_wasm)?;
+    let imported_globals_count = module_info.imported_globals_count;
+
+    // The function imported by the instrumentation, if any, comes after the module's imports
+    let imported_func = match (&options.gas_counter, &options.out_of_gas) {
+        (GasCounter::HostFunction { .. }, OutOfGas::HostFunction { .. })
+        | (GasCounter::HostFunction { .. }, OutOfGas::ExportStatus { .. }) => {
+            return Err(anyhow!(
+                "out of gas handling requires a gas counter kept by the module"
+            ));
+        }
+        (GasCounter::HostFunction { module, name }, _) => {
+            let params = vec![wasmparser::ValType::I64];
+            let index = add_func_import(&mut module_info, module, name, params)?;
+            Some((index, name.clone()))
+        }
+        (_, OutOfGas::HostFunction { module, name }) => {
+            let index = add_func_import(&mut module_info, module, name, vec![])?;
+            Some((index, name.clone()))
+        }
+        _ => None,
+    };
+
+    let gas_global = match &options.gas_counter {
+        GasCounter::Import { module } => {
+            // Injecting gas counting external, after the globals imported by the module
+            add_gas_global_import(&mut module_info, module)?;
+            Some(imported_globals_count)
+        }
+        // Defined after all other globals, so no index changes
+        GasCounter::Export { .. } => Some(module_info.num_globals()),
+        GasCounter::HostFunction { .. } => None,
+    };
+    let remap = IndexRemap {
+        gas_global,
+        imported_func: imported_func.as_ref().map(|(index, _)| *index),
+    };
+
+    // We'll push the gas counter fuction after all other functions, unless it is imported
+    let gas_func = match (gas_global, &imported_func) {
+        (None, Some((index, _))) => *index,
+        _ => module_info.function_map.len() as u32,
+    };
+
+    // The out of gas status global is defined after the gas counter, once the indices have been
+    // remapped
+    let status_global = module_info.num_globals()
+        + u32::from(matches!(options.gas_counter, GasCounter::Export { .. }));
+    let gas_charge = match gas_global {
+        Some(gas_global) => {
+            let out_of_gas = match &options.out_of_gas {
+                OutOfGas::Trap => vec![Instruction::Unreachable],
+                OutOfGas::HostFunction { .. } => {
+                    let (index, _) = imported_func
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("out of gas function is not imported"))?;
+                    vec![Instruction::Call(*index), Instruction::Unreachable]
+                }
+                OutOfGas::ExportStatus { .. } => vec![
+                    Instruction::I32Const(1),
+                    Instruction::GlobalSet(status_global),
+                    Instruction::Unreachable,
+                ],
+            };
+            Some(GasCharge {
+                gas_global,
+                charge_mode: options.charge_mode,
+                counter_type: options.counter_type,
+                out_of_gas,
+            })
+        }
+        None => None,
+    };
+    let charger = Charger {
+        gas_func,
+        charge_cost: rules.gas_charge_cost(),
+        inline: gas_charge
+            .as_ref()
+            .map(|gas_charge| (gas_charge, options.inlining)),
+    };
 
     // Read types which are needed in later steps
     let mut functype_param_counts = Vec::new();
@@ -580,33 +1233,32 @@ pub fn inject<R: Rules>(raw_wasm: &[u8], rules: &R, gas_module_name: &str) -> Re
         }
     }
 
-    // Updating calling addresses (all calls to function index >= `gas_func` should be incremented)
+    let types = ModuleTypes::new(&module_info)?;
+    let mut function_reports = Vec::new();
+
+    // Updating the global and function indices shifted by the gas global or function
     if let Some(code_section) = module_info.raw_sections.get_mut(&SectionId::Code.into()) {
         let mut code_section_builder = wasm_encoder::CodeSection::new();
         let mut code_sec_reader = CodeSectionReader::new(&code_section.data, 0)?;
 
         let mut param_counts = func_param_counts.into_iter();
+        let mut func_index = module_info.imported_functions_count;
+        let mut defined_index = 0;
 
         // For each function
         while !code_sec_reader.eof() {
             let func_body = code_sec_reader.read()?;
             let mut func_builder = wasm_encoder::Function::new(copy_locals(&func_body)?);
 
-            // Go through instructions, increment all global gets
-            // todo this is wrong, can have imports at lower index??
+            // Go through instructions, shifting the indices after the gas global or function
             let mut operator_reader = func_body.get_operators_reader()?;
             while !operator_reader.eof() {
                 let op = operator_reader.read()?;
-                match op {
-                    // todo if > gas global??
-                    Operator::GlobalGet { global_index } => {
-                        func_builder.instruction(&Instruction::GlobalGet(global_index + 1))
-                    }
-                    Operator::GlobalSet { global_index } => {
-                        func_builder.instruction(&Instruction::GlobalSet(global_index + 1))
-                    }
-                    op => func_builder.instruction(&DefaultTranslator.translate_op(&op)?),
-                };
+                func_builder.instruction(
+                    &remap
+                        .translate_op(&op)
+                        .map_err(|_| InstrumentError::UnsupportedProposal(format!("{:?}", op)))?,
+                );
             }
 
             let param_count = param_counts
@@ -616,909 +1268,3224 @@ pub fn inject<R: Rules>(raw_wasm: &[u8], rules: &R, gas_module_name: &str) -> Re
             // Determine metered blocks and dynamically priced instructions
             // Rewrite function bodies with code block gas tracking instrumented
             // TODO: merge the second step into the loop above which is already rewriting functions
-            match inject_counter(
+            let func = MeteredFunction {
+                func_index,
+                defined_index,
+                param_count,
+                types: &types,
+            };
+            let (func_builder, func_report) = inject_counter(
                 &FunctionBody::new(0, &truncate_len_from_encoder(&func_builder)?),
                 rules,
-                param_count,
-                gas_func,
-            ) {
-                Ok(new_builder) => func_builder = new_builder,
-                Err(_) => {
-                    error = true;
-                    break;
-                }
-            }
+                &func,
+                charger,
+                options,
+            )?;
 
             code_section_builder.function(&func_builder);
+            function_reports.push(func_report);
+            func_index += 1;
+            defined_index += 1;
         }
         module_info.replace_section(SectionId::Code.into(), &code_section_builder)?;
     }
 
-    if let Some(export_section) = module_info.raw_sections.get_mut(&SectionId::Export.into()) {
-        let mut export_sec_builder = ExportSection::new();
-        let mut export_sec_reader = ExportSectionReader::new(&export_section.data, 0)?;
-        while !export_sec_reader.eof() {
-            let export = export_sec_reader.read()?;
-            let mut global_index = export.index;
-            if let ExternalKind::Global = export.kind {
-                if global_index >= gas_global {
-                    global_index += 1;
-                }
-            }
-            export_sec_builder.export(
-                export.name,
-                DefaultTranslator.translate_export_kind(export.kind)?,
-                global_index,
-            );
-        }
-    }
+    remap.remap_sections(&mut module_info)?;
 
-    if let Some(import_section) = module_info.raw_sections.get_mut(&SectionId::Import.into()) {
+    let gas_module_name = match &options.gas_counter {
+        GasCounter::Import { module } => Some(module.as_str()),
+        GasCounter::Export { name } => {
+            let init = wasm_encoder::ConstExpr::i64_const(0);
+            add_global_export(&mut module_info, name, wasmparser::ValType::I64, &init)?;
+            None
+        }
+        GasCounter::HostFunction { .. } => None,
+    };
+    let import_section = module_info.raw_sections.get(&SectionId::Import.into());
+    if let (Some(import_section), Some(gas_module_name)) = (import_section, gas_module_name) {
         // Take the imports for the gasglobal
         let import_sec_reader = ImportSectionReader::new(&import_section.data, 0)?;
-        let gas_globals = import_sec_reader.into_iter().filter(|r| match r {
-            Ok(p) => match p.ty {
-                TypeRef::Global(g) => {
-                    if p.module == gas_module_name && p.name == GAS_COUNTER_NAME {
-                        if !g.mutable {
-                            error = true
-                        }
-
-                        true
-                    } else {
-                        false
-                    }
+        let mut gas_globals = Vec::new();
+        for import in import_sec_reader {
+            let import = import?;
+            if let TypeRef::Global(g) = import.ty {
+                if import.module == gas_module_name && import.name == GAS_COUNTER_NAME {
+                    gas_globals.push(g);
                 }
-
-                _ => false,
-            },
-            _ => false,
-        });
+            }
+        }
 
         // Ensure there is only one gas global import
-        if gas_globals.count() != 1 {
-            return Err(anyhow!("expected 1 gas global"));
+        if gas_globals.len() != 1 {
+            return Err(InstrumentError::DuplicateGasGlobal.into());
+        }
+        if gas_globals.iter().any(|g| !g.mutable) {
+            return Err(InstrumentError::ImmutableGasGlobal.into());
         }
     }
 
-    if let Some(ele_section) = module_info.raw_sections.get_mut(&SectionId::Element.into()) {
+    // Check the offsets of active segments. The global indices have been remapped already.
+    if let Some(ele_section) = module_info.raw_sections.get(&SectionId::Element.into()) {
         let ele_sec_reader = ElementSectionReader::new(&ele_section.data, 0)?;
         for segment in ele_sec_reader {
-            let element_reader = segment?.items.get_items_reader()?;
-            for ele in element_reader {
-                if let ElementItem::Expr(expr) = ele? {
-                    let operators = expr
-                        .get_operators_reader()
-                        .into_iter()
-                        .collect::<wasmparser::Result<Vec<Operator>>>()
-                        .unwrap();
-                    if !check_offset_code(&operators) {
-                        error = true;
-                        break;
-                    }
+            if let ElementKind::Active { offset_expr, .. } = segment?.kind {
+                if !check_offset_code(&module_info, imported_globals_count, &offset_expr)? {
+                    return Err(InstrumentError::NonConstSegmentOffset.into());
                 }
             }
         }
     }
 
-    if let Some(data_section) = module_info.raw_sections.get_mut(&SectionId::Data.into()) {
+    if let Some(data_section) = module_info.raw_sections.get(&SectionId::Data.into()) {
         let data_sec_reader = DataSectionReader::new(&data_section.data, 0)?;
         for data in data_sec_reader {
-            if let DataKind::Active {
-                memory_index: _,
-                offset_expr: expr,
-            } = data?.kind
-            {
-                let operators = expr
-                    .get_operators_reader()
-                    .into_iter()
-                    .collect::<wasmparser::Result<Vec<Operator>>>()
-                    .unwrap();
-                if !check_offset_code(&operators) {
-                    error = true;
-                    break;
+            if let DataKind::Active { offset_expr, .. } = data?.kind {
+                if !check_offset_code(&module_info, imported_globals_count, &offset_expr)? {
+                    return Err(InstrumentError::NonConstSegmentOffset.into());
                 }
             }
         }
     }
 
-    if error {
-        return Err(anyhow!("inject fail"));
+    let mut new_func_names = Vec::new();
+    if let Some(gas_charge) = &gas_charge {
+        if let OutOfGas::ExportStatus { name } = &options.out_of_gas {
+            let init = wasm_encoder::ConstExpr::i32_const(0);
+            let status =
+                add_global_export(&mut module_info, name, wasmparser::ValType::I32, &init)?;
+            if status != status_global {
+                return Err(anyhow!("unexpected out of gas status global index"));
+            }
+        }
+        let (func_t, gas_counter_func) = gas_charge.function();
+        module_info.add_func(func_t, &gas_counter_func)?;
+        new_func_names.push((gas_func, "charge_gas".into()));
     }
+    if let Some((index, name)) = imported_func {
+        new_func_names.push((index, name));
+    }
+    module_info.update_func_names(|idx| remap.func(idx), &new_func_names)?;
 
-    let (func_t, gas_counter_func) = generate_gas_counter(gas_global);
-    module_info.add_func(func_t, &gas_counter_func)?;
-
-    Ok(module_info.bytes())
-}
-
-fn generate_gas_counter(gas_global: u32) -> (Type, Function) {
-    use wasm_encoder::Instruction::*;
-    let mut func = wasm_encoder::Function::new(None);
-    func.instruction(&GlobalGet(gas_global));
-    func.instruction(&LocalGet(0));
-    func.instruction(&I64Sub);
-    func.instruction(&GlobalSet(gas_global));
-    func.instruction(&GlobalGet(gas_global));
-    func.instruction(&I64Const(0));
-    func.instruction(&I64LtS);
-    func.instruction(&If(BlockType::Empty));
-    func.instruction(&Unreachable);
-    func.instruction(&End);
-    func.instruction(&End);
-    (
-        Type::Func(FuncType::new(vec![wasmparser::ValType::I64], vec![])),
-        func,
-    )
-}
-
-fn inject_counter<R: Rules>(
-    instructions: &wasmparser::FunctionBody,
-    rules: &R,
-    param_count: u32,
-    gas_func: u32,
-) -> Result<wasm_encoder::Function> {
-    let (blocks, metered_instrs) = determine_metered_blocks(instructions, rules)?;
-    let charge_cost = rules.gas_charge_cost();
-
-    insert_metering_calls(
-        instructions,
-        blocks,
-        metered_instrs,
-        param_count,
+    let wasm = module_info.bytes();
+    let report = InjectionReport {
+        functions: function_reports,
         gas_func,
-        charge_cost,
-    )
+        gas_global: remap.gas_global,
+        size_delta: wasm.len() as i64 - raw_wasm.len() as i64,
+    };
+    Ok((wasm, report))
 }
 
-// Then insert metering calls into a sequence of instructions given the block locations and costs.
-fn insert_metering_calls(
-    func_body: &wasmparser::FunctionBody,
-    blocks: Vec<MeteredBlock>,
-    instructions: Vec<MeteredInstruction>,
-    param_count: u32,
-    gas_func: u32,
-    charge_cost: u64,
-) -> Result<wasm_encoder::Function> {
-    // collect value types on which we will be doing dynamic gas math.
-    // We need those for temp locals because wasm has no other way to duplicate stack items..
-
-    // todo: if in the future when we want to do linear gas cost on instructions where
-    //  the last parameter doesn't happen to always be i32 this will need to be smarter
-    // note: code in determine_metered_blocks already enforces that all `instructions`
-    // have i32 stack top.
-    let has_i32_temp = !instructions.is_empty();
-
-    let mut locals = copy_locals(func_body)?;
-    let temp_local_idx = param_count + (&locals).iter().fold(0, |acc, (count, _)| acc + count);
+/// Shifts the indices of the globals defined by the module by one to make room for an imported
+/// gas global, and the indices of the functions defined by the module to make room for an
+/// imported gas or out of gas function.
+struct IndexRemap {
+    /// Index of the gas global, the globals from this index on are shifted unless it is the last.
+    gas_global: Option<u32>,
+    /// Index of the function imported by the instrumentation, the functions from this index on
+    /// are shifted.
+    imported_func: Option<u32>,
+}
 
-    if has_i32_temp {
-        locals.push((1, ValType::I32));
+impl IndexRemap {
+    fn global(&self, global_index: u32) -> u32 {
+        match self.gas_global {
+            Some(gas_global) if global_index >= gas_global => global_index + 1,
+            _ => global_index,
+        }
     }
 
-    // To do this in linear time, construct a new vector of instructions, copying over old
-    // instructions one by one and injecting new ones as required.
-    let mut new_func = wasm_encoder::Function::new(locals);
+    fn func(&self, func_index: u32) -> u32 {
+        match self.imported_func {
+            Some(imported_func) if func_index >= imported_func => func_index + 1,
+            _ => func_index,
+        }
+    }
 
-    let mut block_iter = blocks.into_iter().peekable();
-    let mut instr_iter = instructions.into_iter().peekable();
-    let operators = func_body
-        .get_operators_reader()
-        .unwrap()
-        .into_iter()
-        .collect::<wasmparser::Result<Vec<Operator>>>()
-        .unwrap();
-    for (original_pos, instr) in operators.iter().enumerate() {
-        // If there the next block starts at this position, inject metering func_body.
-        if let Some(block) = block_iter.peek() {
-            if block.start_pos == original_pos {
-                new_func.instruction(&wasm_encoder::Instruction::I64Const(
-                    (charge_cost + block.cost) as i64,
-                ));
-                new_func.instruction(&wasm_encoder::Instruction::Call(gas_func));
+    /// Remaps the indices of all sections but the code section, which is rewritten while
+    /// inserting the metering code.
+    fn remap_sections(&self, module_info: &mut ModuleInfo) -> Result<()> {
+        if let Some(global_section) = module_info.raw_sections.get(&SectionId::Global.into()) {
+            let mut global_sec_builder = GlobalSection::new();
+            for global in GlobalSectionReader::new(&global_section.data, 0)? {
+                self.translate_global(global?, &mut global_sec_builder)?;
+            }
+            module_info.replace_section(SectionId::Global.into(), &global_sec_builder)?;
+        }
 
-                block_iter.next();
+        if let Some(export_section) = module_info.raw_sections.get(&SectionId::Export.into()) {
+            let mut export_sec_builder = ExportSection::new();
+            for export in ExportSectionReader::new(&export_section.data, 0)? {
+                self.translate_export(&export?, &mut export_sec_builder)?;
             }
+            module_info.replace_section(SectionId::Export.into(), &export_sec_builder)?;
         }
 
-        // if this instruction requires dynamic gas charge calculation, inject that code
-        if let Some(metered_instr) = instr_iter.peek() {
-            if metered_instr.pos == original_pos {
-                // duplicate stack top
-                // save into temp local
-                new_func.instruction(&wasm_encoder::Instruction::LocalTee(temp_local_idx));
-
-                // one copy to do math for gas charge
-                new_func.instruction(&wasm_encoder::Instruction::LocalGet(temp_local_idx));
-
-                // NOTE(negative bulk instruction arg):
-                // right now this instrumentation is mostly meant for bulk memory instructions
-                // In the formal spec instructions are NOT required to trap when the "count" argument
-                // is negative, and if the spec is followed exactly, those instructions may take
-                // very long to trap with a negative argument.
-                //
-                // e.g. see https://webassembly.github.io/spec/core/exec/instructions.html#xref-syntax-instructions-syntax-instr-memory-mathsf-memory-init-x
-                // To guard against this we use unsigned extend instructions.
-                // This means that e.g. -1_i32 becomes 0x0000_0000_ffff_ffff
-
-                // cast to I64 if needed
-                // note: today we only expect i32, so cast always needed
-                new_func.instruction(&wasm_encoder::Instruction::I64ExtendI32U);
-
-                // calculate gas charge
-                new_func.instruction(&wasm_encoder::Instruction::I64Const(
-                    metered_instr.unit_cost as i64,
-                ));
-                new_func.instruction(&wasm_encoder::Instruction::I64Mul);
+        if let Some(start_function) = module_info.start_function {
+            let function_index = self.func(start_function);
+            module_info.start_function = Some(function_index);
+            module_info.replace_section(
+                SectionId::Start.into(),
+                &wasm_encoder::StartSection { function_index },
+            )?;
+        }
 
-                // charge gas!
-                new_func.instruction(&wasm_encoder::Instruction::Call(gas_func));
+        if let Some(ele_section) = module_info.raw_sections.get(&SectionId::Element.into()) {
+            let mut ele_sec_builder = ElementSection::new();
+            for segment in ElementSectionReader::new(&ele_section.data, 0)? {
+                self.translate_element(segment?, &mut ele_sec_builder)?;
+            }
+            module_info.replace_section(SectionId::Element.into(), &ele_sec_builder)?;
+        }
 
-                instr_iter.next();
+        if let Some(data_section) = module_info.raw_sections.get(&SectionId::Data.into()) {
+            let mut data_sec_builder = DataSection::new();
+            for data in DataSectionReader::new(&data_section.data, 0)? {
+                self.translate_data(data?, &mut data_sec_builder)?;
             }
+            module_info.replace_section(SectionId::Data.into(), &data_sec_builder)?;
+        }
+
+        match self.gas_global {
+            Some(gas_global) => module_info.update_global_names(
+                |idx| self.global(idx),
+                &[(gas_global, GAS_COUNTER_NAME.into())],
+            ),
+            None => Ok(()),
         }
-        // Copy over the original instruction.
-        new_func.instruction(&DefaultTranslator.translate_op(instr)?);
     }
+}
 
-    if block_iter.next().is_some() {
-        return Err(anyhow!("metered blocks should be all consumed"));
+impl Translator for IndexRemap {
+    fn as_obj(&self) -> &dyn Translator {
+        self
     }
-    if instr_iter.next().is_some() {
-        return Err(anyhow!("metered instructions should be all consumed"));
+
+    fn remap(&self, item: Item, idx: u32) -> Result<u32> {
+        Ok(match item {
+            Item::Global => self.global(idx),
+            Item::Function => self.func(idx),
+            _ => idx,
+        })
     }
+}
 
-    Ok(new_func)
+/// The code charging gas from a gas counter kept by the module, either in the gas charging
+/// function or inlined.
+struct GasCharge {
+    gas_global: u32,
+    charge_mode: ChargeMode,
+    counter_type: CounterType,
+    /// Executed when the charge can't be paid.
+    out_of_gas: Vec<Instruction<'static>>,
 }
 
-fn add_gas_global_import(module: &mut ModuleInfo, gas_module_name: &str) -> Result<()> {
-    let mut import_decoder = ImportSection::new();
-    if let Some(import_sec) = module.raw_sections.get_mut(&SectionId::Import.into()) {
-        let import_sec_reader = ImportSectionReader::new(&import_sec.data, 0)?;
-        for import in import_sec_reader {
-            DefaultTranslator.translate_import(import?, &mut import_decoder)?;
-        }
-    }
+impl GasCharge {
+    /// Returns the instructions charging the `i64` amount of gas pushed by `charge`.
+    fn instructions(&self, charge: Instruction<'static>) -> Vec<Instruction<'static>> {
+        use wasm_encoder::Instruction::*;
+        let gas_global = self.gas_global;
+        let less_than = match self.counter_type {
+            CounterType::Signed => I64LtS,
+            CounterType::Unsigned => I64LtU,
+        };
+        let mut instructions = match (self.charge_mode, self.counter_type) {
+            (ChargeMode::SubtractThenCheck, CounterType::Signed) => vec![
+                GlobalGet(gas_global),
+                charge.clone(),
+                I64Sub,
+                GlobalSet(gas_global),
+                GlobalGet(gas_global),
+                I64Const(0),
+                I64LtS,
+                If(BlockType::Empty),
+            ],
+            // The subtraction wraps around, compare the counter to the charge beforehand.
+            (ChargeMode::SubtractThenCheck, CounterType::Unsigned) => vec![
+                GlobalGet(gas_global),
+                charge.clone(),
+                I64LtU,
+                GlobalGet(gas_global),
+                charge.clone(),
+                I64Sub,
+                GlobalSet(gas_global),
+                If(BlockType::Empty),
+            ],
+            (ChargeMode::KeepOnFailure | ChargeMode::ZeroOnFailure, _) => vec![
+                GlobalGet(gas_global),
+                charge.clone(),
+                less_than,
+                If(BlockType::Empty),
+            ],
+        };
+        if self.charge_mode == ChargeMode::ZeroOnFailure {
+            instructions.extend([I64Const(0), GlobalSet(gas_global)]);
+        }
+        instructions.extend_from_slice(&self.out_of_gas);
+        instructions.push(End);
+        if self.charge_mode != ChargeMode::SubtractThenCheck {
+            instructions.extend([GlobalGet(gas_global), charge, I64Sub, GlobalSet(gas_global)]);
+        }
+        instructions
+    }
 
-    import_decoder.import(
-        gas_module_name,
-        GAS_COUNTER_NAME,
-        wasm_encoder::GlobalType {
-            val_type: ValType::I64,
-            mutable: true,
-        },
-    );
-    module.imported_globals_count += 1;
-    module.replace_section(SectionId::Import.into(), &import_decoder)
+    /// Generates the gas charging function, charging the amount of gas given as its parameter.
+    fn function(&self) -> (Type, Function) {
+        let mut func = wasm_encoder::Function::new(None);
+        let mut instructions = self.instructions(Instruction::LocalGet(0));
+        instructions.push(Instruction::End);
+        instructions.iter().for_each(|instr| {
+            func.instruction(instr);
+        });
+        (
+            Type::Func(FuncType::new(vec![wasmparser::ValType::I64], vec![])),
+            func,
+        )
+    }
+}
+
+/// Emits the gas charges of `insert_metering_calls`, either as calls to the gas charging function
+/// or inlined according to the [`Inlining`] option.
+#[derive(Clone, Copy)]
+struct Charger<'a> {
+    gas_func: u32,
+    /// The cost of a static charge, added to the cost of the metered blocks.
+    charge_cost: u64,
+    /// Set if the gas counter is kept by the module.
+    inline: Option<(&'a GasCharge, Inlining)>,
+}
+
+impl Charger<'_> {
+    /// Returns the code to inline for a charge in a metered block of `len` instructions, if it
+    /// should be inlined. `len` is `None` for dynamic charges.
+    fn inlined(&self, in_loop: bool, len: Option<usize>) -> Option<&GasCharge> {
+        match self.inline {
+            Some((gas_charge, Inlining::Heuristic { max_block_len })) => {
+                let short = len.map_or(false, |len| len <= max_block_len);
+                (in_loop || short).then(|| gas_charge)
+            }
+            Some((gas_charge, Inlining::Always)) => Some(gas_charge),
+            _ => None,
+        }
+    }
+
+    /// Adds `amount` back to the gas counter, which must be kept by the module.
+    fn refund(&self, func: &mut Function, amount: u64) -> Result<()> {
+        let gas_global = self
+            .inline
+            .map(|(gas_charge, _)| gas_charge.gas_global)
+            .ok_or_else(|| anyhow!("refunds require a gas counter global"))?;
+        for instr in [
+            Instruction::GlobalGet(gas_global),
+            Instruction::I64Const(amount as i64),
+            Instruction::I64Add,
+            Instruction::GlobalSet(gas_global),
+        ] {
+            func.instruction(&instr);
+        }
+        Ok(())
+    }
+
+    /// Charges the `i64` amount of gas on top of the stack. Uses the `i64` local `temp_local`
+    /// if the charge is inlined.
+    fn charge_stack_top(&self, func: &mut Function, in_loop: bool, temp_local: u32) {
+        match self.inlined(in_loop, None) {
+            Some(gas_charge) => {
+                func.instruction(&Instruction::LocalSet(temp_local));
+                gas_charge
+                    .instructions(Instruction::LocalGet(temp_local))
+                    .iter()
+                    .for_each(|instr| {
+                        func.instruction(instr);
+                    });
+            }
+            None => {
+                func.instruction(&Instruction::Call(self.gas_func));
+            }
+        }
+    }
 }
 
-fn check_offset_code(code: &[Operator]) -> bool {
-    matches!(code, [Operator::I32Const { value: _ }, Operator::End])
+/// Finds the counted loops described in [`HoistedLoop`] and removes the metered blocks of their
+/// bodies from `blocks`. Loops whose total cost with `overhead` could overflow an `i64` are left
+/// alone.
+fn hoist_loop_charges(
+    func_body: &wasmparser::FunctionBody,
+    blocks: &mut Vec<MeteredBlock>,
+    overhead: u64,
+) -> Result<Vec<HoistedLoop>> {
+    let operators = func_body
+        .get_operators_reader()?
+        .into_iter()
+        .collect::<wasmparser::Result<Vec<Operator>>>()?;
+
+    let mut hoisted = Vec::new();
+    for pos in 0..operators.len() {
+        let (body_end, counter, bound, condition) = match counted_loop(&operators, pos) {
+            Some(counted_loop) => counted_loop,
+            None => continue,
+        };
+        let body_start = pos + 1;
+
+        // The body must be charged as a single metered block
+        let block_index = match blocks
+            .iter()
+            .position(|block| block.start_pos == body_start)
+        {
+            Some(index) => index,
+            None => continue,
+        };
+        if blocks
+            .get(block_index + 1)
+            .map_or(false, |block| block.start_pos <= body_end + 1)
+        {
+            continue;
+        }
+
+        // There are at most 2^32 iterations
+        let body_cost = blocks[block_index].cost;
+        let fits = body_cost
+            .checked_mul(1 << 32)
+            .and_then(|cost| cost.checked_add(overhead))
+            .map_or(false, |cost| cost <= i64::MAX as u64);
+        if !fits {
+            continue;
+        }
+
+        blocks.remove(block_index);
+        hoisted.push(HoistedLoop {
+            pos,
+            body_cost,
+            overhead,
+            counter,
+            bound,
+            condition,
+        });
+    }
+    Ok(hoisted)
 }
 
-fn instruction_stack_top_type(instr: &Operator<'_>) -> Result<ValType> {
+/// Matches the counted loop described in [`HoistedLoop`] starting at `pos`, returning the position
+/// of the final `br_if 0` of its body, its counter, its bound and its condition.
+fn counted_loop(
+    operators: &[Operator],
+    pos: usize,
+) -> Option<(usize, u32, LoopBound, LoopCondition)> {
     use wasmparser::Operator::*;
 
-    match instr {
-        // Note: may not trap on negative arg
-        // https://webassembly.github.io/spec/core/exec/instructions.html#xref-syntax-instructions-syntax-instr-memory-mathsf-memory-grow
-        MemoryGrow { .. }
+    if !matches!(
+        operators.get(pos),
+        Some(Loop {
+            blockty: wasmparser::BlockType::Empty
+        })
+    ) {
+        return None;
+    }
 
-        | TableGrow { .. }
+    // The body must be straight-line code, up to the final `br_if 0`
+    let body_start = pos + 1;
+    let body_end = body_start + operators[body_start..].iter().position(is_control)?;
+    match operators.get(body_end..body_end + 2) {
+        Some([BrIf { relative_depth: 0 }, End]) => {}
+        _ => return None,
+    }
+    let body = &operators[body_start..body_end];
+    let (counter, bound, condition) = match body {
+        [.., LocalGet { local_index: get }, I32Const { value: 1 }, I32Add, LocalTee { local_index: tee }, bound, condition]
+            if get == tee =>
+        {
+            let bound = match bound {
+                LocalGet { local_index } if local_index != tee => LoopBound::Local(*local_index),
+                I32Const { value } => LoopBound::Const(*value),
+                _ => return None,
+            };
+            let condition = match condition {
+                I32LtU => LoopCondition::LtU,
+                I32LtS => LoopCondition::LtS,
+                I32Ne => LoopCondition::Ne,
+                _ => return None,
+            };
+            (*tee, bound, condition)
+        }
+        _ => return None,
+    };
+    let is_invariant = |index: u32| {
+        body[..body.len() - 3].iter().all(|op| match op {
+            LocalSet { local_index } | LocalTee { local_index } => *local_index != index,
+            _ => true,
+        })
+    };
+    if !is_invariant(counter) {
+        return None;
+    }
+    if let LoopBound::Local(index) = bound {
+        if !is_invariant(index) {
+            return None;
+        }
+    }
+    Some((body_end, counter, bound, condition))
+}
 
-        // Note: may not trap on negative arg, and/or may be very expensive
-        // https://webassembly.github.io/spec/core/exec/instructions.html#xref-syntax-instructions-syntax-instr-memory-mathsf-memory-init-x
-        | MemoryInit { .. }
+/// Returns whether the instruction can transfer control or delimits a block.
+fn is_control(op: &Operator) -> bool {
+    use wasmparser::Operator::*;
+    matches!(
+        op,
+        Unreachable
+            | Block { .. }
+            | Loop { .. }
+            | If { .. }
+            | Else
+            | Try { .. }
+            | Catch { .. }
+            | Throw { .. }
+            | Rethrow { .. }
+            | Delegate { .. }
+            | CatchAll
+            | End
+            | Br { .. }
+            | BrIf { .. }
+            | BrTable { .. }
+            | Return
+            | ReturnCall { .. }
+            | ReturnCallIndirect { .. }
+    )
+}
 
-        // Note: may not trap on negative arg
-        // https://webassembly.github.io/spec/core/exec/instructions.html#xref-syntax-instructions-syntax-instr-memory-mathsf-memory-grow
-        | MemoryCopy { .. }
+/// A function metered by [`inject_counter`].
+struct MeteredFunction<'a> {
+    /// Index of the function in the function index space.
+    func_index: u32,
+    /// Index of the function among the functions defined by the module.
+    defined_index: u32,
+    param_count: u32,
+    types: &'a ModuleTypes,
+}
 
-        // Note: may not trap on negative arg, and/or may be very expensive
-        // https://webassembly.github.io/spec/core/exec/instructions.html#xref-syntax-instructions-syntax-instr-memory-mathsf-memory-fill
-        | MemoryFill { .. }
+/// The types of a module needed to know the operands of the linearly priced instructions.
+#[derive(Debug, Default)]
+struct ModuleTypes {
+    /// The parameters of each function type.
+    params: Vec<Vec<ValType>>,
+    /// The type index of each function.
+    functions: Vec<u32>,
+    /// The element type of each table.
+    tables: Vec<ValType>,
+    /// Whether each memory is a 64-bit memory.
+    memory64: Vec<bool>,
+    /// The module and name of each imported function.
+    imports: Vec<(String, String)>,
+}
 
-        | TableInit { .. }
-        | TableCopy { .. }
-        | TableFill { .. } => Ok(ValType::I32),
+impl ModuleTypes {
+    fn new(module: &ModuleInfo) -> Result<Self> {
+        let params = module
+            .types_map
+            .iter()
+            .map(|Type::Func(ty)| {
+                ty.params()
+                    .iter()
+                    .map(|param| DefaultTranslator.translate_ty(param))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let tables = module
+            .table_elem_types
+            .iter()
+            .map(|ty| DefaultTranslator.translate_ty(&ty.element_type))
+            .collect::<Result<Vec<_>>>()?;
+        let mut imports = Vec::new();
+        if let Some(import_section) = module.raw_sections.get(&SectionId::Import.into()) {
+            for import in ImportSectionReader::new(&import_section.data, 0)? {
+                let import = import?;
+                if let TypeRef::Func(_) = import.ty {
+                    imports.push((import.module.into(), import.name.into()));
+                }
+            }
+        }
+        Ok(ModuleTypes {
+            params,
+            functions: module.function_map.clone(),
+            tables,
+            memory64: module.memory_types.iter().map(|ty| ty.memory64).collect(),
+            imports,
+        })
+    }
 
-        _ => Err(anyhow!("instruction not supported")),
+    /// Returns the cost added by the rules to the given instruction if it calls an imported
+    /// function. The imports of the original module keep their indices when instrumenting it.
+    fn import_call_cost<R: RulesExt>(&self, rules: &R, instruction: &Operator) -> u64 {
+        match instruction {
+            Operator::Call { function_index } | Operator::ReturnCall { function_index } => self
+                .imports
+                .get(*function_index as usize)
+                .map_or(0, |(module, name)| rules.import_call_cost(module, name)),
+            _ => 0,
+        }
+    }
+
+    fn func_params(&self, type_index: u32) -> Result<&[ValType]> {
+        self.params
+            .get(type_index as usize)
+            .map(Vec::as_slice)
+            .ok_or_else(|| anyhow!("type {} not found", type_index))
+    }
+
+    fn call_params(&self, function_index: u32) -> Result<&[ValType]> {
+        let type_index = self
+            .functions
+            .get(function_index as usize)
+            .ok_or_else(|| anyhow!("function {} not found", function_index))?;
+        self.func_params(*type_index)
+    }
+
+    fn table_type(&self, table: u32) -> Result<ValType> {
+        self.tables
+            .get(table as usize)
+            .copied()
+            .ok_or_else(|| anyhow!("table {} not found", table))
+    }
+
+    /// Returns the type of the addresses of the given memory.
+    fn index_type(&self, mem: u32) -> Result<ValType> {
+        match self.memory64.get(mem as usize) {
+            Some(true) => Ok(ValType::I64),
+            Some(false) => Ok(ValType::I32),
+            None => Err(anyhow!("memory {} not found", mem)),
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use wasm_encoder::{Encode, Instruction::*};
-    use wasmparser::FunctionBody;
+fn inject_counter<R: RulesExt>(
+    instructions: &wasmparser::FunctionBody,
+    rules: &R,
+    func: &MeteredFunction,
+    charger: Charger,
+    options: &InjectOptions,
+) -> Result<(wasm_encoder::Function, FunctionReport)> {
+    let func_index = func.func_index;
+    let (mut blocks, metered_instrs) = determine_metered_blocks(
+        instructions,
+        rules,
+        func_index,
+        func.defined_index,
+        func.types,
+        options.merge_blocks,
+    )?;
+    if options.merge_blocks {
+        superblock::merge_metered_blocks(instructions, &mut blocks)?;
+    }
+    let charge_cost = charger.charge_cost;
+
+    // The refunds must fit in an `i64` along with the charge
+    let upfront_charge = if options.charge_upfront && charger.inline.is_some() {
+        upfront::charge_upfront(instructions, &blocks)?
+            .filter(|charge| charge.cost.checked_add(charge_cost) <= Some(i64::MAX as u64))
+    } else {
+        None
+    };
+    let refunds = match upfront_charge {
+        Some(upfront_charge) => {
+            blocks = vec![MeteredBlock {
+                start_pos: 0,
+                cost: upfront_charge.cost,
+            }];
+            upfront_charge.refunds
+        }
+        None => Vec::new(),
+    };
+
+    let hoist_overhead = charge_cost
+        .checked_add(rules.linear_calc_cost())
+        .ok_or(InstrumentError::CostOverflow { func_index })?;
+    let hoisted_loops = if options.hoist_loop_charges {
+        hoist_loop_charges(instructions, &mut blocks, hoist_overhead)?
+    } else {
+        Vec::new()
+    };
+
+    // The charge of every block, including the cost of the charge itself, is pushed by an
+    // `i64.const`. Only an unsigned gas counter takes it as a `u64`, a negative charge would add
+    // gas to a signed counter or be passed as such to the host function.
+    let max_charge = match (&options.gas_counter, options.counter_type) {
+        (GasCounter::HostFunction { .. }, _) | (_, CounterType::Signed) => i64::MAX as u64,
+        (_, CounterType::Unsigned) => u64::MAX,
+    };
+    if blocks.iter().any(|block| {
+        !matches!(charge_cost.checked_add(block.cost), Some(charge) if charge <= max_charge)
+    }) {
+        return Err(InstrumentError::CostOverflow { func_index }.into());
+    }
 
-    fn check_expect_function_body(
-        raw_wasm: &[u8],
-        index: usize,
-        ops2: &[wasm_encoder::Instruction],
-    ) -> bool {
-        let mut body_raw = vec![];
-        ops2.iter().for_each(|v| v.encode(&mut body_raw));
-        get_function_body(raw_wasm, index).eq(&body_raw)
+    let report = FunctionReport {
+        func_index,
+        metered_blocks: blocks.len(),
+        dynamic_charges: metered_instrs.len(),
+        hoisted_loops: hoisted_loops.len(),
+        refunds: refunds.len(),
+        static_cost: blocks.iter().fold(0u64, |acc, block| {
+            acc.saturating_add(charge_cost.saturating_add(block.cost))
+        }),
+    };
+
+    let func = insert_metering_calls(
+        instructions,
+        blocks,
+        metered_instrs,
+        hoisted_loops,
+        refunds,
+        func.param_count,
+        charger,
+    )?;
+    Ok((func, report))
+}
+
+// Then insert metering calls into a sequence of instructions given the block locations and costs.
+fn insert_metering_calls(
+    func_body: &wasmparser::FunctionBody,
+    blocks: Vec<MeteredBlock>,
+    instructions: Vec<MeteredInstruction>,
+    hoisted_loops: Vec<HoistedLoop>,
+    refunds: Vec<Refund>,
+    param_count: u32,
+    charger: Charger,
+) -> Result<wasm_encoder::Function> {
+    // collect value types on which we will be doing dynamic gas math.
+    // We need those for temp locals because wasm has no other way to duplicate stack items..
+    let has_i32_temp = instructions
+        .iter()
+        .any(|instr| instr.operand_type == ValType::I32);
+    let has_i64_temp = instructions
+        .iter()
+        .any(|instr| instr.operand_type == ValType::I64);
+    // costs other than linear ones need two more i64 temp locals for their arithmetic
+    let has_cost_temps = instructions
+        .iter()
+        .any(|instr| !matches!(instr.cost, OperandCost::Linear(_)));
+
+    let mut locals = copy_locals(func_body)?;
+    let temp_local_idx = param_count + (&locals).iter().fold(0, |acc, (count, _)| acc + count);
+
+    // Refunds when branching also keep the branch condition in the temp local
+    let needs_i32_temp = has_i32_temp
+        || refunds
+            .iter()
+            .any(|refund| refund.kind == RefundKind::Branch);
+    if needs_i32_temp {
+        locals.push((1, ValType::I32));
     }
 
-    fn get_function_body(raw_wasm: &[u8], index: usize) -> Vec<u8> {
-        let mut module = ModuleInfo::new(raw_wasm).unwrap();
-        let func_sec = module
-            .raw_sections
-            .get_mut(&SectionId::Code.into())
-            .unwrap();
-        let func_bodies = wasmparser::CodeSectionReader::new(&func_sec.data, 0)
-            .unwrap()
-            .into_iter()
-            .collect::<wasmparser::Result<Vec<FunctionBody>>>()
-            .unwrap();
+    // i64 stack tops, inlined dynamic charges and hoisted loop charges need another temp local
+    let i64_temp_local_idx = temp_local_idx + u32::from(needs_i32_temp);
+    let inlines = !matches!(charger.inline, None | Some((_, Inlining::Never)));
+    if has_i64_temp
+        || has_cost_temps
+        || (!instructions.is_empty() && inlines)
+        || !hoisted_loops.is_empty()
+    {
+        locals.push((1, ValType::I64));
+    }
+    let charge_locals = ChargeLocals {
+        operand: temp_local_idx,
+        units: i64_temp_local_idx + 1,
+        memory_size: i64_temp_local_idx + 2,
+        temp: i64_temp_local_idx,
+    };
+    if has_cost_temps {
+        locals.push((2, ValType::I64));
+    }
 
-        let func_body = func_bodies
-            .get(index)
-            .unwrap_or_else(|| panic!("module don't have function {}body", index));
+    // The operands above the linearly priced ones are spilled into locals of their type, shared by
+    // all instructions
+    let spill_local_idx = param_count + locals.iter().fold(0, |acc, (count, _)| acc + count);
+    let mut spill_groups: Vec<(ValType, u32)> = Vec::new();
+    for instr in &instructions {
+        for ty in &instr.spilled {
+            let count = instr.spilled.iter().filter(|other| *other == ty).count() as u32;
+            match spill_groups.iter_mut().find(|(group_ty, _)| group_ty == ty) {
+                Some((_, group_count)) => *group_count = (*group_count).max(count),
+                None => spill_groups.push((*ty, count)),
+            }
+        }
+    }
+    locals.extend(spill_groups.iter().map(|(ty, count)| (*count, *ty)));
 
-        let list = func_body
-            .get_operators_reader()
-            .unwrap()
-            .into_iter()
-            .map(|op| DefaultTranslator.translate_op(&op.unwrap()).unwrap())
-            .collect::<Vec<Instruction>>();
+    // To do this in linear time, construct a new vector of instructions, copying over old
+    // instructions one by one and injecting new ones as required.
+    let mut new_func = wasm_encoder::Function::new(locals);
 
-        println!("{:?}", list);
+    let operators = func_body
+        .get_operators_reader()
+        .unwrap()
+        .into_iter()
+        .collect::<wasmparser::Result<Vec<Operator>>>()
+        .unwrap();
+    // The number of instructions of each block, up to the start of the next one
+    let block_lens = blocks
+        .iter()
+        .skip(1)
+        .map(|block| block.start_pos)
+        .chain([operators.len()])
+        .zip(&blocks)
+        .map(|(end, block)| end.saturating_sub(block.start_pos))
+        .collect::<Vec<_>>();
+    let mut block_iter = blocks.iter().zip(block_lens).peekable();
+    let mut instr_iter = instructions.into_iter().peekable();
+    let mut loop_iter = hoisted_loops.into_iter().peekable();
+    let mut refund_iter = refunds.into_iter().peekable();
+    // Whether the instructions are in a loop
+    let mut tracker = ContextTracker::default();
+    for (original_pos, instr) in operators.iter().enumerate() {
+        // If there the next block starts at this position, inject metering func_body.
+        if let Some((block, len)) = block_iter.peek() {
+            if block.start_pos == original_pos {
+                let charge =
+                    wasm_encoder::Instruction::I64Const((charger.charge_cost + block.cost) as i64);
+                match charger.inlined(tracker.in_loop(), Some(*len)) {
+                    Some(gas_charge) => gas_charge.instructions(charge).iter().for_each(|instr| {
+                        new_func.instruction(instr);
+                    }),
+                    None => {
+                        new_func.instruction(&charge);
+                        new_func.instruction(&wasm_encoder::Instruction::Call(charger.gas_func));
+                    }
+                }
 
-        let start = func_body
-            .get_operators_reader()
-            .unwrap()
-            .original_position();
-        func_sec.data[start..func_body.range().end].to_vec()
+                block_iter.next();
+            }
+        }
+
+        // Refund the gas charged up front for the paths not taken
+        while let Some(refund) = refund_iter.next_if(|refund| refund.pos == original_pos) {
+            match refund.kind {
+                RefundKind::Before => charger.refund(&mut new_func, refund.amount)?,
+                RefundKind::Else => {
+                    new_func.instruction(&wasm_encoder::Instruction::Else);
+                    charger.refund(&mut new_func, refund.amount)?;
+                }
+                RefundKind::Branch => {
+                    new_func.instruction(&wasm_encoder::Instruction::LocalTee(temp_local_idx));
+                    new_func.instruction(&wasm_encoder::Instruction::If(BlockType::Empty));
+                    charger.refund(&mut new_func, refund.amount)?;
+                    new_func.instruction(&wasm_encoder::Instruction::End);
+                    new_func.instruction(&wasm_encoder::Instruction::LocalGet(temp_local_idx));
+                }
+            }
+        }
+
+        // If this is a hoisted loop, charge all of its iterations
+        if let Some(hoisted_loop) = loop_iter.peek() {
+            if hoisted_loop.pos == original_pos {
+                hoisted_loop
+                    .charge(i64_temp_local_idx)
+                    .iter()
+                    .for_each(|instr| {
+                        new_func.instruction(instr);
+                    });
+                charger.charge_stack_top(&mut new_func, tracker.in_loop(), i64_temp_local_idx);
+
+                loop_iter.next();
+            }
+        }
+
+        // if this instruction requires dynamic gas charge calculation, inject that code
+        if let Some(metered_instr) = instr_iter.peek() {
+            if metered_instr.pos == original_pos {
+                // move the operands above the linearly priced one out of the way
+                let spill_locals =
+                    spill_locals(&metered_instr.spilled, &spill_groups, spill_local_idx);
+                for local in spill_locals.iter().rev() {
+                    new_func.instruction(&wasm_encoder::Instruction::LocalSet(*local));
+                }
+
+                if let OperandCost::Linear(unit_cost) = metered_instr.cost {
+                    if metered_instr.operand_type == ValType::I64 {
+                        charge_i64_stack_top(&mut new_func, unit_cost, i64_temp_local_idx);
+                    } else {
+                        // duplicate stack top
+                        // save into temp local
+                        new_func.instruction(&wasm_encoder::Instruction::LocalTee(temp_local_idx));
+
+                        // one copy to do math for gas charge
+                        new_func.instruction(&wasm_encoder::Instruction::LocalGet(temp_local_idx));
+
+                        // NOTE(negative bulk instruction arg):
+                        // right now this instrumentation is mostly meant for bulk memory instructions
+                        // In the formal spec instructions are NOT required to trap when the "count" argument
+                        // is negative, and if the spec is followed exactly, those instructions may take
+                        // very long to trap with a negative argument.
+                        //
+                        // e.g. see https://webassembly.github.io/spec/core/exec/instructions.html#xref-syntax-instructions-syntax-instr-memory-mathsf-memory-init-x
+                        // To guard against this we use unsigned extend instructions.
+                        // This means that e.g. -1_i32 becomes 0x0000_0000_ffff_ffff
+                        new_func.instruction(&wasm_encoder::Instruction::I64ExtendI32U);
+
+                        // calculate gas charge
+                        new_func
+                            .instruction(&wasm_encoder::Instruction::I64Const(unit_cost as i64));
+                        new_func.instruction(&wasm_encoder::Instruction::I64Mul);
+                    }
+                } else {
+                    let memory = match instr {
+                        Operator::MemoryGrow { mem, .. } if metered_instr.cost.total_memory() => {
+                            Some(*mem)
+                        }
+                        _ => None,
+                    };
+                    metered_instr
+                        .cost
+                        .charge(metered_instr.operand_type, memory, charge_locals)
+                        .iter()
+                        .for_each(|instr| {
+                            new_func.instruction(instr);
+                        });
+                }
+
+                // charge gas!
+                charger.charge_stack_top(&mut new_func, tracker.in_loop(), i64_temp_local_idx);
+
+                // and put them back
+                for local in &spill_locals {
+                    new_func.instruction(&wasm_encoder::Instruction::LocalGet(*local));
+                }
+
+                instr_iter.next();
+            }
+        }
+
+        tracker.update(instr);
+        // Copy over the original instruction.
+        new_func.instruction(&DefaultTranslator.translate_op(instr)?);
     }
 
-    fn parse_wat(source: &str) -> ModuleInfo {
-        let module_bytes = wat::parse_str(source).unwrap();
-        ModuleInfo::new(&module_bytes).unwrap()
+    if block_iter.next().is_some() {
+        return Err(anyhow!("metered blocks should be all consumed"));
+    }
+    if instr_iter.next().is_some() {
+        return Err(anyhow!("metered instructions should be all consumed"));
+    }
+    if loop_iter.next().is_some() {
+        return Err(anyhow!("hoisted loops should be all consumed"));
+    }
+    if refund_iter.next().is_some() {
+        return Err(anyhow!("refunds should be all consumed"));
     }
 
-    #[test]
-    fn gas_charge_charge() {
-        pub struct TestRules {}
-        impl Rules for TestRules {
+    Ok(new_func)
+}
+
+/// Returns the locals holding the spilled operands of the given types, from the bottom of the stack.
+///
+/// `groups` are the types and numbers of the locals for spilled operands, declared in this order
+/// from `first_local`.
+fn spill_locals(spilled: &[ValType], groups: &[(ValType, u32)], first_local: u32) -> Vec<u32> {
+    let mut next_locals = groups
+        .iter()
+        .scan(first_local, |next, (_, count)| {
+            let first = *next;
+            *next += count;
+            Some(first)
+        })
+        .collect::<Vec<_>>();
+    spilled
+        .iter()
+        .map(|ty| {
+            let group = groups
+                .iter()
+                .position(|(group_ty, _)| group_ty == ty)
+                .expect("there is a group of locals for each spilled type; qed");
+            next_locals[group] += 1;
+            next_locals[group] - 1
+        })
+        .collect()
+}
+
+/// Pushes the charge of an `i64` stack top priced `unit_cost` per unit, keeping a copy of the stack
+/// top in `temp_local_idx`.
+///
+/// The stack top is treated as unsigned (see "NOTE(negative bulk instruction arg)") and the charge
+/// saturates at `i64::MAX` instead of wrapping around to a cheaper one.
+fn charge_i64_stack_top(func: &mut wasm_encoder::Function, unit_cost: u32, temp_local_idx: u32) {
+    use wasm_encoder::Instruction::*;
+
+    for instr in [
+        LocalTee(temp_local_idx),
+        I64Const(i64::MAX),
+        LocalGet(temp_local_idx),
+        I64Const(unit_cost as i64),
+        I64Mul,
+        // Select the saturated charge if the product overflows
+        LocalGet(temp_local_idx),
+        I64Const(i64::MAX / unit_cost as i64),
+        I64GtU,
+        Select,
+    ] {
+        func.instruction(&instr);
+    }
+}
+
+fn add_gas_global_import(module: &mut ModuleInfo, gas_module_name: &str) -> Result<()> {
+    let mut import_decoder = ImportSection::new();
+    if let Some(import_sec) = module.raw_sections.get_mut(&SectionId::Import.into()) {
+        let import_sec_reader = ImportSectionReader::new(&import_sec.data, 0)?;
+        for import in import_sec_reader {
+            DefaultTranslator.translate_import(import?, &mut import_decoder)?;
+        }
+    }
+
+    import_decoder.import(
+        gas_module_name,
+        GAS_COUNTER_NAME,
+        wasm_encoder::GlobalType {
+            val_type: ValType::I64,
+            mutable: true,
+        },
+    );
+    module.global_types.insert(
+        module.imported_globals_count as usize,
+        wasmparser::GlobalType {
+            content_type: wasmparser::ValType::I64,
+            mutable: true,
+        },
+    );
+    module.imported_globals_count += 1;
+    module.replace_section(SectionId::Import.into(), &import_decoder)
+}
+
+/// Imports a host function taking `params` after the functions imported by the module, returning
+/// its index.
+fn add_func_import(
+    module: &mut ModuleInfo,
+    module_name: &str,
+    name: &str,
+    params: Vec<wasmparser::ValType>,
+) -> Result<u32> {
+    let func_type = Type::Func(FuncType::new(params, vec![]));
+    let type_index = module.add_func_type(&func_type)?;
+
+    let mut import_decoder = ImportSection::new();
+    if let Some(import_sec) = module.raw_sections.get(&SectionId::Import.into()) {
+        let import_sec_reader = ImportSectionReader::new(&import_sec.data, 0)?;
+        for import in import_sec_reader {
+            DefaultTranslator.translate_import(import?, &mut import_decoder)?;
+        }
+    }
+    import_decoder.import(module_name, name, EntityType::Function(type_index));
+
+    let func_index = module.imported_functions_count;
+    module.function_map.insert(func_index as usize, type_index);
+    module.imported_functions_count += 1;
+    module.replace_section(SectionId::Import.into(), &import_decoder)?;
+    Ok(func_index)
+}
+
+/// Appends a mutable global to the globals defined by the module and exports it as `name`,
+/// returning its index.
+fn add_global_export(
+    module: &mut ModuleInfo,
+    name: &str,
+    content_type: wasmparser::ValType,
+    init: &wasm_encoder::ConstExpr,
+) -> Result<u32> {
+    let mut global_sec_builder = GlobalSection::new();
+    if let Some(global_sec) = module.raw_sections.get(&SectionId::Global.into()) {
+        for global in GlobalSectionReader::new(&global_sec.data, 0)? {
+            DefaultTranslator.translate_global(global?, &mut global_sec_builder)?;
+        }
+    }
+    global_sec_builder.global(
+        wasm_encoder::GlobalType {
+            val_type: DefaultTranslator.translate_ty(&content_type)?,
+            mutable: true,
+        },
+        init,
+    );
+
+    let mut export_sec_builder = ExportSection::new();
+    if let Some(export_sec) = module.raw_sections.get(&SectionId::Export.into()) {
+        for export in ExportSectionReader::new(&export_sec.data, 0)? {
+            let export = export?;
+            if export.name == name {
+                return Err(InstrumentError::DuplicateExport(name.into()).into());
+            }
+            DefaultTranslator.translate_export(&export, &mut export_sec_builder)?;
+        }
+    }
+    let index = module.num_globals();
+    export_sec_builder.export(name, ExportKind::Global, index);
+
+    module.global_types.push(wasmparser::GlobalType {
+        content_type,
+        mutable: true,
+    });
+    module.replace_section(SectionId::Global.into(), &global_sec_builder)?;
+    module.replace_section(SectionId::Export.into(), &export_sec_builder)?;
+    Ok(index)
+}
+
+/// Checks that a segment offset is either an `i32.const` or a `global.get` of an immutable `i32`
+/// global imported by the original module, i.e. one of the first `imported_globals_count`.
+fn check_offset_code(
+    module_info: &ModuleInfo,
+    imported_globals_count: u32,
+    expr: &wasmparser::ConstExpr,
+) -> Result<bool> {
+    let code = expr
+        .get_operators_reader()
+        .into_iter()
+        .collect::<wasmparser::Result<Vec<Operator>>>()?;
+    Ok(match code[..] {
+        [Operator::I32Const { .. }, Operator::End] => true,
+        [Operator::GlobalGet { global_index }, Operator::End]
+            if global_index < imported_globals_count =>
+        {
+            module_info
+                .global_types
+                .get(global_index as usize)
+                .map_or(false, |g| {
+                    !g.mutable && g.content_type == wasmparser::ValType::I32
+                })
+        }
+        _ => false,
+    })
+}
+
+/// Returns the types of the operands of a linearly priced instruction, from the bottom of the stack.
+fn instruction_operand_types(instr: &Operator<'_>, types: &ModuleTypes) -> Result<Vec<ValType>> {
+    use wasmparser::Operator::*;
+
+    match instr {
+        // Note: may not trap on negative arg
+        // https://webassembly.github.io/spec/core/exec/instructions.html#xref-syntax-instructions-syntax-instr-memory-mathsf-memory-grow
+        MemoryGrow { mem, .. } => Ok(vec![types.index_type(*mem)?]),
+
+        // Note: may not trap on negative arg, and/or may be very expensive
+        // https://webassembly.github.io/spec/core/exec/instructions.html#xref-syntax-instructions-syntax-instr-memory-mathsf-memory-fill
+        MemoryFill { mem } => {
+            let index_type = types.index_type(*mem)?;
+            Ok(vec![index_type, ValType::I32, index_type])
+        }
+
+        // Note: may not trap on negative arg
+        // https://webassembly.github.io/spec/core/exec/instructions.html#xref-syntax-instructions-syntax-instr-memory-mathsf-memory-grow
+        //
+        // The length is an `i64` only if both memories are 64-bit.
+        MemoryCopy { dst_mem, src_mem } => {
+            let dst_type = types.index_type(*dst_mem)?;
+            let src_type = types.index_type(*src_mem)?;
+            let len_type = if dst_type == ValType::I64 && src_type == ValType::I64 {
+                ValType::I64
+            } else {
+                ValType::I32
+            };
+            Ok(vec![dst_type, src_type, len_type])
+        }
+
+        // Note: may not trap on negative arg, and/or may be very expensive
+        // https://webassembly.github.io/spec/core/exec/instructions.html#xref-syntax-instructions-syntax-instr-memory-mathsf-memory-init-x
+        MemoryInit { mem, .. } => Ok(vec![types.index_type(*mem)?, ValType::I32, ValType::I32]),
+
+        TableGrow { table } => Ok(vec![types.table_type(*table)?, ValType::I32]),
+        TableFill { table } => Ok(vec![ValType::I32, types.table_type(*table)?, ValType::I32]),
+        TableInit { .. } | TableCopy { .. } => Ok(vec![ValType::I32; 3]),
+
+        Call { function_index } | ReturnCall { function_index } => {
+            Ok(types.call_params(*function_index)?.to_vec())
+        }
+        CallIndirect { type_index, .. } | ReturnCallIndirect { type_index, .. } => {
+            let mut operand_types = types.func_params(*type_index)?.to_vec();
+            operand_types.push(ValType::I32);
+            Ok(operand_types)
+        }
+
+        _ => Err(anyhow!("instruction not supported")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_encoder::{Encode, Instruction::*};
+    use wasmparser::{ExternalKind, FunctionBody};
+
+    fn check_expect_function_body(
+        raw_wasm: &[u8],
+        index: usize,
+        ops2: &[wasm_encoder::Instruction],
+    ) -> bool {
+        let mut body_raw = vec![];
+        ops2.iter().for_each(|v| v.encode(&mut body_raw));
+        get_function_body(raw_wasm, index).eq(&body_raw)
+    }
+
+    fn get_function_body(raw_wasm: &[u8], index: usize) -> Vec<u8> {
+        let mut module = ModuleInfo::new(raw_wasm).unwrap();
+        let func_sec = module
+            .raw_sections
+            .get_mut(&SectionId::Code.into())
+            .unwrap();
+        let func_bodies = wasmparser::CodeSectionReader::new(&func_sec.data, 0)
+            .unwrap()
+            .into_iter()
+            .collect::<wasmparser::Result<Vec<FunctionBody>>>()
+            .unwrap();
+
+        let func_body = func_bodies
+            .get(index)
+            .unwrap_or_else(|| panic!("module don't have function {}body", index));
+
+        let list = func_body
+            .get_operators_reader()
+            .unwrap()
+            .into_iter()
+            .map(|op| DefaultTranslator.translate_op(&op.unwrap()).unwrap())
+            .collect::<Vec<Instruction>>();
+
+        println!("{:?}", list);
+
+        let start = func_body
+            .get_operators_reader()
+            .unwrap()
+            .original_position();
+        func_sec.data[start..func_body.range().end].to_vec()
+    }
+
+    fn parse_wat(source: &str) -> ModuleInfo {
+        let module_bytes = wat::parse_str(source).unwrap();
+        ModuleInfo::new(&module_bytes).unwrap()
+    }
+
+    #[test]
+    fn gas_charge_charge() {
+        pub struct TestRules {}
+        impl Rules for TestRules {
+            fn instruction_cost(&self, _: &Operator) -> Result<InstructionCost> {
+                Ok(InstructionCost::Fixed(1))
+            }
+
+            fn gas_charge_cost(&self) -> u64 {
+                13
+            }
+            fn linear_calc_cost(&self) -> u64 {
+                99
+            }
+        }
+
+        let module = parse_wat(
+            r#"(module
+			(func (result i32)
+			  i32.const 10)
+			(memory 0 1)
+			)"#,
+        );
+
+        let raw_wasm = module.bytes();
+        let injected_raw_wasm = inject(&raw_wasm, &TestRules {}, "env").unwrap();
+
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            0,
+            &[
+                I64Const(14), // 1 + 13
+                Call(1),      // gas charge
+                I32Const(10),
+                End,
+            ]
+        ));
+
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+    }
+
+    #[test]
+    fn simple_grow() {
+        let module = parse_wat(
+            r#"(module
+            (func (result i32)
+              global.get 0
+              memory.grow)
+            (global i32 (i32.const 42))
+            (memory 0 1)
+            )"#,
+        );
+
+        let raw_wasm = module.bytes();
+        let injected_raw_wasm =
+            inject(&raw_wasm, &ConstantCostRules::new(1, 10_000), "env").unwrap();
+
+        // global0 - gas
+        // global1 - orig global0
+        // func0 - main
+        // func1 - gas_counter
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            0,
+            &[
+                I64Const(2),
+                Call(1),      // gas charge
+                GlobalGet(1), // original code
+                // <dynamic charge>
+                LocalTee(0),
+                LocalGet(0),
+                I64ExtendI32U,
+                I64Const(10000),
+                I64Mul,
+                Call(1),
+                // </dynamic charge>
+                MemoryGrow(0), // original code
+                End,
+            ]
+        ));
+
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+    }
+
+    #[test]
+    fn gas_charge_charge_const_linear() {
+        pub struct TestRules {}
+        impl Rules for TestRules {
+            fn instruction_cost(&self, i: &Operator) -> Result<InstructionCost> {
+                Ok(match i {
+                    Operator::MemoryGrow { .. } => {
+                        InstructionCost::Linear(17, NonZeroU32::new(7).unwrap())
+                    }
+                    _ => InstructionCost::Fixed(3),
+                })
+            }
+
+            fn gas_charge_cost(&self) -> u64 {
+                13
+            }
+            fn linear_calc_cost(&self) -> u64 {
+                5
+            }
+        }
+
+        let module = parse_wat(
+            r#"(module
+			(func (result i32)
+			  i32.const 10
+			  memory.grow 0)
+			(memory 0 1)
+			)"#,
+        );
+
+        let raw_wasm = module.bytes();
+        let injected_raw_wasm = inject(&raw_wasm, &TestRules {}, "env").unwrap();
+
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            0,
+            &[
+                I64Const(103), // 3*1 + 17 + 10*7 + 13*1
+                Call(1),       // gas charge
+                I32Const(10),
+                MemoryGrow(0),
+                End,
+            ]
+        ));
+
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+    }
+
+    #[test]
+    fn gas_charge_charge_dyn_linear() {
+        pub struct TestRules {}
+        impl Rules for TestRules {
+            fn instruction_cost(&self, i: &Operator) -> Result<InstructionCost> {
+                Ok(match i {
+                    Operator::MemoryGrow { .. } => {
+                        InstructionCost::Linear(17, NonZeroU32::new(7).unwrap())
+                    }
+                    _ => InstructionCost::Fixed(3),
+                })
+            }
+
+            fn gas_charge_cost(&self) -> u64 {
+                13
+            }
+            fn linear_calc_cost(&self) -> u64 {
+                5
+            }
+        }
+
+        let module = parse_wat(
+            r#"(module
+			(func (result i32)
+			  i32.const 10
+			  i32.const 1
+			  i32.mul
+			  memory.grow 0)
+			(memory 0 1)
+			)"#,
+        );
+
+        let raw_wasm = module.bytes();
+        let injected_raw_wasm = inject(&raw_wasm, &TestRules {}, "env").unwrap();
+
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            0,
+            &[
+                I64Const(57), // 3*3 + 17 + 13*2 + 5
+                Call(1),      // gas charge
+                I32Const(10),
+                I32Const(1),
+                I32Mul,
+                LocalTee(0),
+                LocalGet(0),
+                I64ExtendI32U,
+                I64Const(7),
+                I64Mul,
+                Call(1),
+                MemoryGrow(0),
+                End,
+            ]
+        ));
+
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+    }
+
+    #[test]
+    fn gas_charge_charge_dyn_linear_memory64() {
+        pub struct TestRules {}
+        impl Rules for TestRules {
+            fn instruction_cost(&self, i: &Operator) -> Result<InstructionCost> {
+                Ok(match i {
+                    Operator::MemoryGrow { .. } | Operator::MemoryFill { .. } => {
+                        InstructionCost::Linear(17, NonZeroU32::new(7).unwrap())
+                    }
+                    _ => InstructionCost::Fixed(3),
+                })
+            }
+
+            fn gas_charge_cost(&self) -> u64 {
+                13
+            }
+            fn linear_calc_cost(&self) -> u64 {
+                5
+            }
+        }
+
+        let module = parse_wat(
+            r#"(module
+			(func (param i32 i64)
+			  local.get 1
+			  memory.grow 1
+			  drop
+			  i32.const 0
+			  i32.const 0
+			  local.get 0
+			  memory.fill 0)
+			(memory 0 1)
+			(memory i64 0 1)
+			)"#,
+        );
+
+        let raw_wasm = module.bytes();
+        let injected_raw_wasm = inject(&raw_wasm, &TestRules {}, "env").unwrap();
+
+        // The length of `memory.fill` on the 32-bit memory is an `i32` and the number of pages of
+        // `memory.grow` on the 64-bit memory is an `i64`, each with its own temp local.
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            0,
+            &[
+                I64Const(98), // 3*5 + 17*2 + 13*3 + 5*2
+                Call(1),
+                LocalGet(1),
+                LocalTee(3),
+                I64Const(i64::MAX),
+                LocalGet(3),
+                I64Const(7),
+                I64Mul,
+                LocalGet(3),
+                I64Const(i64::MAX / 7),
+                I64GtU,
+                Select,
+                Call(1),
+                MemoryGrow(1),
+                Drop,
+                I32Const(0),
+                I32Const(0),
+                LocalGet(0),
+                LocalTee(2),
+                LocalGet(2),
+                I64ExtendI32U,
+                I64Const(7),
+                I64Mul,
+                Call(1),
+                MemoryFill(0),
+                End,
+            ]
+        ));
+
+        let features = wasmparser::WasmFeatures {
+            memory64: true,
+            multi_memory: true,
+            ..Default::default()
+        };
+        wasmparser::Validator::new_with_features(features)
+            .validate_all(&injected_raw_wasm)
+            .unwrap();
+        assert_eq!(
+            verify(&raw_wasm, &injected_raw_wasm, &TestRules {}).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn gas_charge_charge_linear_operand() {
+        pub struct TestRules {
+            call_depth: u32,
+        }
+        impl Rules for TestRules {
+            fn instruction_cost(&self, i: &Operator) -> Result<InstructionCost> {
+                Ok(match i {
+                    Operator::Call { .. } => InstructionCost::LinearOperand(
+                        2,
+                        NonZeroU32::new(7).unwrap(),
+                        self.call_depth,
+                    ),
+                    Operator::CallIndirect { .. } => {
+                        InstructionCost::LinearOperand(3, NonZeroU32::new(5).unwrap(), 3)
+                    }
+                    _ => InstructionCost::Fixed(1),
+                })
+            }
+
+            fn gas_charge_cost(&self) -> u64 {
+                10
+            }
+            fn linear_calc_cost(&self) -> u64 {
+                3
+            }
+        }
+
+        let module = parse_wat(
+            r#"(module
+			(type $t (func (param i32 i64 f32)))
+			(import "env" "host" (func $host (type $t)))
+			(table 1 funcref)
+			(func (param i32)
+			  local.get 0
+			  i64.const 1
+			  f32.const 0
+			  call $host
+			  local.get 0
+			  i64.const 1
+			  f32.const 0
+			  i32.const 0
+			  call_indirect (type $t))
+			)"#,
+        );
+
+        let raw_wasm = module.bytes();
+        let rules = TestRules { call_depth: 2 };
+        let injected_raw_wasm = inject(&raw_wasm, &rules, "env").unwrap();
+
+        // The `i64` and `f32` operands are spilled into locals 2 and 3, shared by both calls, and
+        // the table index of `call_indirect` into local 4
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            0,
+            &[
+                I64Const(48), // 1*7 + 2 + 3 + 13*2 + 10
+                Call(2),
+                LocalGet(0),
+                I64Const(1),
+                F32Const(0.0),
+                LocalSet(3),
+                LocalSet(2),
+                LocalTee(1),
+                LocalGet(1),
+                I64ExtendI32U,
+                I64Const(7),
+                I64Mul,
+                Call(2),
+                LocalGet(2),
+                LocalGet(3),
+                Call(0),
+                LocalGet(0),
+                I64Const(1),
+                F32Const(0.0),
+                I32Const(0),
+                LocalSet(4),
+                LocalSet(3),
+                LocalSet(2),
+                LocalTee(1),
+                LocalGet(1),
+                I64ExtendI32U,
+                I64Const(5),
+                I64Mul,
+                Call(2),
+                LocalGet(2),
+                LocalGet(3),
+                LocalGet(4),
+                CallIndirect { ty: 0, table: 0 },
+                End,
+            ]
+        ));
+
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+        assert_eq!(
+            verify(&raw_wasm, &injected_raw_wasm, &rules).unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            verify(&raw_wasm, &injected_raw_wasm, &TestRules { call_depth: 1 }).unwrap(),
+            vec![CostMismatch::DynamicCharge {
+                func_index: 1,
+                pos: 3,
+                unit_cost: Some(7),
+                charged_unit_cost: Some(7),
+                operand_depth: Some(1),
+                charged_operand_depth: Some(2),
+            }]
+        );
+
+        // The stack top is an `f32`
+        assert!(inject(&raw_wasm, &TestRules { call_depth: 0 }, "env").is_err());
+    }
+
+    #[test]
+    fn gas_charge_charge_superlinear() {
+        pub struct TestRules {
+            total_memory: bool,
+        }
+        impl Rules for TestRules {
+            fn instruction_cost(&self, i: &Operator) -> Result<InstructionCost> {
+                Ok(match i {
+                    Operator::MemoryGrow { .. } => InstructionCost::Quadratic {
+                        base: 1,
+                        linear: 2,
+                        quadratic: 3,
+                        total_memory: self.total_memory,
+                    },
+                    Operator::MemoryFill { .. } => InstructionCost::Piecewise {
+                        base: 4,
+                        segments: vec![(0, 0), (1024, 1), (4096, 8)],
+                        total_memory: false,
+                    },
+                    _ => InstructionCost::Fixed(1),
+                })
+            }
+
+            fn gas_charge_cost(&self) -> u64 {
+                10
+            }
+            fn linear_calc_cost(&self) -> u64 {
+                3
+            }
+        }
+
+        let module = parse_wat(
+            r#"(module
+			(memory 1)
+			(func (param i32)
+			  i32.const 10
+			  memory.grow
+			  drop
+			  local.get 0
+			  memory.grow
+			  drop
+			  i32.const 0
+			  i32.const 0
+			  local.get 0
+			  memory.fill)
+			)"#,
+        );
+        let raw_wasm = module.bytes();
+
+        // A constant operand is charged statically, `3*10*10 + 2*10 + 1`
+        let rules = TestRules {
+            total_memory: false,
+        };
+        let injected_raw_wasm = inject(&raw_wasm, &rules, "env").unwrap();
+        let mut prefix = vec![];
+        [I64Const(369), Call(1), I32Const(10), MemoryGrow(0), Drop]
+            .iter()
+            .for_each(|v| v.encode(&mut prefix));
+        assert!(get_function_body(&injected_raw_wasm, 0).starts_with(&prefix));
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+        assert_eq!(
+            verify(&raw_wasm, &injected_raw_wasm, &rules).unwrap(),
+            vec![]
+        );
+
+        // Priced by the total memory, the growth is always charged dynamically from `memory.size`
+        let total_rules = TestRules { total_memory: true };
+        let injected_total_wasm = inject(&raw_wasm, &total_rules, "env").unwrap();
+        let mut memory_size = vec![];
+        MemorySize(0).encode(&mut memory_size);
+        assert!(get_function_body(&injected_total_wasm, 0)
+            .windows(memory_size.len())
+            .any(|window| window == memory_size));
+        wasmparser::validate(&injected_total_wasm).unwrap();
+        assert_eq!(
+            verify(&raw_wasm, &injected_total_wasm, &total_rules).unwrap(),
+            vec![]
+        );
+
+        // The charges of other quadratic costs aren't recognized
+        assert!(verify(&raw_wasm, &injected_raw_wasm, &total_rules).is_err());
+    }
+
+    #[test]
+    fn call_import_cost() {
+        pub struct TestRules;
+        impl Rules for TestRules {
+            fn instruction_cost(&self, _: &Operator) -> Result<InstructionCost> {
+                Ok(InstructionCost::Fixed(1))
+            }
+
+            fn gas_charge_cost(&self) -> u64 {
+                10
+            }
+            fn linear_calc_cost(&self) -> u64 {
+                3
+            }
+            fn import_call_cost(&self, module: &str, name: &str) -> u64 {
+                match (module, name) {
+                    ("vm", "abort") => 500,
+                    _ => 0,
+                }
+            }
+        }
+
+        let module = parse_wat(
+            r#"(module
+			(import "env" "log" (func $log))
+			(import "vm" "abort" (func $abort))
+			(func $f
+			  call $log
+			  call $abort
+			  call $f)
+			)"#,
+        );
+
+        let raw_wasm = module.bytes();
+        let injected_raw_wasm = inject(&raw_wasm, &TestRules, "env").unwrap();
+
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            0,
+            &[
+                I64Const(513), // 3 + 500 + 10
+                Call(3),
+                Call(0),
+                Call(1),
+                Call(2),
+                End,
+            ]
+        ));
+
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+        assert_eq!(
+            verify(&raw_wasm, &injected_raw_wasm, &TestRules).unwrap(),
+            vec![]
+        );
+        // Without the cost of the import, the call is overcharged
+        assert!(matches!(
+            verify(&raw_wasm, &injected_raw_wasm, &ConstantCostRules::new(1, 0)).unwrap()[..],
+            [CostMismatch::Unbalanced(PathCost { charged: 513, .. })]
+        ));
+    }
+
+    #[test]
+    fn grow_const() {
+        let module = parse_wat(
+            r#"(module
+            (func (result i32)
+              i32.const 10
+              memory.grow)
+            (memory 0 1)
+            )"#,
+        );
+
+        let raw_wasm = module.bytes();
+        let injected_raw_wasm =
+            inject(&raw_wasm, &ConstantCostRules::new(1, 10_000), "env").unwrap();
+
+        // global0 - gas
+        // global1 - orig global0
+        // func0 - main
+        // func1 - gas_counter
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            0,
+            &[
+                I64Const(100002),
+                Call(1), // gas charge
+                I32Const(10),
+                MemoryGrow(0),
+                End,
+            ]
+        ));
+
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+    }
+
+    #[test]
+    fn grow_const_neg_fail() {
+        let module = parse_wat(
+            r#"(module
+            (func (result i32)
+              i32.const -10
+              memory.grow)
+            (memory 0 1)
+            )"#,
+        );
+
+        let raw_wasm = module.bytes();
+        wasmparser::validate(&raw_wasm).unwrap();
+
+        assert!(inject(&raw_wasm, &ConstantCostRules::new(1, 10_000), "env").is_err())
+    }
+
+    #[test]
+    fn simple_grow_two() {
+        // this test checks dynamic counter for instructions with different const param
+
+        pub struct TestRules {}
+        impl Rules for TestRules {
+            fn instruction_cost(&self, i: &Operator) -> Result<InstructionCost> {
+                Ok(match i {
+                    Operator::MemoryGrow { .. } => {
+                        InstructionCost::Linear(1, NonZeroU32::new(10).unwrap())
+                    }
+                    Operator::MemoryInit { .. } => {
+                        InstructionCost::Linear(3, NonZeroU32::new(12).unwrap())
+                    }
+                    _ => InstructionCost::Fixed(1),
+                })
+            }
+
+            fn gas_charge_cost(&self) -> u64 {
+                0
+            }
+            fn linear_calc_cost(&self) -> u64 {
+                0
+            }
+        }
+
+        let module = parse_wat(
+            r#"(module
+            (global i32 (i32.const 42))
+            (memory 0 1)
+            (func (param i32) (result i32)
+              local.get 0
+              global.get 0
+              i32.mul
+              memory.grow
+              (memory.init 1
+                  (i32.const 16)
+                  (i32.const 0)
+                  (i32.mul (i32.const 7) (i32.const 1)))
+              (memory.init 0
+                  (i32.const 8)
+                  (i32.const 0)
+                  (i32.mul (i32.const 2) (i32.const 1))))
+            (data "gm")
+            (data "goodbye"))"#,
+        );
+
+        let raw_wasm = module.bytes();
+        wasmparser::validate(&raw_wasm).unwrap();
+
+        let injected_raw_wasm = inject(&raw_wasm, &TestRules {}, "env").unwrap();
+
+        // global0 - gas
+        // global1 - orig global0
+        // func0 - main
+        // func1 - gas_counter
+
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            0,
+            &[
+                I64Const(20),
+                Call(1),
+                LocalGet(0),
+                GlobalGet(1),
+                I32Mul,
+                LocalTee(1),
+                LocalGet(1),
+                I64ExtendI32U,
+                I64Const(10),
+                I64Mul,
+                Call(1),
+                MemoryGrow(0),
+                I32Const(16),
+                I32Const(0),
+                I32Const(7),
+                I32Const(1),
+                I32Mul,
+                LocalTee(1),
+                LocalGet(1),
+                I64ExtendI32U,
+                I64Const(12),
+                I64Mul,
+                Call(1),
+                MemoryInit {
+                    mem: 0,
+                    data_index: 1
+                },
+                I32Const(8),
+                I32Const(0),
+                I32Const(2),
+                I32Const(1),
+                I32Mul,
+                LocalTee(1),
+                LocalGet(1),
+                I64ExtendI32U,
+                I64Const(12),
+                I64Mul,
+                Call(1),
+                MemoryInit {
+                    mem: 0,
+                    data_index: 0
+                },
+                End
+            ]
+        ));
+
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+    }
+
+    #[test]
+    fn grow_no_gas_no_track() {
+        let raw_wasm = parse_wat(
+            r"(module
+            (func (result i32)
+              global.get 0
+              memory.grow)
+            (global i32 (i32.const 42))
+            (memory 0 1)
+            )",
+        )
+        .bytes();
+        let injected_raw_wasm = inject(&raw_wasm, &ConstantCostRules::default(), "env").unwrap();
+
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            0,
+            &[I64Const(2), Call(1), GlobalGet(1), MemoryGrow(0), End,]
+        ));
+
+        let injected_module = ModuleInfo::new(&injected_raw_wasm).unwrap();
+        assert_eq!(injected_module.num_functions(), 2);
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+    }
+
+    #[test]
+    fn call_index() {
+        let raw_wasm = parse_wat(
+            r"(module
+                  (type (;0;) (func (result i32)))
+                  (func (;0;) (type 0) (result i32))
+                  (func (;1;) (type 0) (result i32)
+                    call 0
+                    if  ;; label = @1
+                      call 0
+                      call 0
+                      call 0
+                    else
+                      call 0
+                      call 0
+                    end
+                    call 0
+                  )
+                  (global (;0;) i32 )
+                )",
+        )
+        .bytes();
+        let injected_raw_wasm = inject(&raw_wasm, &ConstantCostRules::default(), "env").unwrap();
+
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            1,
+            &vec![
+                I64Const(3),
+                Call(2),
+                Call(0),
+                If(BlockType::Empty),
+                I64Const(3),
+                Call(2),
+                Call(0),
+                Call(0),
+                Call(0),
+                Else,
+                I64Const(2),
+                Call(2),
+                Call(0),
+                Call(0),
+                End,
+                Call(0),
+                End
+            ]
+        ));
+    }
+
+    macro_rules! test_gas_counter_injection {
+        (name = $name:ident; input = $input:expr; expected = $expected:expr) => {
+            #[test]
+            fn $name() {
+                let input_wasm = parse_wat($input).bytes();
+                let expected_wasm = parse_wat($expected).bytes();
+
+                let injected_wasm = inject(&input_wasm, &ConstantCostRules::default(), "env")
+                    .expect("inject_gas_counter call failed");
+
+                let actual_func_body = get_function_body(&injected_wasm, 0);
+
+                let expected_func_body = get_function_body(&expected_wasm, 0);
+
+                assert_eq!(actual_func_body, expected_func_body);
+            }
+        };
+    }
+
+    test_gas_counter_injection! {
+        name = simple;
+        input = r#"
+        (module
+            (func (result i32)
+                (get_global 0)))
+        "#;
+        expected = r#"
+        (module
+            (func (result i32)
+                (call 1 (i64.const 1))
+                (get_global 1)))
+        "#
+    }
+
+    #[test]
+    fn test_gas_error_fvm_fuzzin_5() {
+        let input = r#"
+        (module
+            (type (;0;) (func (result i32)))
+            (type (;1;) (func (param i32)))
+            (type (;2;) (func (param i32) (result i32)))
+            (type (;3;) (func (param i32 i32)))
+            (type (;4;) (func (param i32) (result i64)))
+            (type (;5;) (func (param i32 i32 i32) (result i64)))
+            (type (;6;) (func (param i32 i32 i32)))
+            (type (;7;) (func (param i32 i32) (result i32)))
+            (type (;8;) (func (param i32 i32 i32 i32)))
+            (type (;9;) (func (param i64 i32) (result i64)))
+            (type (;10;) (func (param i32 i64)))
+            (import "env" "memory" (memory (;0;) 256 256))
+            (import "env" "DYNAMICTOP_PTR" (global (;0;) i32))
+            (import "env" "STACKTOP" (global (;1;) i32))
+            (import "env" "enlargeMemory" (func (;0;) (type 0)))
+            (import "env" "getTotalMemory" (func (;1;) (type 0)))
+            (import "env" "abortOnCannotGrowMemory" (func (;2;) (type 0)))
+            (import "env" "___setErrNo" (func (;3;) (type 1)))
+            (func (;4;) (type 9) (param i64 i32) (result i64)
+              local.get 0
+              i32.const 64
+              local.get 1
+              i32.sub
+              i64.extend_i32_u
+              i64.shl
+              local.get 0
+              local.get 1
+              i64.extend_i32_u
+              i64.shr_u
+              i64.or
+            )
+          )
+        "#;
+        let raw_wasm = parse_wat(input).bytes();
+        let injected_raw_wasm = inject(&raw_wasm, &ConstantCostRules::default(), "other")
+            .expect("inject_gas_counter call failed");
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            0,
+            &[
+                I64Const(11),
+                Call(5),
+                LocalGet(0),
+                I32Const(64),
+                LocalGet(1),
+                I32Sub,
+                I64ExtendI32U,
+                I64Shl,
+                LocalGet(0),
+                LocalGet(1),
+                I64ExtendI32U,
+                I64ShrU,
+                I64Or,
+                End,
+            ]
+        ));
+
+        // 1 is gas counter
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            1,
+            &[
+                GlobalGet(2), // 2 imported globals, so gas one is third
+                LocalGet(0),
+                I64Sub,
+                GlobalSet(2),
+                GlobalGet(2),
+                I64Const(0),
+                I64LtS,
+                If(BlockType::Empty),
+                Unreachable,
+                End,
+                End
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_inject_with_report() {
+        let input = r#"
+        (module
+            (import "env" "f" (func (;0;)))
+            (memory 1)
+            (func (;1;) (param i32) (result i32)
+              local.get 0
+              memory.grow
+            )
+            (func (;2;) (param i32) (result i32)
+              local.get 0
+              if (result i32)
+                i32.const 2
+              else
+                i32.const 3
+              end
+            )
+          )
+        "#;
+        let raw_wasm = parse_wat(input).bytes();
+        let (injected_raw_wasm, report) =
+            inject_with_report(&raw_wasm, &ConstantCostRules::new(1, 10), "env").unwrap();
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+
+        assert_eq!(
+            report,
+            InjectionReport {
+                functions: vec![
+                    FunctionReport {
+                        func_index: 1,
+                        metered_blocks: 1,
+                        dynamic_charges: 1,
+                        hoisted_loops: 0,
+                        refunds: 0,
+                        static_cost: 2,
+                    },
+                    FunctionReport {
+                        func_index: 2,
+                        metered_blocks: 3,
+                        dynamic_charges: 0,
+                        hoisted_loops: 0,
+                        refunds: 0,
+                        static_cost: 4,
+                    },
+                ],
+                gas_func: 3,
+                gas_global: Some(0),
+                size_delta: 71,
+            }
+        );
+    }
+
+    #[test]
+    fn test_report_static_cost_saturates() {
+        struct ExpensiveCharges(u64);
+
+        impl Rules for ExpensiveCharges {
             fn instruction_cost(&self, _: &Operator) -> Result<InstructionCost> {
                 Ok(InstructionCost::Fixed(1))
             }
 
             fn gas_charge_cost(&self) -> u64 {
-                13
+                self.0
             }
+
             fn linear_calc_cost(&self) -> u64 {
-                99
+                0
             }
         }
 
-        let module = parse_wat(
+        let raw_wasm = parse_wat(
             r#"(module
-			(func (result i32)
-			  i32.const 10)
-			(memory 0 1)
-			)"#,
+            (func (param i32)
+              local.get 0
+              if
+                nop
+              end
+            ))"#,
+        )
+        .bytes();
+
+        let overflow = |result: Result<(Vec<u8>, InjectionReport), InstrumentError>| {
+            matches!(result, Err(InstrumentError::CostOverflow { .. }))
+        };
+        let unsigned = InjectOptions {
+            counter_type: CounterType::Unsigned,
+            ..InjectOptions::new(GasCounter::Import {
+                module: "env".into(),
+            })
+        };
+        let host_function = InjectOptions::new(GasCounter::HostFunction {
+            module: "env".into(),
+            name: "gas".into(),
+        });
+
+        // Each charge fits into an `i64`.
+        let rules = ExpensiveCharges(i64::MAX as u64 - 10);
+        let (_, report) = inject_with_report(&raw_wasm, &rules, "env").unwrap();
+        assert_eq!(report.functions[0].metered_blocks, 2);
+        assert!(inject_with_options(&raw_wasm, &rules, &host_function).is_ok());
+
+        // Each charge fits into a `u64` but not into an `i64`, only an unsigned counter takes
+        // them. Their sum doesn't fit into a `u64`.
+        let rules = ExpensiveCharges(u64::MAX / 2);
+        let (_, report) = inject_with_options(&raw_wasm, &rules, &unsigned).unwrap();
+        assert_eq!(report.functions[0].metered_blocks, 2);
+        assert_eq!(report.functions[0].static_cost, u64::MAX);
+        assert!(overflow(inject_with_report(&raw_wasm, &rules, "env")));
+        assert!(overflow(inject_with_options(
+            &raw_wasm,
+            &rules,
+            &host_function
+        )));
+
+        let rules = ExpensiveCharges(u64::MAX);
+        assert!(overflow(inject_with_options(&raw_wasm, &rules, &unsigned)));
+    }
+
+    #[test]
+    fn test_global_remapping() {
+        let input = r#"
+        (module
+            (import "env" "a" (global $a i32))
+            (import "env" "b" (global $b (mut i32)))
+            (global $c (mut i32) (global.get $a))
+            (global $d i64 (i64.const 5))
+            (func (result i32)
+              global.get $a
+              global.get $b
+              i32.add
+              global.get $c
+              i32.add
+              global.set $b
+              global.get $c
+            )
+            (export "c" (global $c))
+            (export "d" (global $d))
+            (export "a" (global $a))
+          )
+        "#;
+        let raw_wasm = parse_wat(input).bytes();
+        let injected_raw_wasm = inject(&raw_wasm, &ConstantCostRules::default(), "env").unwrap();
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+
+        // a and b keep their indices, the gas global is 2, c and d are shifted by one
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            0,
+            &[
+                I64Const(7),
+                Call(1),
+                GlobalGet(0),
+                GlobalGet(1),
+                I32Add,
+                GlobalGet(3),
+                I32Add,
+                GlobalSet(1),
+                GlobalGet(3),
+                End
+            ]
+        ));
+
+        let module = ModuleInfo::new(&injected_raw_wasm).unwrap();
+        let exports =
+            ExportSectionReader::new(&module.raw_sections[&SectionId::Export.into()].data, 0)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.map(|e| (e.name, e.kind, e.index)))
+                .collect::<wasmparser::Result<Vec<_>>>()
+                .unwrap();
+        assert_eq!(
+            exports,
+            [
+                ("c", ExternalKind::Global, 3),
+                ("d", ExternalKind::Global, 4),
+                ("a", ExternalKind::Global, 0),
+            ]
         );
 
-        let raw_wasm = module.bytes();
-        let injected_raw_wasm = inject(&raw_wasm, &TestRules {}, "env").unwrap();
+        let init_ops =
+            GlobalSectionReader::new(&module.raw_sections[&SectionId::Global.into()].data, 0)
+                .unwrap()
+                .read()
+                .unwrap()
+                .init_expr
+                .get_operators_reader()
+                .into_iter()
+                .collect::<wasmparser::Result<Vec<_>>>()
+                .unwrap();
+        assert!(matches!(
+            init_ops[..],
+            [Operator::GlobalGet { global_index: 0 }, Operator::End]
+        ));
+
+        let printed = wasmprinter::print_bytes(&injected_raw_wasm).unwrap();
+        assert!(printed.contains("(global $c (;3;)"), "{}", printed);
+        assert!(
+            printed.contains("(global $gas_counter (;2;)"),
+            "{}",
+            printed
+        );
+    }
+
+    #[test]
+    fn test_exported_gas_counter() {
+        let input = r#"
+        (module
+            (import "env" "a" (global $a i32))
+            (global $b (mut i32) (i32.const 0))
+            (func (result i32)
+              global.get $a
+              global.get $b
+              i32.add
+            )
+            (export "b" (global $b))
+          )
+        "#;
+        let raw_wasm = parse_wat(input).bytes();
+        let options = InjectOptions::new(GasCounter::Export { name: "gas".into() });
+        let (injected_raw_wasm, report) =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options).unwrap();
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+        assert_eq!(report.gas_global, Some(2));
 
+        // No import is added and the existing globals keep their indices
+        let module = ModuleInfo::new(&injected_raw_wasm).unwrap();
+        assert_eq!(module.imported_globals_count, 1);
+        assert_eq!(module.num_globals(), 3);
         assert!(check_expect_function_body(
             &injected_raw_wasm,
             0,
             &[
-                I64Const(14), // 1 + 13
-                Call(1),      // gas charge
-                I32Const(10),
+                I64Const(3),
+                Call(1),
+                GlobalGet(0),
+                GlobalGet(1),
+                I32Add,
+                End
+            ]
+        ));
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            1,
+            &[
+                GlobalGet(2),
+                LocalGet(0),
+                I64Sub,
+                GlobalSet(2),
+                GlobalGet(2),
+                I64Const(0),
+                I64LtS,
+                If(BlockType::Empty),
+                Unreachable,
                 End,
+                End
             ]
         ));
 
-        wasmparser::validate(&injected_raw_wasm).unwrap();
+        let exports =
+            ExportSectionReader::new(&module.raw_sections[&SectionId::Export.into()].data, 0)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.map(|e| (e.name, e.kind, e.index)))
+                .collect::<wasmparser::Result<Vec<_>>>()
+                .unwrap();
+        assert_eq!(
+            exports,
+            [
+                ("b", ExternalKind::Global, 1),
+                ("gas", ExternalKind::Global, 2)
+            ]
+        );
+
+        let options = InjectOptions::new(GasCounter::Export { name: "b".into() });
+        let err =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options).unwrap_err();
+        assert!(matches!(err, InstrumentError::DuplicateExport(name) if name == "b"));
     }
 
     #[test]
-    fn simple_grow() {
-        let module = parse_wat(
-            r#"(module
-            (func (result i32)
-              global.get 0
-              memory.grow)
-            (global i32 (i32.const 42))
-            (memory 0 1)
-            )"#,
+    fn test_host_function_gas() {
+        let input = r#"
+        (module
+            (import "env" "f" (func $f))
+            (func $a (result i32)
+              call $f
+              call $b
+            )
+            (func $b (result i32)
+              i32.const 1
+            )
+            (table 2 funcref)
+            (elem (i32.const 0) $a $f)
+            (start $f)
+            (export "a" (func $a))
+          )
+        "#;
+        let raw_wasm = parse_wat(input).bytes();
+        let options = InjectOptions::new(GasCounter::HostFunction {
+            module: "env".into(),
+            name: "gas".into(),
+        });
+        let (injected_raw_wasm, report) =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options).unwrap();
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+        assert_eq!(report.gas_func, 1);
+        assert_eq!(report.gas_global, None);
+
+        // No gas counter global nor charging function, the defined functions are shifted
+        let module = ModuleInfo::new(&injected_raw_wasm).unwrap();
+        assert_eq!(module.num_globals(), 0);
+        assert_eq!(module.num_functions(), 4);
+        assert_eq!(module.start_function, Some(0));
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            0,
+            &[I64Const(2), Call(1), Call(0), Call(3), End]
+        ));
+
+        let exports =
+            ExportSectionReader::new(&module.raw_sections[&SectionId::Export.into()].data, 0)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.map(|e| (e.name, e.kind, e.index)))
+                .collect::<wasmparser::Result<Vec<_>>>()
+                .unwrap();
+        assert_eq!(exports, [("a", ExternalKind::Func, 2)]);
+
+        let printed = wasmprinter::print_bytes(&injected_raw_wasm).unwrap();
+        assert!(
+            printed.contains(r#"(import "env" "gas" (func $gas (;1;) (type"#),
+            "{}",
+            printed
+        );
+        assert!(
+            printed.contains("(elem (;0;) (i32.const 0) func $a $f)"),
+            "{}",
+            printed
         );
+    }
 
-        let raw_wasm = module.bytes();
+    #[test]
+    fn test_out_of_gas_status() {
+        let input = r#"
+        (module
+            (global $g (mut i32) (i32.const 0))
+            (func)
+          )
+        "#;
+        let raw_wasm = parse_wat(input).bytes();
+        let mut options = InjectOptions::new(GasCounter::Import {
+            module: "env".into(),
+        });
+        options.out_of_gas = OutOfGas::ExportStatus {
+            name: "out_of_gas".into(),
+        };
         let injected_raw_wasm =
-            inject(&raw_wasm, &ConstantCostRules::new(1, 10_000), "env").unwrap();
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options)
+                .unwrap()
+                .0;
+        wasmparser::validate(&injected_raw_wasm).unwrap();
 
-        // global0 - gas
-        // global1 - orig global0
-        // func0 - main
-        // func1 - gas_counter
+        // The gas global is 0, $g is shifted to 1 and the status global appended as 2
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            1,
+            &[
+                GlobalGet(0),
+                LocalGet(0),
+                I64Sub,
+                GlobalSet(0),
+                GlobalGet(0),
+                I64Const(0),
+                I64LtS,
+                If(BlockType::Empty),
+                I32Const(1),
+                GlobalSet(2),
+                Unreachable,
+                End,
+                End
+            ]
+        ));
+        let module = ModuleInfo::new(&injected_raw_wasm).unwrap();
+        let exports =
+            ExportSectionReader::new(&module.raw_sections[&SectionId::Export.into()].data, 0)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.map(|e| (e.name, e.kind, e.index)))
+                .collect::<wasmparser::Result<Vec<_>>>()
+                .unwrap();
+        assert_eq!(exports, [("out_of_gas", ExternalKind::Global, 2)]);
+    }
+
+    #[test]
+    fn test_out_of_gas_host_function() {
+        let input = r#"
+        (module
+            (import "env" "f" (func))
+            (func call 0)
+          )
+        "#;
+        let raw_wasm = parse_wat(input).bytes();
+        let mut options = InjectOptions::new(GasCounter::Export { name: "gas".into() });
+        options.out_of_gas = OutOfGas::HostFunction {
+            module: "env".into(),
+            name: "out_of_gas".into(),
+        };
+        let (injected_raw_wasm, report) =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options).unwrap();
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+        assert_eq!(report.gas_func, 3);
+
+        // The out of gas function is imported as 1, shifting the defined function to 2
         assert!(check_expect_function_body(
             &injected_raw_wasm,
             0,
+            &[I64Const(1), Call(3), Call(0), End]
+        ));
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            1,
             &[
-                I64Const(2),
-                Call(1),      // gas charge
-                GlobalGet(1), // original code
-                // <dynamic charge>
-                LocalTee(0),
+                GlobalGet(0),
                 LocalGet(0),
-                I64ExtendI32U,
-                I64Const(10000),
-                I64Mul,
+                I64Sub,
+                GlobalSet(0),
+                GlobalGet(0),
+                I64Const(0),
+                I64LtS,
+                If(BlockType::Empty),
                 Call(1),
-                // </dynamic charge>
-                MemoryGrow(0), // original code
+                Unreachable,
                 End,
+                End
             ]
         ));
 
-        wasmparser::validate(&injected_raw_wasm).unwrap();
+        // The host function backend has no counter to check
+        let mut options = InjectOptions::new(GasCounter::HostFunction {
+            module: "env".into(),
+            name: "gas".into(),
+        });
+        options.out_of_gas = OutOfGas::ExportStatus {
+            name: "out_of_gas".into(),
+        };
+        assert!(inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options).is_err());
     }
 
     #[test]
-    fn gas_charge_charge_const_linear() {
-        pub struct TestRules {}
-        impl Rules for TestRules {
-            fn instruction_cost(&self, i: &Operator) -> Result<InstructionCost> {
-                Ok(match i {
-                    Operator::MemoryGrow { .. } => {
-                        InstructionCost::Linear(17, NonZeroU32::new(7).unwrap())
-                    }
-                    _ => InstructionCost::Fixed(3),
-                })
-            }
+    fn test_charge_modes() {
+        let raw_wasm = parse_wat("(module (func))").bytes();
+        let mut options = InjectOptions::new(GasCounter::Import {
+            module: "env".into(),
+        });
 
-            fn gas_charge_cost(&self) -> u64 {
-                13
-            }
-            fn linear_calc_cost(&self) -> u64 {
-                5
-            }
-        }
+        options.charge_mode = ChargeMode::KeepOnFailure;
+        let injected_raw_wasm =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options)
+                .unwrap()
+                .0;
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            1,
+            &[
+                GlobalGet(0),
+                LocalGet(0),
+                I64LtS,
+                If(BlockType::Empty),
+                Unreachable,
+                End,
+                GlobalGet(0),
+                LocalGet(0),
+                I64Sub,
+                GlobalSet(0),
+                End
+            ]
+        ));
 
-        let module = parse_wat(
-            r#"(module
-			(func (result i32)
-			  i32.const 10
-			  memory.grow 0)
-			(memory 0 1)
-			)"#,
-        );
+        options.charge_mode = ChargeMode::ZeroOnFailure;
+        let injected_raw_wasm =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options)
+                .unwrap()
+                .0;
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            1,
+            &[
+                GlobalGet(0),
+                LocalGet(0),
+                I64LtS,
+                If(BlockType::Empty),
+                I64Const(0),
+                GlobalSet(0),
+                Unreachable,
+                End,
+                GlobalGet(0),
+                LocalGet(0),
+                I64Sub,
+                GlobalSet(0),
+                End
+            ]
+        ));
+    }
 
-        let raw_wasm = module.bytes();
-        let injected_raw_wasm = inject(&raw_wasm, &TestRules {}, "env").unwrap();
+    #[test]
+    fn test_unsigned_counter() {
+        let raw_wasm = parse_wat("(module (func))").bytes();
+        let mut options = InjectOptions::new(GasCounter::Import {
+            module: "env".into(),
+        });
+        options.counter_type = CounterType::Unsigned;
+
+        let injected_raw_wasm =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options)
+                .unwrap()
+                .0;
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+        assert!(check_expect_function_body(
+            &injected_raw_wasm,
+            1,
+            &[
+                GlobalGet(0),
+                LocalGet(0),
+                I64LtU,
+                GlobalGet(0),
+                LocalGet(0),
+                I64Sub,
+                GlobalSet(0),
+                If(BlockType::Empty),
+                Unreachable,
+                End,
+                End
+            ]
+        ));
 
+        options.charge_mode = ChargeMode::KeepOnFailure;
+        let injected_raw_wasm =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options)
+                .unwrap()
+                .0;
+        wasmparser::validate(&injected_raw_wasm).unwrap();
         assert!(check_expect_function_body(
             &injected_raw_wasm,
-            0,
+            1,
             &[
-                I64Const(103), // 3*1 + 17 + 10*7 + 13*1
-                Call(1),       // gas charge
-                I32Const(10),
-                MemoryGrow(0),
+                GlobalGet(0),
+                LocalGet(0),
+                I64LtU,
+                If(BlockType::Empty),
+                Unreachable,
                 End,
+                GlobalGet(0),
+                LocalGet(0),
+                I64Sub,
+                GlobalSet(0),
+                End
             ]
         ));
-
-        wasmparser::validate(&injected_raw_wasm).unwrap();
     }
 
     #[test]
-    fn gas_charge_charge_dyn_linear() {
-        pub struct TestRules {}
-        impl Rules for TestRules {
-            fn instruction_cost(&self, i: &Operator) -> Result<InstructionCost> {
-                Ok(match i {
-                    Operator::MemoryGrow { .. } => {
-                        InstructionCost::Linear(17, NonZeroU32::new(7).unwrap())
-                    }
-                    _ => InstructionCost::Fixed(3),
-                })
-            }
-
-            fn gas_charge_cost(&self) -> u64 {
-                13
-            }
-            fn linear_calc_cost(&self) -> u64 {
-                5
-            }
-        }
-
-        let module = parse_wat(
+    fn test_inlining() {
+        let raw_wasm = parse_wat(
             r#"(module
-			(func (result i32)
-			  i32.const 10
-			  i32.const 1
-			  i32.mul
-			  memory.grow 0)
-			(memory 0 1)
-			)"#,
-        );
-
-        let raw_wasm = module.bytes();
-        let injected_raw_wasm = inject(&raw_wasm, &TestRules {}, "env").unwrap();
+			(func (param i32)
+			  (loop
+				local.get 0
+				i32.const 1
+				i32.sub
+				local.tee 0
+				br_if 0)))"#,
+        )
+        .bytes();
+        let mut options = InjectOptions::new(GasCounter::Import {
+            module: "env".into(),
+        });
+        options.inlining = Inlining::Heuristic { max_block_len: 0 };
 
+        let injected_raw_wasm =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options)
+                .unwrap()
+                .0;
+        wasmparser::validate(&injected_raw_wasm).unwrap();
         assert!(check_expect_function_body(
             &injected_raw_wasm,
             0,
             &[
-                I64Const(57), // 3*3 + 17 + 13*2 + 5
-                Call(1),      // gas charge
-                I32Const(10),
+                I64Const(1),
+                Call(1),
+                Loop(BlockType::Empty),
+                GlobalGet(0),
+                I64Const(5),
+                I64Sub,
+                GlobalSet(0),
+                GlobalGet(0),
+                I64Const(0),
+                I64LtS,
+                If(BlockType::Empty),
+                Unreachable,
+                End,
+                LocalGet(0),
                 I32Const(1),
-                I32Mul,
+                I32Sub,
                 LocalTee(0),
-                LocalGet(0),
-                I64ExtendI32U,
-                I64Const(7),
-                I64Mul,
-                Call(1),
-                MemoryGrow(0),
+                BrIf(0),
                 End,
+                End
             ]
         ));
 
-        wasmparser::validate(&injected_raw_wasm).unwrap();
+        // The whole function is short enough to be inlined
+        options.inlining = Inlining::Heuristic { max_block_len: 1 };
+        let injected_raw_wasm =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options)
+                .unwrap()
+                .0;
+        let body = get_function_body(&injected_raw_wasm, 0);
+        let mut call = vec![];
+        Call(1).encode(&mut call);
+        assert!(!body.windows(call.len()).any(|window| window == call));
     }
 
     #[test]
-    fn grow_const() {
-        let module = parse_wat(
+    fn test_inline_dynamic_charge() {
+        let raw_wasm = parse_wat(
             r#"(module
-            (func (result i32)
-              i32.const 10
-              memory.grow)
-            (memory 0 1)
-            )"#,
-        );
+			(func (param i32) (result i32)
+			  local.get 0
+			  memory.grow 0)
+			(memory 0 1)
+			)"#,
+        )
+        .bytes();
+        let mut options = InjectOptions::new(GasCounter::Import {
+            module: "env".into(),
+        });
+        options.inlining = Inlining::Always;
+        options.charge_mode = ChargeMode::KeepOnFailure;
 
-        let raw_wasm = module.bytes();
         let injected_raw_wasm =
-            inject(&raw_wasm, &ConstantCostRules::new(1, 10_000), "env").unwrap();
-
-        // global0 - gas
-        // global1 - orig global0
-        // func0 - main
-        // func1 - gas_counter
+            inject_with_options(&raw_wasm, &ConstantCostRules::new(1, 7), &options)
+                .unwrap()
+                .0;
+        wasmparser::validate(&injected_raw_wasm).unwrap();
         assert!(check_expect_function_body(
             &injected_raw_wasm,
             0,
             &[
-                I64Const(100002),
-                Call(1), // gas charge
-                I32Const(10),
-                MemoryGrow(0),
+                GlobalGet(0),
+                I64Const(2),
+                I64LtS,
+                If(BlockType::Empty),
+                Unreachable,
+                End,
+                GlobalGet(0),
+                I64Const(2),
+                I64Sub,
+                GlobalSet(0),
+                LocalGet(0),
+                LocalTee(1),
+                LocalGet(1),
+                I64ExtendI32U,
+                I64Const(7),
+                I64Mul,
+                LocalSet(2),
+                GlobalGet(0),
+                LocalGet(2),
+                I64LtS,
+                If(BlockType::Empty),
+                Unreachable,
                 End,
+                GlobalGet(0),
+                LocalGet(2),
+                I64Sub,
+                GlobalSet(0),
+                MemoryGrow(0),
+                End
             ]
         ));
-
-        wasmparser::validate(&injected_raw_wasm).unwrap();
-    }
-
-    #[test]
-    fn grow_const_neg_fail() {
-        let module = parse_wat(
-            r#"(module
-            (func (result i32)
-              i32.const -10
-              memory.grow)
-            (memory 0 1)
-            )"#,
-        );
-
-        let raw_wasm = module.bytes();
-        wasmparser::validate(&raw_wasm).unwrap();
-
-        assert!(inject(&raw_wasm, &ConstantCostRules::new(1, 10_000), "env").is_err())
     }
 
     #[test]
-    fn simple_grow_two() {
-        // this test checks dynamic counter for instructions with different const param
-
-        pub struct TestRules {}
-        impl Rules for TestRules {
-            fn instruction_cost(&self, i: &Operator) -> Result<InstructionCost> {
-                Ok(match i {
-                    Operator::MemoryGrow { .. } => {
-                        InstructionCost::Linear(1, NonZeroU32::new(10).unwrap())
-                    }
-                    Operator::MemoryInit { .. } => {
-                        InstructionCost::Linear(3, NonZeroU32::new(12).unwrap())
-                    }
-                    _ => InstructionCost::Fixed(1),
-                })
-            }
-
-            fn gas_charge_cost(&self) -> u64 {
-                0
-            }
-            fn linear_calc_cost(&self) -> u64 {
-                0
-            }
-        }
-
-        let module = parse_wat(
+    fn test_hoist_loop_charges() {
+        let raw_wasm = parse_wat(
             r#"(module
-            (global i32 (i32.const 42))
-            (memory 0 1)
-            (func (param i32) (result i32)
-              local.get 0
-              global.get 0
-              i32.mul
-              memory.grow
-              (memory.init 1
-                  (i32.const 16)
-                  (i32.const 0)
-                  (i32.mul (i32.const 7) (i32.const 1)))
-              (memory.init 0
-                  (i32.const 8)
-                  (i32.const 0)
-                  (i32.mul (i32.const 2) (i32.const 1))))
-            (data "gm")
-            (data "goodbye"))"#,
-        );
-
-        let raw_wasm = module.bytes();
-        wasmparser::validate(&raw_wasm).unwrap();
-
-        let injected_raw_wasm = inject(&raw_wasm, &TestRules {}, "env").unwrap();
-
-        // global0 - gas
-        // global1 - orig global0
-        // func0 - main
-        // func1 - gas_counter
+			(func (param i32)
+			  (local i32)
+			  (loop
+				local.get 1
+				i32.const 1
+				i32.add
+				local.tee 1
+				local.get 0
+				i32.lt_u
+				br_if 0)))"#,
+        )
+        .bytes();
+        let mut options = InjectOptions::new(GasCounter::Import {
+            module: "env".into(),
+        });
+        options.hoist_loop_charges = true;
 
+        let (injected_raw_wasm, report) =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options).unwrap();
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+        assert_eq!(report.functions[0].hoisted_loops, 1);
+        assert_eq!(report.functions[0].metered_blocks, 1);
         assert!(check_expect_function_body(
             &injected_raw_wasm,
             0,
             &[
-                I64Const(20),
+                I64Const(1),
                 Call(1),
+                // max(bound - (counter + 1), 0) + 1 iterations
                 LocalGet(0),
-                GlobalGet(1),
-                I32Mul,
-                LocalTee(1),
-                LocalGet(1),
                 I64ExtendI32U,
-                I64Const(10),
-                I64Mul,
-                Call(1),
-                MemoryGrow(0),
-                I32Const(16),
-                I32Const(0),
-                I32Const(7),
-                I32Const(1),
-                I32Mul,
-                LocalTee(1),
                 LocalGet(1),
+                I32Const(1),
+                I32Add,
                 I64ExtendI32U,
-                I64Const(12),
+                I64Sub,
+                LocalTee(2),
+                I64Const(0),
+                LocalGet(2),
+                I64Const(0),
+                I64GtS,
+                Select,
+                I64Const(1),
+                I64Add,
+                I64Const(7),
                 I64Mul,
                 Call(1),
-                MemoryInit {
-                    mem: 0,
-                    data_index: 1
-                },
-                I32Const(8),
-                I32Const(0),
-                I32Const(2),
+                Loop(BlockType::Empty),
+                LocalGet(1),
                 I32Const(1),
-                I32Mul,
+                I32Add,
                 LocalTee(1),
-                LocalGet(1),
-                I64ExtendI32U,
-                I64Const(12),
-                I64Mul,
-                Call(1),
-                MemoryInit {
-                    mem: 0,
-                    data_index: 0
-                },
+                LocalGet(0),
+                I32LtU,
+                BrIf(0),
+                End,
                 End
             ]
         ));
+    }
 
-        wasmparser::validate(&injected_raw_wasm).unwrap();
+    #[test]
+    fn test_hoist_loop_charges_fallback() {
+        let mut options = InjectOptions::new(GasCounter::Import {
+            module: "env".into(),
+        });
+        options.hoist_loop_charges = true;
+
+        for body in [
+            // The bound changes in the loop
+            "local.get 0 i32.const 1 i32.add local.set 0
+			 local.get 1 i32.const 1 i32.add local.tee 1 local.get 0 i32.lt_u br_if 0",
+            // The counter is decremented
+            "local.get 1 i32.const 1 i32.sub local.tee 1 local.get 0 i32.ne br_if 0",
+            // The loop body branches
+            "local.get 0 if end
+			 local.get 1 i32.const 1 i32.add local.tee 1 i32.const 10 i32.lt_s br_if 0",
+        ] {
+            let raw_wasm = parse_wat(&format!(
+                "(module (func (param i32) (local i32) (loop {})))",
+                body
+            ))
+            .bytes();
+            let (injected_raw_wasm, report) =
+                inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options).unwrap();
+            wasmparser::validate(&injected_raw_wasm).unwrap();
+            assert_eq!(report.functions[0].hoisted_loops, 0, "{}", body);
+        }
     }
 
     #[test]
-    fn grow_no_gas_no_track() {
-        let raw_wasm = parse_wat(
-            r"(module
-            (func (result i32)
-              global.get 0
-              memory.grow)
-            (global i32 (i32.const 42))
-            (memory 0 1)
-            )",
+    fn test_merge_blocks() {
+        let raw_wasm = parse_wat(
+            r#"(module
+			(func (param i32)
+			  (block
+				(block
+				  local.get 0
+				  br_if 1
+				  nop)
+				nop)
+			  nop))"#,
         )
         .bytes();
-        let injected_raw_wasm = inject(&raw_wasm, &ConstantCostRules::default(), "env").unwrap();
+        let mut options = InjectOptions::new(GasCounter::Import {
+            module: "env".into(),
+        });
+        options.merge_blocks = true;
 
+        let (injected_raw_wasm, report) =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options).unwrap();
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+        assert_eq!(report.functions[0].metered_blocks, 2);
         assert!(check_expect_function_body(
             &injected_raw_wasm,
             0,
-            &[I64Const(2), Call(1), GlobalGet(1), MemoryGrow(0), End,]
+            &[
+                I64Const(5),
+                Call(1),
+                Block(BlockType::Empty),
+                Block(BlockType::Empty),
+                LocalGet(0),
+                BrIf(1),
+                // The charge of the `nop` after the inner block is merged here
+                I64Const(2),
+                Call(1),
+                Nop,
+                End,
+                Nop,
+                End,
+                Nop,
+                End
+            ]
         ));
-
-        let injected_module = ModuleInfo::new(&injected_raw_wasm).unwrap();
-        assert_eq!(injected_module.num_functions(), 2);
-        wasmparser::validate(&injected_raw_wasm).unwrap();
     }
 
     #[test]
-    fn call_index() {
+    fn test_charge_upfront() {
         let raw_wasm = parse_wat(
-            r"(module
-                  (type (;0;) (func (result i32)))
-                  (func (;0;) (type 0) (result i32))
-                  (func (;1;) (type 0) (result i32)
-                    call 0
-                    if  ;; label = @1
-                      call 0
-                      call 0
-                      call 0
-                    else
-                      call 0
-                      call 0
-                    end
-                    call 0
-                  )
-                  (global (;0;) i32 )
-                )",
+            r#"(module
+			(func (param i32)
+			  (block
+				local.get 0
+				br_if 0
+				nop
+				nop)
+			  nop))"#,
         )
         .bytes();
-        let injected_raw_wasm = inject(&raw_wasm, &ConstantCostRules::default(), "env").unwrap();
+        let mut options = InjectOptions::new(GasCounter::Import {
+            module: "env".into(),
+        });
+        options.charge_upfront = true;
 
+        let (injected_raw_wasm, report) =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options).unwrap();
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+        assert_eq!(report.functions[0].metered_blocks, 1);
+        assert_eq!(report.functions[0].refunds, 1);
         assert!(check_expect_function_body(
             &injected_raw_wasm,
-            1,
-            &vec![
-                I64Const(3),
-                Call(2),
-                Call(0),
+            0,
+            &[
+                I64Const(6),
+                Call(1),
+                Block(BlockType::Empty),
+                LocalGet(0),
+                // Branching skips the two `nop`s
+                LocalTee(1),
                 If(BlockType::Empty),
-                I64Const(3),
-                Call(2),
-                Call(0),
-                Call(0),
-                Call(0),
-                Else,
+                GlobalGet(0),
                 I64Const(2),
-                Call(2),
-                Call(0),
-                Call(0),
+                I64Add,
+                GlobalSet(0),
                 End,
-                Call(0),
+                LocalGet(1),
+                BrIf(0),
+                Nop,
+                Nop,
+                End,
+                Nop,
                 End
             ]
         ));
+
+        // The gas can't be refunded to a host function
+        options.gas_counter = GasCounter::HostFunction {
+            module: "env".into(),
+            name: "gas".into(),
+        };
+        let (_, report) =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options).unwrap();
+        assert_eq!(report.functions[0].refunds, 0);
+        assert_eq!(report.functions[0].metered_blocks, 2);
     }
 
-    macro_rules! test_gas_counter_injection {
-        (name = $name:ident; input = $input:expr; expected = $expected:expr) => {
-            #[test]
-            fn $name() {
-                let input_wasm = parse_wat($input).bytes();
-                let expected_wasm = parse_wat($expected).bytes();
+    #[test]
+    fn test_rules_ext() {
+        use core::cell::RefCell;
+
+        // Instructions cost 10 in loops, and 100 in the second function
+        struct ContextRules(RefCell<Vec<InstrContext>>);
+        impl RulesExt for ContextRules {
+            fn instruction_cost_in(
+                &self,
+                ctx: &InstrContext,
+                _instruction: &Operator,
+            ) -> Result<InstructionCost> {
+                self.0.borrow_mut().push(*ctx);
+                let cost = if ctx.func_index == 1 {
+                    100
+                } else if ctx.in_loop {
+                    10
+                } else {
+                    1
+                };
+                Ok(InstructionCost::Fixed(cost))
+            }
 
-                let injected_wasm = inject(&input_wasm, &ConstantCostRules::default(), "env")
-                    .expect("inject_gas_counter call failed");
+            fn gas_charge_cost(&self) -> u64 {
+                0
+            }
 
-                let actual_func_body = get_function_body(&injected_wasm, 0);
+            fn linear_calc_cost(&self) -> u64 {
+                0
+            }
+        }
 
-                let expected_func_body = get_function_body(&expected_wasm, 0);
+        let raw_wasm = parse_wat(
+            r#"(module
+			(import "env" "f" (func))
+			(func (param i32)
+			  (loop
+				local.get 0
+				br_if 0)
+			  nop)
+			(func nop))"#,
+        )
+        .bytes();
+        let rules = ContextRules(RefCell::new(Vec::new()));
+        let (injected_raw_wasm, report) = inject_with_report(&raw_wasm, &rules, "env").unwrap();
+        wasmparser::validate(&injected_raw_wasm).unwrap();
 
-                assert_eq!(actual_func_body, expected_func_body);
-            }
-        };
+        let contexts = rules
+            .0
+            .borrow()
+            .iter()
+            .filter(|ctx| ctx.func_index == 0)
+            .map(|ctx| (ctx.offset, ctx.depth, ctx.in_loop))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            contexts,
+            vec![
+                (0, 0, false),
+                (1, 1, true),
+                (2, 1, true),
+                (3, 1, true),
+                (4, 0, false),
+                (5, 0, false)
+            ]
+        );
+        // The loop and the `nop` after it, then the loop body
+        assert_eq!(report.functions[0].static_cost, 2 + 20);
+        assert_eq!(report.functions[1].static_cost, 100);
+
+        assert_eq!(
+            verify(&raw_wasm, &injected_raw_wasm, &rules).unwrap(),
+            vec![]
+        );
     }
 
-    test_gas_counter_injection! {
-        name = simple;
-        input = r#"
-        (module
-            (func (result i32)
-                (get_global 0)))
-        "#;
-        expected = r#"
-        (module
-            (func (result i32)
-                (call 1 (i64.const 1))
-                (get_global 1)))
-        "#
+    #[test]
+    fn test_context_tracker_delegate() {
+        use wasmparser::BlockType;
+
+        // `loop (try nop delegate 0) nop end`, where `delegate` closes the `try` without `end`
+        let operators = [
+            Operator::Loop {
+                blockty: BlockType::Empty,
+            },
+            Operator::Try {
+                blockty: BlockType::Empty,
+            },
+            Operator::Nop,
+            Operator::Delegate { relative_depth: 0 },
+            Operator::Nop,
+            Operator::End,
+            Operator::Nop,
+        ];
+        let mut tracker = ContextTracker::default();
+        let contexts = operators
+            .iter()
+            .enumerate()
+            .map(|(offset, instruction)| {
+                let ctx = tracker.context(0, offset);
+                tracker.update(instruction);
+                (ctx.depth, ctx.in_loop)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            contexts,
+            vec![
+                (0, false),
+                (1, true),
+                (2, true),
+                (2, true),
+                (1, true),
+                (1, true),
+                (0, false)
+            ]
+        );
     }
 
     #[test]
-    fn test_gas_error_fvm_fuzzin_5() {
-        let input = r#"
-        (module
-            (type (;0;) (func (result i32)))
-            (type (;1;) (func (param i32)))
-            (type (;2;) (func (param i32) (result i32)))
-            (type (;3;) (func (param i32 i32)))
-            (type (;4;) (func (param i32) (result i64)))
-            (type (;5;) (func (param i32 i32 i32) (result i64)))
-            (type (;6;) (func (param i32 i32 i32)))
-            (type (;7;) (func (param i32 i32) (result i32)))
-            (type (;8;) (func (param i32 i32 i32 i32)))
-            (type (;9;) (func (param i64 i32) (result i64)))
-            (type (;10;) (func (param i32 i64)))
-            (import "env" "memory" (memory (;0;) 256 256))
-            (import "env" "DYNAMICTOP_PTR" (global (;0;) i32))
-            (import "env" "STACKTOP" (global (;1;) i32))
-            (import "env" "enlargeMemory" (func (;0;) (type 0)))
-            (import "env" "getTotalMemory" (func (;1;) (type 0)))
-            (import "env" "abortOnCannotGrowMemory" (func (;2;) (type 0)))
-            (import "env" "___setErrNo" (func (;3;) (type 1)))
-            (func (;4;) (type 9) (param i64 i32) (result i64)
-              local.get 0
-              i32.const 64
-              local.get 1
-              i32.sub
-              i64.extend_i32_u
-              i64.shl
-              local.get 0
-              local.get 1
-              i64.extend_i32_u
-              i64.shr_u
-              i64.or
-            )
-          )
-        "#;
-        let raw_wasm = parse_wat(input).bytes();
-        let injected_raw_wasm = inject(&raw_wasm, &ConstantCostRules::default(), "other")
-            .expect("inject_gas_counter call failed");
-        wasmparser::validate(&injected_raw_wasm).unwrap();
+    fn test_br_table_default_target() {
+        let raw_wasm = parse_wat(
+            r#"(module
+			(func (param i32)
+			  (block
+				(block
+				  local.get 0
+				  br_table 0 1)
+				nop)
+			  nop
+			  nop))"#,
+        )
+        .bytes();
 
+        // By default, the default target of the `br_table` is ignored as in version 0.4, so the
+        // `nop` of the outer block is charged along with the code before the branch
+        let injected_raw_wasm = inject(&raw_wasm, &ConstantCostRules::default(), "env").unwrap();
         assert!(check_expect_function_body(
             &injected_raw_wasm,
             0,
             &[
-                I64Const(11),
-                Call(5),
-                LocalGet(0),
-                I32Const(64),
-                LocalGet(1),
-                I32Sub,
-                I64ExtendI32U,
-                I64Shl,
+                I64Const(7),
+                Call(1),
+                Block(BlockType::Empty),
+                Block(BlockType::Empty),
                 LocalGet(0),
-                LocalGet(1),
-                I64ExtendI32U,
-                I64ShrU,
-                I64Or,
+                BrTable(vec![0].into(), 1),
+                End,
+                Nop,
+                End,
+                Nop,
+                Nop,
                 End,
             ]
         ));
 
-        // 1 is gas counter
+        // With merged blocks, the default target skips the `nop` of the outer block, which is
+        // charged on its own
+        let mut options = InjectOptions::new(GasCounter::Import {
+            module: "env".into(),
+        });
+        options.merge_blocks = true;
+        let (injected_raw_wasm, _) =
+            inject_with_options(&raw_wasm, &ConstantCostRules::default(), &options).unwrap();
         assert!(check_expect_function_body(
             &injected_raw_wasm,
-            1,
+            0,
             &[
-                GlobalGet(2), // 2 imported globals, so gas one is third
+                I64Const(6),
+                Call(1),
+                Block(BlockType::Empty),
+                Block(BlockType::Empty),
                 LocalGet(0),
-                I64Sub,
-                GlobalSet(2),
-                GlobalGet(2),
-                I64Const(0),
-                I64LtS,
-                If(BlockType::Empty),
-                Unreachable,
+                BrTable(vec![0].into(), 1),
+                End,
+                I64Const(1),
+                Call(1),
+                Nop,
+                End,
+                Nop,
+                Nop,
                 End,
-                End
             ]
         ));
+        assert_eq!(
+            verify(&raw_wasm, &injected_raw_wasm, &ConstantCostRules::default()).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_global_segment_offsets() {
+        let input = r#"
+        (module
+            (import "env" "memory_base" (global $memory_base i32))
+            (import "env" "table_base" (global $table_base i32))
+            (import "env" "memory" (memory 1))
+            (import "env" "table" (table 1 funcref))
+            (global $g (mut i32) (i32.const 0))
+            (func $f)
+            (elem (global.get $table_base) $f)
+            (data (global.get $memory_base) "abc")
+          )
+        "#;
+        let raw_wasm = parse_wat(input).bytes();
+        let injected_raw_wasm = inject(&raw_wasm, &ConstantCostRules::default(), "env").unwrap();
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+
+        let printed = wasmprinter::print_bytes(&injected_raw_wasm).unwrap();
+        assert!(printed.contains("(global.get $table_base)"), "{}", printed);
+        assert!(printed.contains("(global.get $memory_base)"), "{}", printed);
+    }
+
+    #[test]
+    fn test_element_segments_on_table_0() {
+        let input = r#"
+        (module
+            (import "env" "r" (global $r externref))
+            (table $refs 1 externref)
+            (table $funcs 1 funcref)
+            (func $f)
+            (elem (table $refs) (i32.const 0) externref (global.get $r))
+            (elem (table $funcs) (i32.const 0) func $f)
+          )
+        "#;
+        let raw_wasm = parse_wat(input).bytes();
+        let injected_raw_wasm = inject(&raw_wasm, &ConstantCostRules::default(), "env").unwrap();
+        wasmparser::validate(&injected_raw_wasm).unwrap();
+
+        // The `externref` segment on table 0 must not be encoded as a `funcref` one
+        let segments = |raw_wasm: &[u8]| {
+            let module = ModuleInfo::new(raw_wasm).unwrap();
+            let ele_section = module.raw_sections.get(&SectionId::Element.into()).unwrap();
+            ElementSectionReader::new(&ele_section.data, 0)
+                .unwrap()
+                .into_iter()
+                .map(|segment| {
+                    let segment = segment.unwrap();
+                    let table_index = match segment.kind {
+                        ElementKind::Active { table_index, .. } => table_index,
+                        _ => panic!("expected an active segment"),
+                    };
+                    (table_index, segment.ty)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(segments(&injected_raw_wasm), segments(&raw_wasm));
+    }
+
+    #[test]
+    fn test_mutable_global_segment_offset_fails() {
+        let input = r#"
+        (module
+            (import "env" "memory_base" (global $memory_base (mut i32)))
+            (import "env" "memory" (memory 1))
+            (data (global.get $memory_base) "abc")
+          )
+        "#;
+        let raw_wasm = parse_wat(input).bytes();
+        let err = inject(&raw_wasm, &ConstantCostRules::default(), "env").unwrap_err();
+        assert!(matches!(err, InstrumentError::NonConstSegmentOffset));
     }
 
     #[test]
@@ -1535,12 +4502,61 @@ mod tests {
           )
         "#;
         let raw_wasm = parse_wat(input).bytes();
-        let estr = inject(&raw_wasm, &ConstantCostRules::default(), "other")
-            .unwrap_err()
-            .to_string();
+        let err = inject(&raw_wasm, &ConstantCostRules::default(), "other").unwrap_err();
+        assert!(matches!(err, InstrumentError::DuplicateGasGlobal));
+        let estr = err.to_string();
         assert!(estr == "expected 1 gas global", "error was {}", estr);
     }
 
+    #[test]
+    fn test_forbidden_instruction_fails() {
+        struct NoMemoryGrow;
+
+        impl Rules for NoMemoryGrow {
+            fn instruction_cost(&self, i: &Operator) -> Result<InstructionCost> {
+                match i {
+                    Operator::MemoryGrow { .. } => Err(anyhow!("memory.grow is forbidden")),
+                    _ => Ok(InstructionCost::Fixed(1)),
+                }
+            }
+
+            fn gas_charge_cost(&self) -> u64 {
+                0
+            }
+
+            fn linear_calc_cost(&self) -> u64 {
+                0
+            }
+        }
+
+        let input = r#"
+        (module
+            (import "env" "f" (func (;0;)))
+            (memory 1)
+            (func (;1;))
+            (func (;2;) (result i32)
+              i32.const 1
+              memory.grow
+            )
+          )
+        "#;
+        let raw_wasm = parse_wat(input).bytes();
+        match inject(&raw_wasm, &NoMemoryGrow, "env").unwrap_err() {
+            InstrumentError::ForbiddenInstruction {
+                func_index,
+                op_index,
+                op,
+                reason,
+            } => {
+                assert_eq!(func_index, 2);
+                assert_eq!(op_index, 1);
+                assert!(op.starts_with("MemoryGrow"), "op was {}", op);
+                assert_eq!(reason.to_string(), "memory.grow is forbidden");
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
     test_gas_counter_injection! {
         name = nested;
         input = r#"
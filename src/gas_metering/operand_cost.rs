@@ -0,0 +1,454 @@
+//! The gas costs of instructions which depend on the value of one of their operands.
+
+use super::InstructionCost;
+use alloc::{vec, vec::Vec};
+use anyhow::{anyhow, Result};
+use wasm_encoder::{Instruction, ValType};
+
+/// The largest charge, in which the dynamic charges saturate instead of overflowing.
+const MAX_CHARGE: i64 = i64::MAX;
+
+/// How the dynamic part of the cost of an instruction grows with one of its operands, the number
+/// of units. See [`InstructionCost`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum OperandCost {
+    /// The cost per unit.
+    Linear(u32),
+    Quadratic {
+        linear: u32,
+        quadratic: u32,
+        total_memory: bool,
+    },
+    /// The first unit of each segment and the cost per unit from there on.
+    Piecewise {
+        segments: Vec<(u64, u32)>,
+        total_memory: bool,
+    },
+}
+
+/// The locals used by [`OperandCost::charge`].
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ChargeLocals {
+    /// An `i32` local duplicating `i32` operands.
+    pub operand: u32,
+    /// An `i64` local holding the number of units.
+    pub units: u32,
+    /// An `i64` local holding the size of the memory.
+    pub memory_size: u32,
+    /// An `i64` local for intermediate results.
+    pub temp: u32,
+}
+
+impl OperandCost {
+    /// Splits the cost of an instruction into its base and the cost of its operand, along with
+    /// the depth of the operand below the top of the stack.
+    ///
+    /// Fails if the cost is out of the bounds supported by the injected code.
+    pub fn split(cost: InstructionCost) -> Result<(u64, Option<(OperandCost, u32)>)> {
+        Ok(match cost {
+            InstructionCost::Fixed(c) => (c, None),
+            InstructionCost::Linear(base, cost_per) => {
+                (base, Some((OperandCost::linear(cost_per.get())?, 0)))
+            }
+            InstructionCost::LinearOperand(base, cost_per, depth) => {
+                (base, Some((OperandCost::linear(cost_per.get())?, depth)))
+            }
+            InstructionCost::Quadratic {
+                base,
+                linear,
+                quadratic,
+                total_memory,
+            } => {
+                let operand_cost = OperandCost::Quadratic {
+                    linear,
+                    quadratic,
+                    total_memory,
+                };
+                (base, Some((operand_cost, 0)))
+            }
+            InstructionCost::Piecewise {
+                base,
+                segments,
+                total_memory,
+            } => {
+                if segments.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                    return Err(anyhow!(
+                        "the segments of a piecewise cost must be sorted by their first unit"
+                    ));
+                }
+                let operand_cost = OperandCost::Piecewise {
+                    segments,
+                    total_memory,
+                };
+                (base, Some((operand_cost, 0)))
+            }
+        })
+    }
+
+    fn linear(cost_per: u32) -> Result<OperandCost> {
+        // Enforce that cost per unit fits in 31 bits
+        if cost_per >= 0x8000_0000 {
+            return Err(anyhow!("cost per unit excedes the 0x80000000 limit"));
+        }
+        Ok(OperandCost::Linear(cost_per))
+    }
+
+    /// Whether the units are added to the size of the memory grown by the instruction.
+    pub fn total_memory(&self) -> bool {
+        match self {
+            OperandCost::Linear(_) => false,
+            OperandCost::Quadratic { total_memory, .. }
+            | OperandCost::Piecewise { total_memory, .. } => *total_memory,
+        }
+    }
+
+    /// Returns the cost of the given number of units, `None` if it overflows.
+    pub fn static_cost(&self, units: u64) -> Option<u64> {
+        match self {
+            OperandCost::Linear(cost_per) => units.checked_mul((*cost_per).into()),
+            OperandCost::Quadratic {
+                linear, quadratic, ..
+            } => units
+                .checked_mul((*linear).into())?
+                .checked_add(units.checked_mul(units)?.checked_mul((*quadratic).into())?),
+            OperandCost::Piecewise { segments, .. } => {
+                segments
+                    .iter()
+                    .enumerate()
+                    .try_fold(0u64, |cost, (index, (first, cost_per))| {
+                        let end = segments.get(index + 1).map_or(u64::MAX, |(next, _)| *next);
+                        let segment_units = units.min(end).saturating_sub(*first);
+                        cost.checked_add(segment_units.checked_mul((*cost_per).into())?)
+                    })
+            }
+        }
+    }
+
+    /// Returns the instructions pushing the charge of the operand on top of the stack, which is
+    /// left in place.
+    ///
+    /// The operand is an `i32` or an `i64` taken as unsigned, and `memory` is the memory grown by
+    /// it if the cost is for the total memory. The charge saturates at `i64::MAX`.
+    pub fn charge(
+        &self,
+        operand_type: ValType,
+        memory: Option<u32>,
+        locals: ChargeLocals,
+    ) -> Vec<Instruction<'static>> {
+        use Instruction::*;
+
+        let ChargeLocals {
+            operand,
+            units,
+            memory_size,
+            temp,
+        } = locals;
+
+        // The number of units is the operand, up to `i64::MAX`
+        let mut code = if operand_type == ValType::I64 {
+            let mut code = vec![LocalTee(units)];
+            code.extend(min(units, MAX_CHARGE as u64));
+            code.push(LocalSet(units));
+            code
+        } else {
+            vec![
+                LocalTee(operand),
+                LocalGet(operand),
+                I64ExtendI32U,
+                LocalSet(units),
+            ]
+        };
+        // The index type of a memory is the type of the operand of `memory.grow`
+        if let Some(mem) = memory {
+            code.push(MemorySize(mem));
+            if operand_type == ValType::I32 {
+                code.push(I64ExtendI32U);
+            }
+            code.push(LocalSet(memory_size));
+        }
+
+        let mut terms = 0;
+        let mut add_term = |code: &mut Vec<Instruction<'static>>,
+                            term: Vec<Instruction<'static>>| {
+            code.extend(term);
+            if terms > 0 {
+                code.extend([I64Add, LocalSet(temp)]);
+                code.extend(min(temp, MAX_CHARGE as u64));
+            }
+            terms += 1;
+        };
+        match self {
+            OperandCost::Linear(cost_per) => add_term(&mut code, mul(units, *cost_per)),
+            OperandCost::Quadratic {
+                linear, quadratic, ..
+            } => {
+                if *linear > 0 {
+                    add_term(&mut code, mul(units, *linear));
+                }
+                if *quadratic > 0 {
+                    // The square of the units, or the growth of the square of the total memory
+                    // `units * (2 * memory_size + units)`
+                    let mut term = Vec::new();
+                    let factor = match memory {
+                        Some(_) => {
+                            term.extend([
+                                LocalGet(memory_size),
+                                I64Const(1),
+                                I64Shl,
+                                LocalGet(units),
+                                I64Add,
+                                LocalSet(memory_size),
+                            ]);
+                            term.extend(min(memory_size, MAX_CHARGE as u64));
+                            term.push(LocalSet(memory_size));
+                            memory_size
+                        }
+                        None => units,
+                    };
+                    // Select the saturated product if it overflows, dividing by 1 instead of 0
+                    term.extend([
+                        I64Const(MAX_CHARGE),
+                        LocalGet(units),
+                        LocalGet(factor),
+                        I64Mul,
+                        LocalGet(factor),
+                        I64Const(MAX_CHARGE),
+                        I64Const(1),
+                        LocalGet(units),
+                        LocalGet(units),
+                        I64Eqz,
+                        Select,
+                        I64DivU,
+                        I64GtU,
+                        Select,
+                        LocalSet(temp),
+                    ]);
+                    term.extend(mul(temp, *quadratic));
+                    add_term(&mut code, term);
+                }
+            }
+            OperandCost::Piecewise { segments, .. } => {
+                // The units are counted from the size of the memory
+                if memory.is_some() {
+                    code.extend([
+                        LocalGet(memory_size),
+                        LocalGet(units),
+                        I64Add,
+                        LocalSet(units),
+                    ]);
+                }
+                for (index, (first, cost_per)) in segments.iter().enumerate() {
+                    if *cost_per == 0 {
+                        continue;
+                    }
+                    let end = segments.get(index + 1).map(|(next, _)| *next);
+
+                    // The units of the segment, `min(units, end) - min(units, first)`, minus the
+                    // units of the segment already in the memory
+                    let mut term = Vec::new();
+                    let mut segment_units = |local| {
+                        match end {
+                            Some(end) => term.extend(min(local, end)),
+                            None => term.push(LocalGet(local)),
+                        }
+                        term.extend(min(local, *first));
+                        term.push(I64Sub);
+                    };
+                    segment_units(units);
+                    if memory.is_some() {
+                        segment_units(memory_size);
+                        term.push(I64Sub);
+                    }
+                    term.push(LocalSet(temp));
+                    term.extend(mul(temp, *cost_per));
+                    add_term(&mut code, term);
+                }
+            }
+        }
+        if terms == 0 {
+            code.push(I64Const(0));
+        }
+        code
+    }
+}
+
+/// Pushes the minimum of the unsigned `local` and `bound`.
+fn min(local: u32, bound: u64) -> [Instruction<'static>; 6] {
+    use Instruction::*;
+
+    [
+        I64Const(bound as i64),
+        LocalGet(local),
+        LocalGet(local),
+        I64Const(bound as i64),
+        I64GtU,
+        Select,
+    ]
+}
+
+/// Pushes the product of the unsigned `local` and `factor`, saturating at `i64::MAX`.
+fn mul(local: u32, factor: u32) -> Vec<Instruction<'static>> {
+    use Instruction::*;
+
+    match factor {
+        0 => vec![I64Const(0)],
+        _ => vec![
+            I64Const(MAX_CHARGE),
+            LocalGet(local),
+            I64Const(factor.into()),
+            I64Mul,
+            LocalGet(local),
+            I64Const(MAX_CHARGE / i64::from(factor)),
+            I64GtU,
+            Select,
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCALS: ChargeLocals = ChargeLocals {
+        operand: 0,
+        units: 1,
+        memory_size: 2,
+        temp: 3,
+    };
+
+    /// Runs the charge of `cost` for the given operand and memory size, returning the charge.
+    fn run(cost: &OperandCost, operand: u64, operand_type: ValType, memory_size: u64) -> u64 {
+        use Instruction::*;
+
+        let memory = if cost.total_memory() { Some(0) } else { None };
+        let mut locals = [0u64; 4];
+        let mut stack = vec![operand];
+        for instr in cost.charge(operand_type, memory, LOCALS) {
+            let mut pop = || stack.pop().unwrap();
+            let value = match instr {
+                LocalGet(local) => locals[local as usize],
+                LocalSet(local) => {
+                    locals[local as usize] = pop();
+                    continue;
+                }
+                LocalTee(local) => {
+                    locals[local as usize] = *stack.last().unwrap();
+                    continue;
+                }
+                I64Const(value) => value as u64,
+                I64ExtendI32U => pop() & 0xffff_ffff,
+                MemorySize(_) => memory_size,
+                I64Eqz => (pop() == 0).into(),
+                I64Add | I64Sub | I64Mul | I64Shl | I64DivU | I64GtU => {
+                    let (b, a) = (pop(), pop());
+                    match instr {
+                        I64Add => a.wrapping_add(b),
+                        I64Sub => a.wrapping_sub(b),
+                        I64Mul => a.wrapping_mul(b),
+                        I64Shl => a << b,
+                        I64DivU => a / b,
+                        _ => (a > b).into(),
+                    }
+                }
+                Select => {
+                    let (condition, b, a) = (pop(), pop(), pop());
+                    if condition != 0 {
+                        a
+                    } else {
+                        b
+                    }
+                }
+                instr => panic!("unexpected instruction {:?}", instr),
+            };
+            stack.push(value);
+        }
+        let charge = stack.pop().unwrap();
+        assert_eq!(stack, vec![operand]);
+        charge
+    }
+
+    fn expected_charge(cost: &OperandCost, units: u64, memory_size: u64) -> u64 {
+        let saturated =
+            |cost: Option<u64>| cost.map_or(i64::MAX as u64, |c| c.min(i64::MAX as u64));
+        if cost.total_memory() {
+            let before = cost.static_cost(memory_size).unwrap();
+            saturated(
+                cost.static_cost(memory_size + units)
+                    .map(|after| after - before),
+            )
+        } else {
+            saturated(cost.static_cost(units))
+        }
+    }
+
+    #[test]
+    fn charges_match_static_costs() {
+        let costs = [
+            OperandCost::Linear(7),
+            OperandCost::Quadratic {
+                linear: 3,
+                quadratic: 2,
+                total_memory: false,
+            },
+            OperandCost::Quadratic {
+                linear: 0,
+                quadratic: 5,
+                total_memory: true,
+            },
+            OperandCost::Piecewise {
+                segments: vec![(0, 1), (16, 0), (64, 10)],
+                total_memory: false,
+            },
+            OperandCost::Piecewise {
+                segments: vec![(8, 2), (32, u32::MAX)],
+                total_memory: true,
+            },
+        ];
+        let operands = [
+            0,
+            1,
+            7,
+            8,
+            20,
+            40,
+            100,
+            0xffff_ffff,
+            1 << 40,
+            i64::MAX as u64,
+            u64::MAX,
+        ];
+        for cost in &costs {
+            for memory_size in [0, 10, 50] {
+                for operand in operands {
+                    let units = operand.min(i64::MAX as u64);
+                    assert_eq!(
+                        run(cost, operand, ValType::I64, memory_size),
+                        expected_charge(cost, units, memory_size),
+                        "{:?} of {} from {}",
+                        cost,
+                        operand,
+                        memory_size
+                    );
+                    assert_eq!(
+                        run(cost, operand & 0xffff_ffff, ValType::I32, memory_size),
+                        expected_charge(cost, operand & 0xffff_ffff, memory_size),
+                        "{:?} of i32 {} from {}",
+                        cost,
+                        operand,
+                        memory_size
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_unsorted_segments() {
+        let cost = InstructionCost::Piecewise {
+            base: 1,
+            segments: vec![(0, 1), (8, 2), (8, 3)],
+            total_memory: false,
+        };
+        assert!(OperandCost::split(cost).is_err());
+    }
+}
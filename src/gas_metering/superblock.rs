@@ -0,0 +1,536 @@
+//! Merging of metered blocks across forward branches.
+//!
+//! The metering algorithm of [`super::determine_metered_blocks`] only merges the metered block of
+//! a `block` into the enclosing one when no branch leaves it. This pass works on the control flow
+//! graph of the function instead: the charge of a metered block is moved into the charge of a
+//! dominating metered block in the same loop whenever the former post-dominates the latter within
+//! an iteration of the loop. Every execution of one then implies exactly one execution of the
+//! other, so the amount of gas charged on any path through the function is unchanged, it is only
+//! charged earlier.
+
+use super::MeteredBlock;
+use alloc::{collections::BTreeMap as Map, vec, vec::Vec};
+use anyhow::{anyhow, Result};
+use wasmparser::Operator;
+
+/// An ID for a node in the control flow graph.
+type NodeId = usize;
+
+/// The node where the function starts.
+const ENTRY_NODE: NodeId = 0;
+/// The node where the function returns.
+const TERMINAL_NODE: NodeId = 1;
+
+/// A basic block of the control flow graph.
+#[derive(Debug, Default)]
+struct Node {
+    successors: Vec<NodeId>,
+    /// Position of the innermost `loop` instruction around the node, if any.
+    innermost_loop: Option<usize>,
+}
+
+/// A control frame opened by a function, `block`, `if` or `loop` and closed by `end`.
+struct Frame {
+    /// The node branches to this frame jump to, its exit node unless it is a loop.
+    branch_target: NodeId,
+    exit_node: NodeId,
+    active_node: NodeId,
+    /// For an `if` without `else` yet, the node skipping the `then` arm.
+    if_node: Option<NodeId>,
+    innermost_loop: Option<usize>,
+}
+
+/// The control flow graph of a function body, where `unreachable` is treated as an ordinary
+/// instruction.
+struct ControlFlowGraph {
+    nodes: Vec<Node>,
+    /// The node each instruction belongs to, by position.
+    instruction_nodes: Vec<NodeId>,
+    /// The first node of the body and the enclosing loop of every loop, by position.
+    loops: Map<usize, (NodeId, Option<usize>)>,
+}
+
+impl ControlFlowGraph {
+    /// Returns `None` if the body contains instructions this module can't reason about.
+    fn build(operators: &[Operator]) -> Result<Option<Self>> {
+        use wasmparser::Operator::*;
+
+        let mut graph = ControlFlowGraph {
+            nodes: vec![Node::default(), Node::default()],
+            instruction_nodes: Vec::with_capacity(operators.len()),
+            loops: Map::new(),
+        };
+        let mut stack = vec![Frame {
+            branch_target: TERMINAL_NODE,
+            exit_node: TERMINAL_NODE,
+            active_node: ENTRY_NODE,
+            if_node: None,
+            innermost_loop: None,
+        }];
+
+        for (cursor, instruction) in operators.iter().enumerate() {
+            let frame = stack.last_mut().ok_or_else(|| anyhow!("stack not found"))?;
+            let active_node = frame.active_node;
+            let innermost_loop = frame.innermost_loop;
+            graph.instruction_nodes.push(active_node);
+
+            match instruction {
+                Block { .. } => {
+                    let exit_node = graph.add_node(innermost_loop);
+                    stack.push(Frame {
+                        branch_target: exit_node,
+                        exit_node,
+                        active_node,
+                        if_node: None,
+                        innermost_loop,
+                    });
+                }
+                If { .. } => {
+                    let then_node = graph.add_node(innermost_loop);
+                    let exit_node = graph.add_node(innermost_loop);
+                    graph.add_edge(active_node, then_node);
+                    stack.push(Frame {
+                        branch_target: exit_node,
+                        exit_node,
+                        active_node: then_node,
+                        if_node: Some(active_node),
+                        innermost_loop,
+                    });
+                }
+                Loop { .. } => {
+                    let body_node = graph.add_node(Some(cursor));
+                    let exit_node = graph.add_node(innermost_loop);
+                    graph.add_edge(active_node, body_node);
+                    graph.loops.insert(cursor, (body_node, innermost_loop));
+                    stack.push(Frame {
+                        branch_target: body_node,
+                        exit_node,
+                        active_node: body_node,
+                        if_node: None,
+                        innermost_loop: Some(cursor),
+                    });
+                }
+                Else => {
+                    let else_node = graph.add_node(innermost_loop);
+                    let frame = stack.last_mut().ok_or_else(|| anyhow!("stack not found"))?;
+                    let if_node = frame
+                        .if_node
+                        .take()
+                        .ok_or_else(|| anyhow!("else without if"))?;
+                    frame.active_node = else_node;
+                    let exit_node = frame.exit_node;
+                    graph.add_edge(active_node, exit_node);
+                    graph.add_edge(if_node, else_node);
+                }
+                End => {
+                    let frame = stack.pop().ok_or_else(|| anyhow!("stack not found"))?;
+                    graph.add_edge(active_node, frame.exit_node);
+                    if let Some(if_node) = frame.if_node {
+                        graph.add_edge(if_node, frame.exit_node);
+                    }
+                    if let Some(parent) = stack.last_mut() {
+                        parent.active_node = frame.exit_node;
+                    }
+                }
+                Br { relative_depth } | BrIf { relative_depth } => {
+                    let target = branch_target(&stack, *relative_depth)?;
+                    graph.add_edge(active_node, target);
+                    let next_node = graph.add_node(innermost_loop);
+                    if let BrIf { .. } = instruction {
+                        graph.add_edge(active_node, next_node);
+                    }
+                    set_active_node(&mut stack, next_node)?;
+                }
+                BrTable { targets } => {
+                    for depth in targets.targets().chain([Ok(targets.default())]) {
+                        let target = branch_target(&stack, depth?)?;
+                        graph.add_edge(active_node, target);
+                    }
+                    let next_node = graph.add_node(innermost_loop);
+                    set_active_node(&mut stack, next_node)?;
+                }
+                Return => {
+                    graph.add_edge(active_node, TERMINAL_NODE);
+                    let next_node = graph.add_node(innermost_loop);
+                    set_active_node(&mut stack, next_node)?;
+                }
+                Try { .. }
+                | Catch { .. }
+                | CatchAll
+                | Throw { .. }
+                | Rethrow { .. }
+                | Delegate { .. }
+                | ReturnCall { .. }
+                | ReturnCallIndirect { .. } => return Ok(None),
+                _ => {}
+            }
+        }
+
+        if !stack.is_empty() {
+            return Err(anyhow!("function body is not terminated by end"));
+        }
+        Ok(Some(graph))
+    }
+
+    /// Returns the nodes of every loop, including those of its nested loops, and all nodes for
+    /// `None`.
+    fn loop_nodes(&self) -> Map<Option<usize>, Vec<NodeId>> {
+        let mut loop_nodes: Map<_, Vec<_>> = Map::new();
+        for (node_id, node) in self.nodes.iter().enumerate() {
+            let mut current = node.innermost_loop;
+            while let Some(pos) = current {
+                loop_nodes.entry(Some(pos)).or_default().push(node_id);
+                current = self.loops.get(&pos).and_then(|(_, parent)| *parent);
+            }
+            loop_nodes.entry(None).or_default().push(node_id);
+        }
+        loop_nodes
+    }
+
+    /// Returns the post-dominator tree of the given loop, or function for `None`, where leaving
+    /// the loop or jumping to its start counts as returning. A node of the loop post-dominates
+    /// another one in this tree if it is executed after it, before the next iteration.
+    fn post_dominators(&self, innermost_loop: Option<usize>, nodes: &[NodeId]) -> PostDominators {
+        let loop_start =
+            innermost_loop.and_then(|pos| self.loops.get(&pos).map(|(start, _)| *start));
+        // The nodes are numbered from 1, 0 being the exit of the loop
+        let local_ids = nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (*node, index + 1))
+            .collect::<Map<_, _>>();
+
+        let mut predecessors = vec![Vec::new(); nodes.len() + 1];
+        for (index, node) in nodes.iter().enumerate() {
+            for successor in &self.nodes[*node].successors {
+                // Jumping to the start of the loop, leaving it or returning
+                let local_id = if Some(*successor) == loop_start || *successor == TERMINAL_NODE {
+                    0
+                } else {
+                    local_ids.get(successor).copied().unwrap_or(0)
+                };
+                predecessors[local_id].push(index + 1);
+            }
+        }
+        PostDominators {
+            idom: immediate_dominators(&predecessors, 0),
+            local_ids,
+        }
+    }
+
+    fn add_node(&mut self, innermost_loop: Option<usize>) -> NodeId {
+        self.nodes.push(Node {
+            successors: Vec::new(),
+            innermost_loop,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn add_edge(&mut self, from: NodeId, to: NodeId) {
+        self.nodes[from].successors.push(to);
+    }
+}
+
+/// The post-dominator tree of the nodes of a loop.
+struct PostDominators {
+    /// The immediate post-dominators by local ID.
+    idom: Vec<Option<usize>>,
+    local_ids: Map<NodeId, usize>,
+}
+
+impl PostDominators {
+    /// Returns whether `node` is executed after every execution of `other`, both being nodes of
+    /// the loop.
+    fn post_dominates(&self, node: NodeId, other: NodeId) -> bool {
+        match (self.local_ids.get(&node), self.local_ids.get(&other)) {
+            (Some(node), Some(other)) => is_ancestor(&self.idom, *node, *other),
+            _ => false,
+        }
+    }
+}
+
+fn set_active_node(stack: &mut [Frame], node: NodeId) -> Result<()> {
+    stack
+        .last_mut()
+        .ok_or_else(|| anyhow!("stack not found"))?
+        .active_node = node;
+    Ok(())
+}
+
+fn branch_target(stack: &[Frame], relative_depth: u32) -> Result<NodeId> {
+    stack
+        .len()
+        .checked_sub(relative_depth as usize + 1)
+        .map(|index| stack[index].branch_target)
+        .ok_or_else(|| anyhow!("branch target not found"))
+}
+
+/// Returns the immediate dominator of every node reachable from `entry` in the graph given by
+/// `successors`, and `None` for the entry itself and for the unreachable nodes.
+///
+/// This is the iterative algorithm of Cooper, Harvey and Kennedy, "A Simple, Fast Dominance
+/// Algorithm".
+fn immediate_dominators(successors: &[Vec<NodeId>], entry: NodeId) -> Vec<Option<NodeId>> {
+    let len = successors.len();
+
+    // Depth-first search for the postorder of the reachable nodes
+    let mut postorder = Vec::with_capacity(len);
+    let mut visited = vec![false; len];
+    let mut stack = vec![(entry, 0)];
+    visited[entry] = true;
+    while let Some((node, next_edge)) = stack.pop() {
+        match successors[node].get(next_edge) {
+            Some(&successor) => {
+                stack.push((node, next_edge + 1));
+                if !visited[successor] {
+                    visited[successor] = true;
+                    stack.push((successor, 0));
+                }
+            }
+            None => postorder.push(node),
+        }
+    }
+
+    let mut postorder_index = vec![0; len];
+    for (index, node) in postorder.iter().enumerate() {
+        postorder_index[*node] = index;
+    }
+    let mut predecessors = vec![Vec::new(); len];
+    for &node in &postorder {
+        for &successor in &successors[node] {
+            predecessors[successor].push(node);
+        }
+    }
+
+    let mut idom: Vec<Option<NodeId>> = vec![None; len];
+    idom[entry] = Some(entry);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        // In reverse postorder, skipping the entry
+        for &node in postorder.iter().rev().skip(1) {
+            let mut new_idom: Option<NodeId> = None;
+            for &predecessor in &predecessors[node] {
+                // Nothing is above the entry, which saves walking up to it from every predecessor
+                if new_idom == Some(entry) {
+                    break;
+                }
+                if idom[predecessor].is_none() {
+                    continue;
+                }
+                new_idom = Some(match new_idom {
+                    None => predecessor,
+                    Some(mut other) => {
+                        let mut finger = predecessor;
+                        while finger != other {
+                            while postorder_index[finger] < postorder_index[other] {
+                                finger = idom[finger].expect("processed node; qed");
+                            }
+                            while postorder_index[other] < postorder_index[finger] {
+                                other = idom[other].expect("processed node; qed");
+                            }
+                        }
+                        finger
+                    }
+                });
+            }
+            if new_idom != idom[node] {
+                idom[node] = new_idom;
+                changed = true;
+            }
+        }
+    }
+    idom[entry] = None;
+    idom
+}
+
+/// Returns whether `ancestor` is `node` or one of its ancestors in the tree given by `idom`.
+fn is_ancestor(idom: &[Option<NodeId>], ancestor: NodeId, mut node: NodeId) -> bool {
+    loop {
+        if node == ancestor {
+            return true;
+        }
+        match idom[node] {
+            Some(parent) => node = parent,
+            None => return false,
+        }
+    }
+}
+
+/// Moves the cost of every metered block into the metered block of its closest dominating node
+/// with a metered block, if it post-dominates that node and both are in the same loop, and removes
+/// the merged blocks. `blocks` must be sorted by start position.
+///
+/// Leaves the blocks alone if the function uses the exception handling or the tail call proposals.
+pub(super) fn merge_metered_blocks(
+    func_body: &wasmparser::FunctionBody,
+    blocks: &mut Vec<MeteredBlock>,
+) -> Result<()> {
+    let operators = func_body
+        .get_operators_reader()?
+        .into_iter()
+        .collect::<wasmparser::Result<Vec<Operator>>>()?;
+    let graph = match ControlFlowGraph::build(&operators)? {
+        Some(graph) => graph,
+        None => return Ok(()),
+    };
+
+    let successors = graph
+        .nodes
+        .iter()
+        .map(|node| node.successors.clone())
+        .collect::<Vec<_>>();
+    let idom = immediate_dominators(&successors, ENTRY_NODE);
+    let mut loop_nodes = graph.loop_nodes();
+    // The post-dominators of the nodes of each loop
+    let mut ipdoms = Map::new();
+
+    // The index of the block each block is merged into, and the block charged in each node
+    let mut merged_into = vec![None; blocks.len()];
+    let mut node_blocks = Map::new();
+    for (index, block) in blocks.iter().enumerate() {
+        let node = *graph
+            .instruction_nodes
+            .get(block.start_pos)
+            .ok_or_else(|| anyhow!("metered block out of the function body"))?;
+
+        let innermost_loop = graph.nodes[node].innermost_loop;
+        let mut dominator = idom[node];
+        while let Some(candidate) = dominator {
+            if graph.nodes[candidate].innermost_loop != innermost_loop {
+                break;
+            }
+            if let Some(&candidate_block) = node_blocks.get(&candidate) {
+                let ipdom = ipdoms.entry(innermost_loop).or_insert_with(|| {
+                    let nodes = loop_nodes.remove(&innermost_loop).unwrap_or_default();
+                    graph.post_dominators(innermost_loop, &nodes)
+                });
+                if ipdom.post_dominates(node, candidate) {
+                    let mut target: usize = candidate_block;
+                    while let Some(next) = merged_into[target] {
+                        target = next;
+                    }
+                    merged_into[index] = Some(target);
+                }
+                break;
+            }
+            dominator = idom[candidate];
+        }
+        node_blocks.insert(node, index);
+    }
+
+    for index in 0..blocks.len() {
+        if let Some(target) = merged_into[index] {
+            match blocks[target].cost.checked_add(blocks[index].cost) {
+                Some(cost) => blocks[target].cost = cost,
+                // Keep the block, it is charged separately
+                None => merged_into[index] = None,
+            }
+        }
+    }
+    let mut merged = merged_into.iter();
+    blocks.retain(|_| merged.next().map_or(true, Option::is_none));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{determine_metered_blocks, ModuleTypes},
+        *,
+    };
+    use crate::gas_metering::ConstantCostRules;
+    use wasmparser::{CodeSectionReader, FunctionBody, Payload::CodeSectionStart};
+
+    fn metered_blocks(source: &str, merge: bool) -> Vec<(usize, u64)> {
+        let module_bytes = wat::parse_str(source).unwrap();
+        let payload = wasmparser::Parser::new(0)
+            .parse_all(&module_bytes)
+            .map(|v| v.unwrap())
+            .find(|payload| matches!(payload, CodeSectionStart { .. }))
+            .unwrap();
+        let range = match payload {
+            CodeSectionStart { range, .. } => range,
+            _ => unreachable!(),
+        };
+        let func_body = CodeSectionReader::new(&module_bytes[range], 0)
+            .unwrap()
+            .into_iter()
+            .collect::<wasmparser::Result<Vec<FunctionBody>>>()
+            .unwrap()
+            .remove(0);
+        let (mut blocks, _) = determine_metered_blocks(
+            &func_body,
+            &ConstantCostRules::default(),
+            0,
+            0,
+            &ModuleTypes::default(),
+            true,
+        )
+        .unwrap();
+        if merge {
+            merge_metered_blocks(&func_body, &mut blocks).unwrap();
+        }
+        blocks
+            .iter()
+            .map(|block| (block.start_pos, block.cost))
+            .collect()
+    }
+
+    #[test]
+    fn merges_code_after_forward_branch() {
+        // The second `nop` is skipped by the branch like the first one
+        let source = r#"(module
+			(func (param i32)
+			  (block
+				(block
+				  local.get 0
+				  br_if 1
+				  nop)
+				nop)
+			  nop))"#;
+        assert_eq!(metered_blocks(source, false), vec![(0, 5), (4, 1), (6, 1)]);
+        assert_eq!(metered_blocks(source, true), vec![(0, 5), (4, 2)]);
+    }
+
+    #[test]
+    fn merges_code_after_else() {
+        // The code after the `if` is only reached through the `else` arm
+        let source = r#"(module
+			(func (param i32)
+			  (block
+				local.get 0
+				(if
+				  (then
+					br 1)
+				  (else
+					nop))
+				nop)
+			  nop))"#;
+        assert_eq!(
+            metered_blocks(source, false),
+            vec![(0, 4), (3, 1), (5, 1), (7, 1)]
+        );
+        assert_eq!(metered_blocks(source, true), vec![(0, 4), (3, 1), (5, 2)]);
+    }
+
+    #[test]
+    fn keeps_skipped_code_separate() {
+        for body in [
+            // The code after the `if` is skipped by returning
+            "local.get 0 (if (then local.get 0 br_if 1)) nop",
+            // The code after the block is skipped by continuing the loop
+            "(loop (block local.get 0 br_if 1 nop) nop)",
+            // The loop body runs more often than the code before it
+            "nop (loop local.get 0 br_if 0)",
+        ] {
+            let source = format!("(module (func (param i32) {}))", body);
+            assert_eq!(
+                metered_blocks(&source, true),
+                metered_blocks(&source, false),
+                "{}",
+                body
+            );
+        }
+    }
+}
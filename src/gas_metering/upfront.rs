@@ -0,0 +1,262 @@
+//! Charging the gas of loop-free functions up front.
+//!
+//! Instead of charging every metered block when it is reached, the most expensive path through the
+//! function is charged once at its start. Wherever the control flow splits, the difference with the
+//! cheaper branch is refunded when it is taken, so that the amount of gas charged on any path
+//! through the function is unchanged once it returns.
+
+use super::MeteredBlock;
+use alloc::vec::Vec;
+use anyhow::{anyhow, Result};
+use wasmparser::Operator;
+
+/// Where a refund of the gas charged up front is inserted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum RefundKind {
+    /// Before the instruction at the position of the refund.
+    Before,
+    /// In an `else` arm added before the `end` at the position of the refund, which closes an `if`
+    /// without `else`.
+    Else,
+    /// Before the `br_if` at the position of the refund, only if it branches.
+    Branch,
+}
+
+/// A refund of the gas charged up front.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Refund {
+    pub pos: usize,
+    pub kind: RefundKind,
+    pub amount: u64,
+}
+
+/// The charge of a function at its start and the refunds of the paths cheaper than the most
+/// expensive one.
+#[derive(Debug)]
+pub(super) struct UpfrontCharge {
+    pub cost: u64,
+    /// Sorted by position, then by kind.
+    pub refunds: Vec<Refund>,
+}
+
+/// A control frame seen from its `end`, walking the function body backwards.
+struct Frame {
+    /// The most expensive cost from the `end` to the end of the function.
+    exit_cost: u64,
+    end_pos: usize,
+    /// The start position and the most expensive cost from the start of the `else` arm, if any.
+    else_arm: Option<(usize, u64)>,
+}
+
+/// Computes the charge replacing the given metered blocks, which must be sorted by start position.
+///
+/// Returns `None` if the function contains loops, a `br_table` whose targets don't cost the same,
+/// instructions of the exception handling or the tail call proposals, or if the charge could
+/// overflow an `i64`.
+pub(super) fn charge_upfront(
+    func_body: &wasmparser::FunctionBody,
+    blocks: &[MeteredBlock],
+) -> Result<Option<UpfrontCharge>> {
+    use wasmparser::Operator::*;
+
+    let operators = func_body
+        .get_operators_reader()?
+        .into_iter()
+        .collect::<wasmparser::Result<Vec<Operator>>>()?;
+
+    let mut refunds = Vec::new();
+    let mut add_refund = |pos, kind, amount| {
+        if amount > 0 {
+            refunds.push(Refund { pos, kind, amount });
+        }
+    };
+
+    // The most expensive cost from the current position to the end of the function
+    let mut cost = 0u64;
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut block_iter = blocks.iter().rev().peekable();
+    for (pos, instruction) in operators.iter().enumerate().rev() {
+        let label_cost = |stack: &[Frame], relative_depth: u32| {
+            stack
+                .len()
+                .checked_sub(relative_depth as usize + 1)
+                .map(|index| stack[index].exit_cost)
+                .ok_or_else(|| anyhow!("branch target not found"))
+        };
+
+        match instruction {
+            End => stack.push(Frame {
+                exit_cost: cost,
+                end_pos: pos,
+                else_arm: None,
+            }),
+            Else => {
+                let frame = stack.last_mut().ok_or_else(|| anyhow!("else without if"))?;
+                frame.else_arm = Some((pos + 1, cost));
+                cost = frame.exit_cost;
+            }
+            If { .. } => {
+                let frame = stack.pop().ok_or_else(|| anyhow!("if without end"))?;
+                let then_cost = cost;
+                let else_cost = frame.else_arm.map_or(frame.exit_cost, |(_, cost)| cost);
+                cost = then_cost.max(else_cost);
+
+                add_refund(pos + 1, RefundKind::Before, cost - then_cost);
+                match frame.else_arm {
+                    Some((else_pos, _)) => {
+                        add_refund(else_pos, RefundKind::Before, cost - else_cost)
+                    }
+                    None => add_refund(frame.end_pos, RefundKind::Else, cost - else_cost),
+                }
+            }
+            Block { .. } => {
+                stack.pop().ok_or_else(|| anyhow!("block without end"))?;
+            }
+            Br { relative_depth } => cost = label_cost(&stack, *relative_depth)?,
+            BrIf { relative_depth } => {
+                let fallthrough_cost = cost;
+                let branch_cost = label_cost(&stack, *relative_depth)?;
+                cost = fallthrough_cost.max(branch_cost);
+
+                add_refund(pos + 1, RefundKind::Before, cost - fallthrough_cost);
+                add_refund(pos, RefundKind::Branch, cost - branch_cost);
+            }
+            BrTable { targets } => {
+                let mut target_costs = targets
+                    .targets()
+                    .chain([Ok(targets.default())])
+                    .map(|depth| label_cost(&stack, depth?));
+                cost = target_costs
+                    .next()
+                    .ok_or_else(|| anyhow!("br_table without default target"))??;
+                for target_cost in target_costs {
+                    if target_cost? != cost {
+                        return Ok(None);
+                    }
+                }
+            }
+            Return => cost = 0,
+            Loop { .. }
+            | Try { .. }
+            | Catch { .. }
+            | CatchAll
+            | Throw { .. }
+            | Rethrow { .. }
+            | Delegate { .. }
+            | ReturnCall { .. }
+            | ReturnCallIndirect { .. } => return Ok(None),
+            _ => {}
+        }
+
+        // The blocks are charged before their first instruction
+        while let Some(block) = block_iter.next_if(|block| block.start_pos == pos) {
+            cost = match cost.checked_add(block.cost) {
+                Some(cost) => cost,
+                None => return Ok(None),
+            };
+        }
+    }
+
+    if cost > i64::MAX as u64 {
+        return Ok(None);
+    }
+    refunds.sort_unstable_by_key(|refund| (refund.pos, refund.kind));
+    Ok(Some(UpfrontCharge { cost, refunds }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{determine_metered_blocks, ModuleTypes},
+        *,
+    };
+    use crate::gas_metering::ConstantCostRules;
+    use alloc::vec;
+    use wasmparser::{CodeSectionReader, FunctionBody, Payload::CodeSectionStart};
+
+    /// The position, kind and amount of each refund.
+    type Refunds = Vec<(usize, RefundKind, u64)>;
+
+    fn upfront_charge(body: &str) -> Option<(u64, Refunds)> {
+        let module_bytes = wat::parse_str(format!("(module (func (param i32) {}))", body)).unwrap();
+        let payload = wasmparser::Parser::new(0)
+            .parse_all(&module_bytes)
+            .map(|v| v.unwrap())
+            .find(|payload| matches!(payload, CodeSectionStart { .. }))
+            .unwrap();
+        let range = match payload {
+            CodeSectionStart { range, .. } => range,
+            _ => unreachable!(),
+        };
+        let func_body = CodeSectionReader::new(&module_bytes[range], 0)
+            .unwrap()
+            .into_iter()
+            .collect::<wasmparser::Result<Vec<FunctionBody>>>()
+            .unwrap()
+            .remove(0);
+        let (blocks, _) = determine_metered_blocks(
+            &func_body,
+            &ConstantCostRules::default(),
+            0,
+            0,
+            &ModuleTypes::default(),
+            true,
+        )
+        .unwrap();
+        charge_upfront(&func_body, &blocks).unwrap().map(|charge| {
+            let refunds = charge
+                .refunds
+                .iter()
+                .map(|refund| (refund.pos, refund.kind, refund.amount))
+                .collect();
+            (charge.cost, refunds)
+        })
+    }
+
+    #[test]
+    fn refunds_cheaper_if_arm() {
+        // The `then` arm costs 2 more than the `else` arm
+        assert_eq!(
+            upfront_charge("local.get 0 (if (then nop nop nop) (else nop))"),
+            Some((5, vec![(6, RefundKind::Before, 2)]))
+        );
+        // The missing `else` arm is added for the refund
+        assert_eq!(
+            upfront_charge("local.get 0 (if (then nop nop))"),
+            Some((4, vec![(4, RefundKind::Else, 2)]))
+        );
+    }
+
+    #[test]
+    fn refunds_cheaper_branch() {
+        // Branching skips 2 instructions
+        assert_eq!(
+            upfront_charge("(block local.get 0 br_if 0 nop nop) nop"),
+            Some((6, vec![(2, RefundKind::Branch, 2)]))
+        );
+        // Branching skips 3 instructions, returning skips 3 instructions after the `return`
+        assert_eq!(
+            upfront_charge(
+                "(block local.get 0 br_if 0 local.get 0 (if (then return)) nop) nop nop nop"
+            ),
+            Some((
+                9,
+                vec![(2, RefundKind::Branch, 3), (5, RefundKind::Before, 3)]
+            ))
+        );
+    }
+
+    #[test]
+    fn keeps_functions_with_loops() {
+        assert_eq!(upfront_charge("(loop local.get 0 br_if 0)"), None);
+        // The targets of the `br_table` need different refunds
+        assert_eq!(
+            upfront_charge("(block (block local.get 0 br_table 0 1) nop)"),
+            None
+        );
+        assert_eq!(
+            upfront_charge("(block (block local.get 0 br_table 1 1) nop)"),
+            Some((4, vec![]))
+        );
+    }
+}
@@ -1,18 +1,763 @@
-//! This module is used to validate the correctness of the gas metering algorithm.
+//! This module is used to verify the correctness of the gas metering injected into a module.
 //!
-//! Since the gas metering algorithm is complex, this checks correctness by fuzzing. The testing
-//! strategy is to generate random, valid Wasm modules using Binaryen's translate-to-fuzz
-//! functionality, then ensure for all functions defined, in all execution paths though the
-//! function body that do not trap that the amount of gas charged by the proposed metering
-//! instructions is correct. This is done by constructing a control flow graph and exhaustively
-//! searching through all paths, which may take exponential time in the size of the function body in
-//! the worst case.
-
-use super::{ConstantCostRules, MeteredBlock, Rules};
-use crate::gas_metering::InstructionCost;
+//! [`verify`] recovers the gas charges from the code of the instrumented module, then checks for
+//! all functions defined, in all execution paths through the function body that do not trap, that
+//! the amount of gas charged by the injected metering instructions is correct, and that no
+//! instruction is executed before it has been charged. This is done by constructing a control flow
+//! graph of the original function body and checking the range of the charged minus the actual gas
+//! cost over all paths to every node.
+//!
+//! The tests use it to check the gas metering algorithm by fuzzing: they generate random, valid
+//! Wasm modules using Binaryen's translate-to-fuzz functionality and verify their instrumentation
+//! with various options.
+
+use super::{
+    counted_loop,
+    operand_cost::{ChargeLocals, OperandCost},
+    upfront::{Refund, RefundKind},
+    ContextTracker, HoistedLoop, IndexRemap, LoopCondition, MeteredBlock, MeteredInstruction,
+    ModuleTypes, RulesExt,
+};
+use crate::{
+    utils::{
+        copy_locals,
+        translator::{DefaultTranslator, Translator},
+        ModuleInfo,
+    },
+    InstrumentError,
+};
+use alloc::{format, vec, vec::Vec};
 use anyhow::{anyhow, Result};
-use std::collections::BTreeMap as Map;
-use wasmparser::Operator;
+use wasm_encoder::{Encode, Instruction, SectionId, ValType};
+use wasmparser::{CodeSectionReader, FunctionBody, Operator, Type};
+
+/// A difference between the gas charged by an instrumented function and the cost of its
+/// instructions, found by [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum CostMismatch {
+    /// The instructions of the last basic block of the path are executed before the gas charged
+    /// along the path covers their cost.
+    Undercharged(PathCost),
+    /// The gas charged along the path, which returns from the function or branches back to the
+    /// start of the loop body it starts at, differs from the cost of its instructions.
+    Unbalanced(PathCost),
+    /// The instruction isn't charged dynamically for its operand at the cost given by the rules.
+    /// Instructions priced by their stack top pushed by an `i32.const` may be charged statically
+    /// instead, unless they are priced by the total memory.
+    DynamicCharge {
+        /// Index of the function in the function index space of the instrumented module.
+        func_index: u32,
+        /// Index of the instruction in the original function body.
+        pos: usize,
+        /// Cost per unit given by the rules, `None` if the instruction doesn't have a linear cost.
+        unit_cost: Option<u32>,
+        /// Cost per unit charged dynamically, `None` if the instruction isn't charged dynamically
+        /// at a linear cost.
+        charged_unit_cost: Option<u32>,
+        /// Depth of the operand the instruction is priced by according to the rules, `None` if the
+        /// instruction has a fixed cost.
+        operand_depth: Option<u32>,
+        /// Depth of the operand the instruction is charged dynamically by, `None` if the
+        /// instruction isn't charged dynamically.
+        charged_operand_depth: Option<u32>,
+    },
+}
+
+/// A path through a function whose gas charges don't match its cost, see [`CostMismatch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PathCost {
+    /// Index of the function in the function index space of the instrumented module.
+    pub func_index: u32,
+    /// Index in the original function body of the first instruction of each basic block on the
+    /// path, which starts at the start of the function or of a loop body.
+    pub path: Vec<usize>,
+    /// Gas charged along the path.
+    pub charged: u64,
+    /// Gas refunded along the path.
+    pub refunded: u64,
+    /// Cost of the instructions along the path, including the cost of the gas charges.
+    pub actual: u64,
+}
+
+/// Checks the gas metering injected into `instrumented` by [`inject_with_options`] against the
+/// `original` module and the rules it was injected with, returning the paths through the functions
+/// of the module whose charges don't match their cost. If there are none, every instruction is
+/// charged before it is executed, and the gas charged on every path which doesn't trap is exactly
+/// the cost of its instructions.
+///
+/// The gas charges are recovered from the instrumented code, whatever [`InjectOptions`] it was
+/// injected with, and the rest of the code must match the original code. As in [`inject`], every
+/// static charge costs [`RulesExt::gas_charge_cost`], and every dynamic charge
+/// [`RulesExt::linear_calc_cost`] on top of that. The dynamic charges of quadratic and piecewise
+/// costs are only recognized at the cost given by the rules, so the code charging any other cost is
+/// rejected as not matching the original code.
+///
+/// [`inject`]: super::inject
+/// [`inject_with_options`]: super::inject_with_options
+/// [`InjectOptions`]: super::InjectOptions
+pub fn verify<R: RulesExt>(
+    original: &[u8],
+    instrumented: &[u8],
+    rules: &R,
+) -> Result<Vec<CostMismatch>, InstrumentError> {
+    verify_metering(original, instrumented, rules).map_err(InstrumentError::from)
+}
+
+fn verify_metering<R: RulesExt>(
+    original: &[u8],
+    instrumented: &[u8],
+    rules: &R,
+) -> Result<Vec<CostMismatch>> {
+    let original_info = ModuleInfo::new(original)?;
+    let instrumented_info = ModuleInfo::new(instrumented)?;
+    let original_bodies = function_bodies(&original_info)?;
+    let mut instrumented_bodies = function_bodies(&instrumented_info)?;
+    let imported_functions_count = instrumented_info.imported_functions_count;
+    let types = ModuleTypes::new(&original_info)?;
+    // The function imported by the instrumentation, if any, comes after the imports of the
+    // original module
+    let imported_func =
+        match imported_functions_count.checked_sub(original_info.imported_functions_count) {
+            Some(0) => None,
+            Some(1) => Some(original_info.imported_functions_count),
+            _ => {
+                return Err(anyhow!(
+                    "the instrumented module doesn't import the functions of the original module"
+                ))
+            }
+        };
+
+    // The gas charging function is defined after all other functions, unless gas is charged by
+    // a host function imported after the imports of the original module
+    let code = if instrumented_bodies.len() == original_bodies.len() + 1 {
+        let body = instrumented_bodies
+            .pop()
+            .expect("there is one more function body; qed");
+        let mut operators = operators(&body)?;
+        let gas_global = match operators.first() {
+            Some(Operator::GlobalGet { global_index }) => *global_index,
+            _ => {
+                return Err(anyhow!(
+                    "the gas charging function doesn't read a gas counter"
+                ))
+            }
+        };
+        // Drop the final `end`
+        operators.pop();
+        let inlined_charge = operators
+            .iter()
+            .map(|op| match op {
+                Operator::LocalGet { local_index: 0 } => Ok(None),
+                op => encode(op).map(Some),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let unsigned_counter =
+            inlined_charge.contains(&Some(encode_instruction(&Instruction::I64LtU)));
+        InjectedCode {
+            gas_func: imported_functions_count + original_bodies.len() as u32,
+            remap: IndexRemap {
+                gas_global: Some(gas_global),
+                imported_func,
+            },
+            inlined_charge,
+            unsigned_counter,
+        }
+    } else if instrumented_bodies.len() == original_bodies.len() && imported_func.is_some() {
+        InjectedCode {
+            gas_func: original_info.imported_functions_count,
+            remap: IndexRemap {
+                gas_global: None,
+                imported_func,
+            },
+            inlined_charge: Vec::new(),
+            unsigned_counter: false,
+        }
+    } else {
+        return Err(anyhow!(
+            "the instrumented module doesn't define the functions of the original module"
+        ));
+    };
+
+    let mut mismatches = Vec::new();
+    for (index, (original_body, instrumented_body)) in
+        original_bodies.iter().zip(&instrumented_bodies).enumerate()
+    {
+        let func_index = imported_functions_count + index as u32;
+        let original_operators = operators(original_body)?;
+        let instrumented_operators = operators(instrumented_body)?;
+        let encoded = instrumented_operators
+            .iter()
+            .map(encode)
+            .collect::<Result<Vec<_>>>()?;
+        let Type::Func(func_type) = instrumented_info.get_functype_idx(func_index)?;
+        let mut locals = func_type
+            .params()
+            .iter()
+            .map(|ty| Ok((1, DefaultTranslator.translate_ty(ty)?)))
+            .collect::<Result<Vec<_>>>()?;
+        locals.extend(copy_locals(instrumented_body)?);
+
+        let function = InstrumentedFunction {
+            code: &code,
+            operators: &instrumented_operators,
+            encoded: &encoded,
+            locals: &locals,
+        };
+        let operand_costs = superlinear_costs(&original_operators, rules, index as u32);
+        let charges = function.recover_charges(&original_operators, func_index, &operand_costs)?;
+        let graph = build_control_flow_graph(
+            &original_operators,
+            rules,
+            func_index,
+            index as u32,
+            &types,
+            &charges,
+            &mut mismatches,
+        )?;
+        mismatches.extend(validate_graph_gas_costs(&graph, func_index));
+    }
+    Ok(mismatches)
+}
+
+/// Returns the costs other than linear ones of the instructions priced by their operand, which are
+/// needed to match their charges.
+fn superlinear_costs<R: RulesExt>(
+    operators: &[Operator],
+    rules: &R,
+    defined_index: u32,
+) -> Vec<(usize, OperandCost)> {
+    let mut tracker = ContextTracker::default();
+    let mut costs = Vec::new();
+    for (pos, op) in operators.iter().enumerate() {
+        let ctx = tracker.context(defined_index, pos);
+        tracker.update(op);
+        // Invalid costs are reported when building the control flow graph
+        let operand_cost = rules
+            .instruction_cost_in(&ctx, op)
+            .and_then(OperandCost::split)
+            .ok()
+            .and_then(|(_, operand_cost)| operand_cost);
+        match operand_cost {
+            Some((OperandCost::Linear(_), _)) | None => {}
+            Some((cost, _)) => costs.push((pos, cost)),
+        }
+    }
+    costs
+}
+
+fn function_bodies(module_info: &ModuleInfo) -> Result<Vec<FunctionBody<'_>>> {
+    match module_info.raw_sections.get(&SectionId::Code.into()) {
+        Some(code_section) => Ok(CodeSectionReader::new(&code_section.data, 0)?
+            .into_iter()
+            .collect::<wasmparser::Result<Vec<FunctionBody>>>()?),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn operators<'a>(body: &FunctionBody<'a>) -> Result<Vec<Operator<'a>>> {
+    Ok(body
+        .get_operators_reader()?
+        .into_iter()
+        .collect::<wasmparser::Result<Vec<Operator>>>()?)
+}
+
+fn encode(op: &Operator) -> Result<Vec<u8>> {
+    Ok(encode_instruction(&DefaultTranslator.translate_op(op)?))
+}
+
+fn encode_instruction(instruction: &Instruction) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    instruction.encode(&mut bytes);
+    bytes
+}
+
+/// The gas charging code of an instrumented module.
+struct InjectedCode {
+    /// Index of the gas charging function, or of the imported host function.
+    gas_func: u32,
+    /// How the indices of the original functions and globals are shifted, along with the index of
+    /// the gas counter global, `None` if gas is charged by a host function.
+    remap: IndexRemap,
+    /// The encoded instructions of the gas charging function without the final `end`, with `None`
+    /// in place of the `local.get 0` pushing the charge. Empty if gas is charged by a host function.
+    inlined_charge: Vec<Option<Vec<u8>>>,
+    /// Whether the gas counter is compared to the charges as unsigned integers, so that the
+    /// charges pushed as negative `i64` constants are charges above `i64::MAX`.
+    unsigned_counter: bool,
+}
+
+/// The gas charges recovered from an instrumented function, each sorted by the position of the
+/// original instruction they are inserted before.
+#[derive(Default)]
+struct Charges {
+    /// The static charges, including the cost of the charge.
+    blocks: Vec<MeteredBlock>,
+    /// The dynamic charges of linearly priced instructions.
+    dynamic: Vec<MeteredInstruction>,
+    /// The charges of all iterations of counted loops.
+    loops: Vec<HoistedLoop>,
+    refunds: Vec<Refund>,
+}
+
+/// The body of an instrumented function, in which the injected code is matched.
+///
+/// The matching methods return the number of instructions matched at the given position along
+/// with what they charge or refund.
+struct InstrumentedFunction<'a, 'b> {
+    code: &'b InjectedCode,
+    operators: &'b [Operator<'a>],
+    /// The encoding of each instruction.
+    encoded: &'b [Vec<u8>],
+    /// The types of the locals, including the parameters, and how many there are of each.
+    locals: &'b [(u32, ValType)],
+}
+
+impl<'a, 'b> InstrumentedFunction<'a, 'b> {
+    /// Recovers the charges injected into the function with the `original` body, failing if the
+    /// rest of the instrumented body doesn't match it.
+    ///
+    /// The charges of the costs other than linear ones are only matched for the instructions
+    /// priced by them in `operand_costs`.
+    fn recover_charges(
+        &self,
+        original: &[Operator],
+        func_index: u32,
+        operand_costs: &[(usize, OperandCost)],
+    ) -> Result<Charges> {
+        use wasmparser::Operator::*;
+
+        let mut charges = Charges::default();
+        let mut operand_costs = operand_costs.iter().peekable();
+        let mut at = 0;
+        let mut pos = 0;
+        while let Some(original_op) = original.get(pos) {
+            while operand_costs
+                .next_if(|(cost_pos, _)| *cost_pos < pos)
+                .is_some()
+            {}
+            let operand_cost = operand_costs
+                .peek()
+                .filter(|(cost_pos, _)| *cost_pos == pos)
+                .map(|(_, cost)| cost);
+
+            if let Some((len, cost)) = self.static_charge(at) {
+                charges.blocks.push(MeteredBlock {
+                    start_pos: pos,
+                    cost,
+                });
+                at += len;
+            } else if let Some((len, amount)) = self.refund(at) {
+                charges.refunds.push(Refund {
+                    pos,
+                    kind: RefundKind::Before,
+                    amount,
+                });
+                at += len;
+            } else if let (End, Some((len, amount))) = (original_op, self.else_refund(at)) {
+                charges.refunds.push(Refund {
+                    pos,
+                    kind: RefundKind::Else,
+                    amount,
+                });
+                at += len;
+            } else if let (BrIf { .. }, Some((len, amount))) = (original_op, self.branch_refund(at))
+            {
+                charges.refunds.push(Refund {
+                    pos,
+                    kind: RefundKind::Branch,
+                    amount,
+                });
+                at += len;
+            } else if let Some((len, metered_instr)) =
+                operand_cost.and_then(|cost| self.operand_cost_charge(at, pos, cost, original_op))
+            {
+                charges.dynamic.push(metered_instr);
+                at += len;
+            } else if let Some((len, metered_instr)) = self.dynamic_charge(at, pos) {
+                charges.dynamic.push(metered_instr);
+                at += len;
+            } else if let Some((len, hoisted_loop)) = self.hoisted_charge(at, original, pos) {
+                charges.loops.push(hoisted_loop);
+                at += len;
+            } else if at < self.operators.len() && self.is_copy(at, original_op)? {
+                at += 1;
+                pos += 1;
+            } else {
+                return Err(anyhow!(
+                    "instruction {} of function {} doesn't match the original code",
+                    pos,
+                    func_index
+                ));
+            }
+        }
+
+        if at != self.operators.len() {
+            return Err(anyhow!(
+                "function {} has instructions after the end of the original code",
+                func_index
+            ));
+        }
+        Ok(charges)
+    }
+
+    /// Returns whether the instruction at `at` is a copy of the original instruction, whose
+    /// function or global index is shifted as by the instrumentation.
+    fn is_copy(&self, at: usize, original: &Operator) -> Result<bool> {
+        use wasmparser::Operator::*;
+
+        let remap = &self.code.remap;
+        Ok(match (original, &self.operators[at]) {
+            (
+                Call { function_index },
+                Call {
+                    function_index: copy,
+                },
+            )
+            | (
+                ReturnCall { function_index },
+                ReturnCall {
+                    function_index: copy,
+                },
+            )
+            | (
+                RefFunc { function_index },
+                RefFunc {
+                    function_index: copy,
+                },
+            ) => *copy == remap.func(*function_index),
+            (GlobalGet { global_index }, GlobalGet { global_index: copy })
+            | (GlobalSet { global_index }, GlobalSet { global_index: copy }) => {
+                *copy == remap.global(*global_index)
+            }
+            (Call { .. } | ReturnCall { .. } | RefFunc { .. }, _)
+            | (GlobalGet { .. } | GlobalSet { .. }, _) => false,
+            _ => self.encoded[at] == encode(original)?,
+        })
+    }
+
+    /// Matches a static charge, returning the amount charged.
+    fn static_charge(&self, at: usize) -> Option<(usize, u64)> {
+        use wasmparser::Operator::*;
+
+        match self.operators.get(at..at + 2) {
+            Some([I64Const { value }, Call { function_index }])
+                if *function_index == self.code.gas_func =>
+            {
+                Some((2, self.charge(*value)?))
+            }
+            _ => match self.inlined_charge(at)? {
+                (len, I64Const { value }) => Some((len, self.charge(*value)?)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Returns the amount of gas charged by pushing `value`, `None` if it is negative and the
+    /// counter is signed, in which case the charge adds gas instead.
+    fn charge(&self, value: i64) -> Option<u64> {
+        if value < 0 && !self.code.unsigned_counter {
+            None
+        } else {
+            Some(value as u64)
+        }
+    }
+
+    /// Matches the inlined gas charging function, returning the instruction pushing the charge.
+    fn inlined_charge(&self, at: usize) -> Option<(usize, &'b Operator<'a>)> {
+        let template = &self.code.inlined_charge;
+        if template.is_empty() {
+            return None;
+        }
+        let encoded = self.encoded.get(at..at + template.len())?;
+
+        // All the instructions pushing the charge must be the same
+        let mut charge: Option<&Vec<u8>> = None;
+        let mut charge_pos = None;
+        for (offset, (expected, actual)) in template.iter().zip(encoded).enumerate() {
+            match (expected, charge) {
+                (Some(expected), _) if expected != actual => return None,
+                (Some(_), _) => {}
+                (None, Some(charge)) if charge != actual => return None,
+                (None, Some(_)) => {}
+                (None, None) => {
+                    charge = Some(actual);
+                    charge_pos = Some(at + offset);
+                }
+            }
+        }
+        Some((template.len(), &self.operators[charge_pos?]))
+    }
+
+    /// Matches the charge of the `i64` amount of gas on top of the stack.
+    fn stack_top_charge(&self, at: usize) -> Option<usize> {
+        use wasmparser::Operator::*;
+
+        match self.operators.get(at)? {
+            Call { function_index } if *function_index == self.code.gas_func => Some(1),
+            LocalSet { local_index } => match self.inlined_charge(at + 1)? {
+                (
+                    len,
+                    LocalGet {
+                        local_index: charged,
+                    },
+                ) if charged == local_index => Some(1 + len),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Matches the dynamic charge of the linearly priced instruction at the original position `pos`,
+    /// along with the spilling of the operands above the one it is priced by.
+    fn dynamic_charge(&self, at: usize, pos: usize) -> Option<(usize, MeteredInstruction)> {
+        use wasmparser::Operator::*;
+
+        // The operands are spilled from the top of the stack and restored in the reverse order
+        let spill_locals = self
+            .operators
+            .get(at..)?
+            .iter()
+            .map_while(|op| match op {
+                LocalSet { local_index } => Some(*local_index),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let charge_at = at + spill_locals.len();
+        let (charge_len, unit_cost, operand_type) = self.operand_charge(charge_at)?;
+        let restore_at = charge_at + charge_len;
+        let restored = self
+            .operators
+            .get(restore_at..restore_at + spill_locals.len())?
+            .iter()
+            .zip(spill_locals.iter().rev())
+            .all(|(op, local)| matches!(op, LocalGet { local_index } if local_index == local));
+        if !restored {
+            return None;
+        }
+
+        let spilled = spill_locals
+            .iter()
+            .rev()
+            .map(|local| self.local_type(*local))
+            .collect::<Option<Vec<_>>>()?;
+        let metered_instr = MeteredInstruction {
+            pos,
+            cost: OperandCost::Linear(unit_cost),
+            operand_type,
+            spilled,
+        };
+        Some((restore_at + spill_locals.len() - at, metered_instr))
+    }
+
+    /// Matches the charge of the instruction `original` at the original position `pos` by its
+    /// stack top at the given cost, other than a linear one.
+    fn operand_cost_charge(
+        &self,
+        at: usize,
+        pos: usize,
+        cost: &OperandCost,
+        original: &Operator,
+    ) -> Option<(usize, MeteredInstruction)> {
+        // The locals are matched with any local
+        let locals = ChargeLocals {
+            operand: 0,
+            units: 1,
+            memory_size: 2,
+            temp: 3,
+        };
+        let memory = match original {
+            Operator::MemoryGrow { mem, .. } if cost.total_memory() => Some(*mem),
+            _ => None,
+        };
+        [ValType::I32, ValType::I64]
+            .into_iter()
+            .find_map(|operand_type| {
+                let len =
+                    self.matches_any_locals(at, &cost.charge(operand_type, memory, locals))?;
+                Some((len, operand_type))
+            })
+            .and_then(|(len, operand_type)| {
+                let metered_instr = MeteredInstruction {
+                    pos,
+                    cost: cost.clone(),
+                    operand_type,
+                    spilled: Vec::new(),
+                };
+                Some((len + self.stack_top_charge(at + len)?, metered_instr))
+            })
+    }
+
+    /// Matches the given instructions, in which each local stands for any local as long as it
+    /// stands for the same one everywhere, returning their number.
+    fn matches_any_locals(&self, at: usize, expected: &[Instruction]) -> Option<usize> {
+        use wasmparser::Operator::*;
+
+        let mut locals: Vec<(u32, u32)> = Vec::new();
+        for (offset, instruction) in expected.iter().enumerate() {
+            let actual = self.operators.get(at + offset)?;
+            let local = match (instruction, actual) {
+                (Instruction::LocalGet(expected), LocalGet { local_index })
+                | (Instruction::LocalSet(expected), LocalSet { local_index })
+                | (Instruction::LocalTee(expected), LocalTee { local_index }) => {
+                    Some((*expected, *local_index))
+                }
+                _ => None,
+            };
+            match local {
+                Some((expected, local_index)) => {
+                    match locals.iter().find(|(other, _)| *other == expected) {
+                        Some((_, other_index)) if *other_index != local_index => return None,
+                        Some(_) => {}
+                        None => locals.push((expected, local_index)),
+                    }
+                }
+                None if encode_instruction(instruction) != self.encoded[at + offset] => {
+                    return None
+                }
+                None => {}
+            }
+        }
+        Some(expected.len())
+    }
+
+    /// Returns the type of the given local, including the parameters.
+    fn local_type(&self, local_index: u32) -> Option<ValType> {
+        let mut first = 0u32;
+        for (count, ty) in self.locals {
+            first = first.checked_add(*count)?;
+            if local_index < first {
+                return Some(*ty);
+            }
+        }
+        None
+    }
+
+    /// Matches the charge of the operand of a linearly priced instruction on the stack top,
+    /// returning the cost per unit and the type of the operand.
+    fn operand_charge(&self, at: usize) -> Option<(usize, u32, ValType)> {
+        use wasmparser::Operator::*;
+
+        if let Some(
+            [LocalTee { local_index: tee }, LocalGet { local_index: get }, I64ExtendI32U, I64Const { value }, I64Mul],
+        ) = self.operators.get(at..at + 5)
+        {
+            if tee == get {
+                let unit_cost = u32::try_from(*value).ok()?;
+                return Some((5 + self.stack_top_charge(at + 5)?, unit_cost, ValType::I32));
+            }
+        }
+
+        // The charge of an `i64` operand saturates at `i64::MAX`
+        match self.operators.get(at..at + 9)? {
+            [LocalTee { local_index: tee }, I64Const { value: i64::MAX }, LocalGet { local_index: get }, I64Const { value }, I64Mul, LocalGet {
+                local_index: bound_get,
+            }, I64Const { value: bound }, I64GtU, Select]
+                if tee == get && tee == bound_get =>
+            {
+                let unit_cost = u32::try_from(*value).ok().filter(|&unit| unit > 0)?;
+                if *bound != i64::MAX / i64::from(unit_cost) {
+                    return None;
+                }
+                Some((9 + self.stack_top_charge(at + 9)?, unit_cost, ValType::I64))
+            }
+            _ => None,
+        }
+    }
+
+    /// Matches the charge of all iterations of the counted loop at the original position `pos`.
+    fn hoisted_charge(
+        &self,
+        at: usize,
+        original: &[Operator],
+        pos: usize,
+    ) -> Option<(usize, HoistedLoop)> {
+        use wasmparser::Operator::*;
+
+        let (_, counter, bound, condition) = counted_loop(original, pos)?;
+
+        // The number of iterations is multiplied by the body cost, then the overhead is added
+        let (iterations_len, temp_local) = match condition {
+            LoopCondition::Ne => (6, 0),
+            LoopCondition::LtU | LoopCondition::LtS => match self.operators.get(at + 7)? {
+                LocalTee { local_index } => (13, *local_index),
+                _ => return None,
+            },
+        };
+        let body_cost = match self.operators.get(at + iterations_len + 2)? {
+            I64Const { value } => *value as u64,
+            _ => return None,
+        };
+        let overhead = match self
+            .operators
+            .get(at + iterations_len + 4..at + iterations_len + 6)
+        {
+            Some([I64Const { value }, I64Add]) => *value as u64,
+            _ => 0,
+        };
+        let hoisted_loop = HoistedLoop {
+            pos,
+            body_cost,
+            overhead,
+            counter,
+            bound,
+            condition,
+        };
+
+        let expected = hoisted_loop.charge(temp_local);
+        let encoded = self.encoded.get(at..at + expected.len())?;
+        if expected
+            .iter()
+            .zip(encoded)
+            .any(|(instruction, actual)| encode_instruction(instruction) != *actual)
+        {
+            return None;
+        }
+        let len = expected.len() + self.stack_top_charge(at + expected.len())?;
+        Some((len, hoisted_loop))
+    }
+
+    /// Matches a refund, returning the amount refunded.
+    fn refund(&self, at: usize) -> Option<(usize, u64)> {
+        use wasmparser::Operator::*;
+
+        let gas_global = self.code.remap.gas_global?;
+        match self.operators.get(at..at + 4)? {
+            [GlobalGet { global_index: get }, I64Const { value }, I64Add, GlobalSet { global_index: set }]
+                if *get == gas_global && *set == gas_global =>
+            {
+                Some((4, *value as u64))
+            }
+            _ => None,
+        }
+    }
+
+    /// Matches an `else` arm added for a refund.
+    fn else_refund(&self, at: usize) -> Option<(usize, u64)> {
+        match self.operators.get(at)? {
+            Operator::Else => self.refund(at + 1).map(|(len, amount)| (1 + len, amount)),
+            _ => None,
+        }
+    }
+
+    /// Matches a refund made only if the following `br_if` branches.
+    fn branch_refund(&self, at: usize) -> Option<(usize, u64)> {
+        use wasmparser::Operator::*;
+
+        let tee = match self.operators.get(at..at + 2)? {
+            [LocalTee { local_index }, If {
+                blockty: wasmparser::BlockType::Empty,
+            }] => *local_index,
+            _ => return None,
+        };
+        let (len, amount) = self.refund(at + 2)?;
+        match self.operators.get(at + 2 + len..at + 4 + len)? {
+            [End, LocalGet { local_index }] if *local_index == tee => Some((4 + len, amount)),
+            _ => None,
+        }
+    }
+}
 
 /// An ID for a node in a ControlFlowGraph.
 type NodeId = usize;
@@ -21,7 +766,7 @@ type NodeId = usize;
 /// operations that are always executed sequentially.
 #[derive(Debug, Default)]
 struct ControlFlowNode {
-    /// The index of the first instruction in the basic block. This is only used for debugging.
+    /// The index of the first instruction in the basic block, if it has any.
     first_instr_pos: Option<usize>,
 
     /// The actual gas cost of executing all instructions in the basic block.
@@ -30,6 +775,9 @@ struct ControlFlowNode {
     /// The amount of gas charged by the injected metering instructions within this basic block.
     charged_cost: u64,
 
+    /// The amount of gas charged up front which is refunded at the start of this basic block.
+    refunded_cost: u64,
+
     /// Whether there are any other nodes in the graph that loop back to this one. Every cycle in
     /// the control flow graph contains at least one node with this flag set.
     is_loop_target: bool,
@@ -47,7 +795,7 @@ struct ControlFlowNode {
 /// The subgraph with only the forward edges forms a directed acyclic graph (DAG); including the
 /// loop-back edges introduces cycles.
 #[derive(Debug)]
-pub struct ControlFlowGraph {
+struct ControlFlowGraph {
     nodes: Vec<ControlFlowNode>,
 }
 
@@ -70,17 +818,49 @@ impl ControlFlowGraph {
     }
 
     fn increment_actual_cost(&mut self, node_id: NodeId, cost: u64) {
-        self.get_node_mut(node_id).actual_cost += cost;
+        let node = self.get_node_mut(node_id);
+        node.actual_cost = node.actual_cost.saturating_add(cost);
     }
 
     fn increment_charged_cost(&mut self, node_id: NodeId, cost: u64) {
-        self.get_node_mut(node_id).charged_cost += cost;
+        let node = self.get_node_mut(node_id);
+        node.charged_cost = node.charged_cost.saturating_add(cost);
+    }
+
+    /// Adds a node refunding `amount` after `from_id` if it is not zero, returning the node to
+    /// continue from.
+    fn add_refund_node(&mut self, from_id: NodeId, amount: u64) -> NodeId {
+        if amount == 0 {
+            return from_id;
+        }
+        let node_id = self.add_node();
+        self.get_node_mut(node_id).refunded_cost = amount;
+        self.new_forward_edge(from_id, node_id);
+        node_id
     }
 
     fn set_first_instr_pos(&mut self, node_id: NodeId, first_instr_pos: usize) {
         self.get_node_mut(node_id).first_instr_pos = Some(first_instr_pos)
     }
 
+    /// Sums the costs of the nodes along a path.
+    fn path_cost(&self, func_index: u32, path: &[NodeId]) -> PathCost {
+        let mut path_cost = PathCost {
+            func_index,
+            path: Vec::new(),
+            charged: 0,
+            refunded: 0,
+            actual: 0,
+        };
+        for node in path.iter().map(|node_id| self.get_node(*node_id)) {
+            path_cost.path.extend(node.first_instr_pos);
+            path_cost.charged = path_cost.charged.saturating_add(node.charged_cost);
+            path_cost.refunded = path_cost.refunded.saturating_add(node.refunded_cost);
+            path_cost.actual = path_cost.actual.saturating_add(node.actual_cost);
+        }
+        path_cost
+    }
+
     fn new_edge(&mut self, from_id: NodeId, target_frame: &ControlFrame) {
         if target_frame.is_loop {
             self.new_loopback_edge(from_id, target_frame.entry_node);
@@ -106,6 +886,9 @@ struct ControlFrame {
     entry_node: NodeId,
     exit_node: NodeId,
     active_node: NodeId,
+    /// Whether this is an `if` frame without an `else` so far, in which case the condition being
+    /// false jumps to the exit node.
+    needs_else_edge: bool,
 }
 
 impl ControlFrame {
@@ -115,17 +898,24 @@ impl ControlFrame {
             entry_node: entry_node_id,
             exit_node: exit_node_id,
             active_node: entry_node_id,
+            needs_else_edge: false,
         }
     }
 }
 
-/// Construct a control flow graph from a function body and the metered blocks computed for it.
+/// Construct a control flow graph from a function body and the charges recovered from its
+/// instrumented body, adding the instructions which aren't charged dynamically as they should to
+/// `mismatches`.
 ///
 /// This assumes that the function body has been validated already, otherwise this may panic.
 fn build_control_flow_graph(
-    body: &wasmparser::FunctionBody,
-    rules: &impl Rules,
-    blocks: &[MeteredBlock],
+    operators: &[Operator],
+    rules: &impl RulesExt,
+    func_index: u32,
+    defined_index: u32,
+    types: &ModuleTypes,
+    charges: &Charges,
+    mismatches: &mut Vec<CostMismatch>,
 ) -> Result<ControlFlowGraph> {
     use wasmparser::Operator::*;
 
@@ -137,35 +927,111 @@ fn build_control_flow_graph(
     graph.set_first_instr_pos(entry_node_id, 0);
 
     let mut stack = vec![ControlFrame::new(entry_node_id, terminal_node_id, false)];
-    let mut metered_blocks_iter = blocks.iter().peekable();
-    let operators = body
-        .get_operators_reader()?
-        .into_iter()
-        .collect::<wasmparser::Result<Vec<Operator>>>()?;
+    let mut metered_blocks_iter = charges.blocks.iter().peekable();
+    let mut dynamic_charges_iter = charges.dynamic.iter().peekable();
+    let mut hoisted_loops_iter = charges.loops.iter().peekable();
+    let mut refunds_iter = charges.refunds.iter().peekable();
+    let mut tracker = ContextTracker::default();
     for (cursor, instruction) in operators.iter().enumerate() {
         let active_node_id = stack
             .last()
             .expect("module is valid by pre-condition; control stack must not be empty; qed")
             .active_node;
 
-        // Increment the charged cost if there are metering instructions to be inserted here.
-        let apply_block = metered_blocks_iter
-            .peek()
-            .map_or(false, |block| block.start_pos == cursor);
-        if apply_block {
-            let next_metered_block = metered_blocks_iter
-                .next()
-                .expect("peek returned an item; qed");
-            graph.increment_charged_cost(active_node_id, next_metered_block.cost);
-        }
-
-        let instruction_cost = match rules.instruction_cost(instruction) {
-            Ok(InstructionCost::Fixed(c)) => c,
-            _ => Err(anyhow!(
-                "gas rule for instruction {:?} not found or not supported",
-                &instruction
-            ))?,
+        // Increment the charged cost if there are metering instructions to be inserted here, and
+        // the actual cost by the cost of the charge.
+        while let Some(block) = metered_blocks_iter.next_if(|block| block.start_pos == cursor) {
+            graph.increment_charged_cost(active_node_id, block.cost);
+            graph.increment_actual_cost(active_node_id, rules.gas_charge_cost());
+        }
+
+        // Refunds made when branching or in a missing `else` arm are applied to the edges below.
+        let mut else_refund = 0;
+        let mut branch_refund = 0;
+        while let Some(refund) = refunds_iter.next_if(|refund| refund.pos == cursor) {
+            match refund.kind {
+                RefundKind::Before => {
+                    let node = graph.get_node_mut(active_node_id);
+                    node.refunded_cost = node.refunded_cost.saturating_add(refund.amount);
+                }
+                RefundKind::Else => else_refund = refund.amount,
+                RefundKind::Branch => branch_refund = refund.amount,
+            }
+        }
+
+        // The overhead of a hoisted loop is charged here, the cost of its iterations at the start
+        // of its body below.
+        let hoisted_loop = hoisted_loops_iter.next_if(|hoisted_loop| hoisted_loop.pos == cursor);
+        if let Some(hoisted_loop) = hoisted_loop {
+            graph.increment_charged_cost(active_node_id, hoisted_loop.overhead);
+            graph.increment_actual_cost(
+                active_node_id,
+                rules
+                    .gas_charge_cost()
+                    .saturating_add(rules.linear_calc_cost()),
+            );
+        }
+
+        let ctx = tracker.context(defined_index, cursor);
+        tracker.update(instruction);
+        let cost = rules
+            .instruction_cost_in(&ctx, instruction)
+            .map_err(|reason| InstrumentError::ForbiddenInstruction {
+                func_index,
+                op_index: cursor,
+                op: format!("{:?}", instruction),
+                reason,
+            })?;
+
+        // The linear part of the cost is charged dynamically along with the cost of the charge, or
+        // statically if the operand is a constant.
+        // The cost of the operand and its depth
+        let charged = dynamic_charges_iter
+            .next_if(|metered_instr| metered_instr.pos == cursor)
+            .map(|metered_instr| {
+                (
+                    metered_instr.cost.clone(),
+                    metered_instr.spilled.len() as u32,
+                )
+            });
+        let (base, operand_cost) = OperandCost::split(cost)?;
+        let base = base.saturating_add(types.import_call_cost(rules, instruction));
+        // Only the stack top can be charged statically, unless the total memory is priced
+        let operand = match (
+            &operand_cost,
+            cursor.checked_sub(1).map(|prev| &operators[prev]),
+        ) {
+            (Some((cost, 0)), Some(I32Const { value })) if *value >= 0 && !cost.total_memory() => {
+                Some(*value as u64)
+            }
+            _ => None,
         };
+        let operand_charge = match (&operand_cost, &charged, operand) {
+            (Some(_), Some(_), _) => rules
+                .gas_charge_cost()
+                .saturating_add(rules.linear_calc_cost()),
+            (Some((cost, _)), None, Some(operand)) => cost.static_cost(operand).unwrap_or(u64::MAX),
+            _ => 0,
+        };
+        let instruction_cost = base.saturating_add(operand_charge);
+        let charged_correctly = match (&operand_cost, &charged) {
+            (Some(_), None) => operand.is_some(),
+            (operand_cost, charged) => operand_cost == charged,
+        };
+        if !charged_correctly {
+            let unit_cost = |cost: &Option<(OperandCost, u32)>| match cost {
+                Some((OperandCost::Linear(unit_cost), _)) => Some(*unit_cost),
+                _ => None,
+            };
+            mismatches.push(CostMismatch::DynamicCharge {
+                func_index,
+                pos: cursor,
+                unit_cost: unit_cost(&operand_cost),
+                charged_unit_cost: unit_cost(&charged),
+                operand_depth: operand_cost.as_ref().map(|(_, depth)| *depth),
+                charged_operand_depth: charged.as_ref().map(|(_, depth)| *depth),
+            });
+        }
 
         match instruction {
             Block { blockty: _ } => {
@@ -180,7 +1046,9 @@ fn build_control_flow_graph(
                 let then_node_id = graph.add_node();
                 let exit_node_id = graph.add_node();
 
-                stack.push(ControlFrame::new(then_node_id, exit_node_id, false));
+                let mut frame = ControlFrame::new(then_node_id, exit_node_id, false);
+                frame.needs_else_edge = true;
+                stack.push(frame);
                 graph.new_forward_edge(active_node_id, then_node_id);
                 graph.set_first_instr_pos(then_node_id, cursor + 1);
             }
@@ -193,6 +1061,10 @@ fn build_control_flow_graph(
                 stack.push(ControlFrame::new(loop_node_id, exit_node_id, true));
                 graph.new_forward_edge(active_node_id, loop_node_id);
                 graph.set_first_instr_pos(loop_node_id, cursor + 1);
+
+                if let Some(hoisted_loop) = hoisted_loop {
+                    graph.increment_charged_cost(loop_node_id, hoisted_loop.body_cost);
+                }
             }
             Else => {
                 let active_frame_idx = stack.len() - 1;
@@ -200,6 +1072,8 @@ fn build_control_flow_graph(
 
                 let else_node_id = graph.add_node();
                 stack[active_frame_idx].active_node = else_node_id;
+                stack[active_frame_idx].needs_else_edge = false;
+                graph.new_forward_edge(active_node_id, stack[active_frame_idx].exit_node);
 
                 let prev_node_id = stack[prev_frame_idx].active_node;
                 graph.new_forward_edge(prev_node_id, else_node_id);
@@ -210,10 +1084,24 @@ fn build_control_flow_graph(
                     .expect("module is valid by pre-condition; ends correspond to control stack frames; qed");
 
                 graph.new_forward_edge(active_node_id, closing_frame.exit_node);
-                graph.set_first_instr_pos(closing_frame.exit_node, cursor + 1);
 
+                if closing_frame.needs_else_edge {
+                    let prev_node_id = stack
+                        .last()
+                        .expect("if frames are never the function frame; qed")
+                        .active_node;
+                    let else_node_id = graph.add_refund_node(prev_node_id, else_refund);
+                    graph.new_forward_edge(else_node_id, closing_frame.exit_node);
+                } else if else_refund > 0 {
+                    return Err(anyhow!(
+                        "refund in an else arm added to a block which isn't an if"
+                    ));
+                }
+
+                // The exit node of the function has no instructions
                 if let Some(active_frame) = stack.last_mut() {
                     active_frame.active_node = closing_frame.exit_node;
+                    graph.set_first_instr_pos(closing_frame.exit_node, cursor + 1);
                 }
             }
             Br {
@@ -237,7 +1125,8 @@ fn build_control_flow_graph(
 
                 let active_frame_idx = stack.len() - 1;
                 let target_frame_idx = active_frame_idx - (*label as usize);
-                graph.new_edge(active_node_id, &stack[target_frame_idx]);
+                let branch_node_id = graph.add_refund_node(active_node_id, branch_refund);
+                graph.new_edge(branch_node_id, &stack[target_frame_idx]);
 
                 let new_node_id = graph.add_node();
                 stack[active_frame_idx].active_node = new_node_id;
@@ -282,113 +1171,334 @@ fn build_control_flow_graph(
     Ok(graph)
 }
 
-/// Exhaustively search through all paths in the control flow graph, starting from the first node
-/// and ensure that 1) all paths with only forward edges ending with the terminal node have an
-/// equal total actual gas cost and total charged gas cost, and 2) all cycles beginning with a loop
-/// entry point and ending with a node with a loop-back edge to the entry point have equal actual
-/// and charged gas costs. If this returns true, then the metered blocks used to construct the
-/// control flow graph are correct with respect to the function body.
-///
-/// In the worst case, this runs in time exponential in the size of the graph.
-fn validate_graph_gas_costs(graph: &ControlFlowGraph) -> bool {
-    fn visit(
-        graph: &ControlFlowGraph,
-        node_id: NodeId,
-        mut total_actual: u64,
-        mut total_charged: u64,
-        loop_costs: &mut Map<NodeId, (u64, u64)>,
-    ) -> bool {
-        let node = graph.get_node(node_id);
-
-        total_actual += node.actual_cost;
-        total_charged += node.charged_cost;
+/// Checks all paths in the control flow graph and ensures that 1) all paths with only forward edges
+/// from the first node to the terminal node have an equal total actual gas cost and total charged
+/// gas cost, 2) all paths with only forward edges beginning with a loop entry point and ending with
+/// a node with a loop-back edge to the entry point have equal actual and charged gas costs, and 3)
+/// the total charged gas cost is never lower than the total actual gas cost at any node of these
+/// paths, as charges happen at the start of the nodes. Every path through the function body is made
+/// of such paths, so if this returns no mismatches, then the charges used to construct the control
+/// flow graph are correct with respect to the function body.
+fn validate_graph_gas_costs(graph: &ControlFlowGraph, func_index: u32) -> Vec<CostMismatch> {
+    let loop_entries = graph
+        .nodes
+        .iter()
+        .enumerate()
+        .filter(|(_, node)| node.is_loop_target)
+        .map(|(node_id, _)| (node_id, Some(node_id)));
+    [(0, None)]
+        .into_iter()
+        .chain(loop_entries)
+        .filter_map(|(start_id, loop_entry)| {
+            validate_paths(graph, start_id, loop_entry, func_index)
+        })
+        .collect()
+}
 
-        if node.is_loop_target {
-            loop_costs.insert(node_id, (node.actual_cost, node.charged_cost));
+/// Checks the paths with only forward edges starting at `start_id`, ending with a loop-back edge to
+/// `loop_entry` if set, in which case only the nodes within the loop are checked. Rather than
+/// enumerating the paths, this computes the range of the charged minus the actual gas cost over all
+/// paths to every node, in topological order. Returns the first path found whose cost isn't
+/// covered or doesn't match the charges.
+fn validate_paths(
+    graph: &ControlFlowGraph,
+    start_id: NodeId,
+    loop_entry: Option<NodeId>,
+    func_index: u32,
+) -> Option<CostMismatch> {
+    // Depth-first search for the postorder of the nodes reachable through forward edges
+    let mut postorder = Vec::new();
+    let mut visited = vec![false; graph.nodes.len()];
+    let mut stack = vec![(start_id, 0)];
+    visited[start_id] = true;
+    while let Some((node_id, next_edge)) = stack.pop() {
+        match graph.get_node(node_id).forward_edges.get(next_edge) {
+            Some(&next_id) => {
+                stack.push((node_id, next_edge + 1));
+                if !visited[next_id] {
+                    visited[next_id] = true;
+                    stack.push((next_id, 0));
+                }
+            }
+            None => postorder.push(node_id),
         }
+    }
 
-        if node.forward_edges.is_empty() && total_actual != total_charged {
-            return false;
-        }
+    let is_path_end = |node: &ControlFlowNode| match loop_entry {
+        Some(loop_entry) => node.loopback_edges.contains(&loop_entry),
+        None => node.forward_edges.is_empty() && node.loopback_edges.is_empty(),
+    };
 
-        for loop_node_id in node.loopback_edges.iter() {
-            let (loop_actual, loop_charged) = loop_costs
-                .get_mut(loop_node_id)
-                .expect("cannot arrive at loopback edge without visiting loop entry node");
-            if loop_actual != loop_charged {
-                return false;
-            }
+    // The nodes leading back to the loop entry, the code after the loop is checked with the paths
+    // through the enclosing loop or function
+    let mut in_loop = vec![loop_entry.is_none(); graph.nodes.len()];
+    if loop_entry.is_some() {
+        for &node_id in postorder.iter() {
+            let node = graph.get_node(node_id);
+            in_loop[node_id] =
+                is_path_end(node) || node.forward_edges.iter().any(|next_id| in_loop[*next_id]);
         }
+    }
 
-        for next_node_id in node.forward_edges.iter() {
-            if !visit(
-                graph,
-                *next_node_id,
-                total_actual,
-                total_charged,
-                loop_costs,
-            ) {
-                return false;
+    // The range of the charged minus the actual cost before each node, and the predecessors on the
+    // paths reaching its minimum and maximum
+    let mut ranges: Vec<Option<(i128, i128)>> = vec![None; graph.nodes.len()];
+    let mut predecessors = vec![(start_id, start_id); graph.nodes.len()];
+    ranges[start_id] = Some((0, 0));
+    for &node_id in postorder.iter().rev() {
+        let node = graph.get_node(node_id);
+        let (min, max) = ranges[node_id].expect("predecessors come first in topological order");
+        let diff = i128::from(node.charged_cost)
+            - i128::from(node.refunded_cost)
+            - i128::from(node.actual_cost);
+        let (min, max) = (min + diff, max + diff);
+
+        let undercharged = in_loop[node_id] && min < 0;
+        if undercharged || (is_path_end(node) && (min, max) != (0, 0)) {
+            // Walk back the path to the bound which is off
+            let follow_min = undercharged || min != 0;
+            let mut path = vec![node_id];
+            while let Some(&last_id) = path.last().filter(|last_id| **last_id != start_id) {
+                let (min_id, max_id) = predecessors[last_id];
+                path.push(if follow_min { min_id } else { max_id });
             }
-        }
+            path.reverse();
 
-        if node.is_loop_target {
-            loop_costs.remove(&node_id);
+            let path_cost = graph.path_cost(func_index, &path);
+            return Some(if undercharged {
+                CostMismatch::Undercharged(path_cost)
+            } else {
+                CostMismatch::Unbalanced(path_cost)
+            });
         }
 
-        true
+        for next_id in node.forward_edges.iter() {
+            let (next_min, next_max) = ranges[*next_id].unwrap_or((i128::MAX, i128::MIN));
+            if min < next_min {
+                predecessors[*next_id].0 = node_id;
+            }
+            if max > next_max {
+                predecessors[*next_id].1 = node_id;
+            }
+            ranges[*next_id] = Some((next_min.min(min), next_max.max(max)));
+        }
     }
 
-    // Recursively explore all paths through the execution graph starting from the entry node.
-    visit(graph, 0, 0, 0, &mut Map::new())
-}
-
-/// Validate that the metered blocks are correct with respect to the function body by exhaustively
-/// searching all paths through the control flow graph.
-///
-/// This assumes that the function body has been validated already, otherwise this may panic.
-fn validate_metering_injections(
-    body: &wasmparser::FunctionBody,
-    rules: &impl Rules,
-    blocks: &[MeteredBlock],
-) -> Result<bool> {
-    let graph = build_control_flow_graph(body, rules, blocks)?;
-    Ok(validate_graph_gas_costs(&graph))
+    None
 }
 
+#[cfg(test)]
 mod tests {
-    use super::{super::determine_metered_blocks, *};
+    use super::*;
+    use crate::gas_metering::{
+        inject_with_options, ChargeMode, ConstantCostRules, CostTable, CounterType, GasCounter,
+        InjectOptions, Inlining, InstructionCost, OutOfGas,
+    };
     use binaryen::tools::translate_to_fuzz_mvp;
+    use core::num::NonZeroU32;
     use rand::{thread_rng, RngCore};
-    use wasmparser::{CodeSectionReader, FunctionBody, Payload::CodeSectionStart};
+
+    fn fuzz_rules() -> CostTable {
+        CostTable {
+            default: Some(InstructionCost::Fixed(1)),
+            opcodes: [(
+                "memory_grow".into(),
+                InstructionCost::Linear(2, NonZeroU32::new(10).unwrap()),
+            )]
+            .into_iter()
+            .collect(),
+            gas_charge_cost: 3,
+            linear_calc_cost: 2,
+            ..CostTable::default()
+        }
+    }
+
+    fn fuzz_options() -> Vec<InjectOptions> {
+        let import = || {
+            InjectOptions::new(GasCounter::Import {
+                module: "env".into(),
+            })
+        };
+
+        let mut merged = import();
+        merged.merge_blocks = true;
+        merged.hoist_loop_charges = true;
+
+        let mut upfront = import();
+        upfront.merge_blocks = true;
+        upfront.charge_upfront = true;
+        upfront.inlining = Inlining::Always;
+
+        let mut inlined = InjectOptions::new(GasCounter::Export {
+            name: "verified_gas_counter".into(),
+        });
+        inlined.inlining = Inlining::Heuristic { max_block_len: 4 };
+        inlined.hoist_loop_charges = true;
+        inlined.charge_mode = ChargeMode::ZeroOnFailure;
+        inlined.counter_type = CounterType::Unsigned;
+        inlined.out_of_gas = OutOfGas::ExportStatus {
+            name: "verified_out_of_gas".into(),
+        };
+
+        let mut host = InjectOptions::new(GasCounter::HostFunction {
+            module: "env".into(),
+            name: "gas".into(),
+        });
+        host.hoist_loop_charges = true;
+        host.merge_blocks = true;
+
+        vec![import(), merged, upfront, inlined, host]
+    }
 
     #[test]
     fn test_build_control_flow_graph() {
+        let rules = fuzz_rules();
         for _ in 0..20 {
             let mut rand_input = [0u8; 2048];
             thread_rng().fill_bytes(&mut rand_input);
 
             let module_bytes = translate_to_fuzz_mvp(&rand_input).write();
-            let payload = wasmparser::Parser::new(0)
-                .parse_all(&module_bytes)
-                .map(|v| v.unwrap())
-                .find(|payload| matches!(payload, CodeSectionStart { .. }))
-                .unwrap();
-
-            if let CodeSectionStart { range, .. } = payload {
-                let reader = CodeSectionReader::new(&module_bytes[range], 0).unwrap();
-                let bodies = reader
-                    .into_iter()
-                    .collect::<wasmparser::Result<Vec<FunctionBody>>>()
-                    .unwrap();
-                for func_body in bodies {
-                    let rules = ConstantCostRules::default();
-                    let (metered_blocks, _) = determine_metered_blocks(&func_body, &rules).unwrap();
-                    let success =
-                        validate_metering_injections(&func_body, &rules, &metered_blocks).unwrap();
-                    assert!(success);
-                }
+            for options in fuzz_options() {
+                let instrumented = match inject_with_options(&module_bytes, &rules, &options) {
+                    Ok((instrumented, _)) => instrumented,
+                    // Linearly priced instructions can't have negative constant operands
+                    Err(_) => continue,
+                };
+                assert_eq!(
+                    verify(&module_bytes, &instrumented, &rules).unwrap(),
+                    vec![],
+                    "{:?}",
+                    options
+                );
             }
         }
     }
+
+    #[test]
+    fn verify_reports_wrong_charges() {
+        let rules = ConstantCostRules::default();
+        let original = wat::parse_str("(module (func (param i32) local.get 0 drop))").unwrap();
+        let instrumented = |charge: i64| {
+            wat::parse_str(format!(
+                r#"(module
+                    (import "env" "gas" (func (param i64)))
+                    (func (param i32) i64.const {} call 0 local.get 0 drop))"#,
+                charge
+            ))
+            .unwrap()
+        };
+        let path_cost = |charged| PathCost {
+            func_index: 1,
+            path: vec![0],
+            charged,
+            refunded: 0,
+            actual: 2,
+        };
+
+        assert_eq!(verify(&original, &instrumented(2), &rules).unwrap(), vec![]);
+        assert_eq!(
+            verify(&original, &instrumented(1), &rules).unwrap(),
+            vec![CostMismatch::Undercharged(path_cost(1))]
+        );
+        assert_eq!(
+            verify(&original, &instrumented(3), &rules).unwrap(),
+            vec![CostMismatch::Unbalanced(path_cost(3))]
+        );
+
+        // A negative charge adds gas instead
+        assert!(verify(&original, &instrumented(-1), &rules).is_err());
+
+        // The instrumented code must match the original code
+        let other = wat::parse_str("(module (func (param i32) local.get 0 i32.eqz drop))").unwrap();
+        assert!(verify(&other, &instrumented(2), &rules).is_err());
+    }
+
+    #[test]
+    fn verify_checks_shifted_indices() {
+        let rules = ConstantCostRules::default();
+
+        // The gas function is imported after the functions imported by the module
+        let original =
+            wat::parse_str(r#"(module (import "env" "f" (func)) (func call 0 call 1))"#).unwrap();
+        let instrumented = |callee: u32| {
+            wat::parse_str(format!(
+                r#"(module
+                    (import "env" "f" (func))
+                    (import "env" "gas" (func (param i64)))
+                    (func i64.const 2 call 1 call 0 call {}))"#,
+                callee
+            ))
+            .unwrap()
+        };
+        assert_eq!(verify(&original, &instrumented(2), &rules).unwrap(), vec![]);
+        assert!(verify(&original, &instrumented(1), &rules).is_err());
+
+        // The gas global is imported after the globals imported by the module
+        let original = wat::parse_str(
+            r#"(module
+                (import "env" "g" (global i32))
+                (global i32 (i32.const 1))
+                (func (result i32) global.get 1))"#,
+        )
+        .unwrap();
+        let instrumented = |global: u32| {
+            wat::parse_str(format!(
+                r#"(module
+                    (import "env" "g" (global i32))
+                    (import "env" "gas" (global (mut i64)))
+                    (global i32 (i32.const 1))
+                    (func (result i32) i64.const 1 call 1 global.get {})
+                    (func (param i64) global.get 1 local.get 0 i64.sub global.set 1))"#,
+                global
+            ))
+            .unwrap()
+        };
+        assert_eq!(verify(&original, &instrumented(2), &rules).unwrap(), vec![]);
+        assert!(verify(&original, &instrumented(1), &rules).is_err());
+    }
+
+    #[test]
+    fn verify_reports_wrong_dynamic_charges() {
+        let original = wat::parse_str(
+            "(module (memory 1) (func (param i32) (result i32) local.get 0 memory.grow))",
+        )
+        .unwrap();
+        let options = InjectOptions::new(GasCounter::HostFunction {
+            module: "env".into(),
+            name: "gas".into(),
+        });
+        let (instrumented, _) =
+            inject_with_options(&original, &ConstantCostRules::new(1, 10), &options).unwrap();
+
+        assert_eq!(
+            verify(&original, &instrumented, &ConstantCostRules::new(1, 10)).unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            verify(&original, &instrumented, &ConstantCostRules::new(1, 20)).unwrap(),
+            vec![CostMismatch::DynamicCharge {
+                func_index: 1,
+                pos: 1,
+                unit_cost: Some(20),
+                charged_unit_cost: Some(10),
+                operand_depth: Some(0),
+                charged_operand_depth: Some(0),
+            }]
+        );
+
+        // `memory.grow` isn't charged for its operand
+        let (instrumented, _) =
+            inject_with_options(&original, &ConstantCostRules::default(), &options).unwrap();
+        assert_eq!(
+            verify(&original, &instrumented, &ConstantCostRules::new(1, 10)).unwrap(),
+            vec![CostMismatch::DynamicCharge {
+                func_index: 1,
+                pos: 1,
+                unit_cost: Some(10),
+                charged_unit_cost: None,
+                operand_depth: Some(0),
+                charged_operand_depth: None,
+            }]
+        );
+    }
 }
@@ -3,6 +3,9 @@
 extern crate alloc;
 extern crate core;
 
+mod error;
 pub mod gas_metering;
 pub mod stack_limiter;
 mod utils;
+
+pub use error::InstrumentError;
@@ -0,0 +1,128 @@
+use wasmparser::ValType;
+
+// The cost in stack items that should be charged per call of a function. This is
+// is a static cost that is added to each function call. This makes sense because even
+// if a function does not use any parameters or locals some stack space on the host
+// machine might be consumed to hold some context.
+pub(super) const ACTIVATION_FRAME_COST: u32 = 2;
+
+/// How the locals of a function are counted in its stack cost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalsCount {
+    /// Count every parameter and every local variable.
+    Individual,
+    /// Count the local declaration groups of the function body, ignoring parameters, so that
+    /// `(local i64 i64 i64)` counts as one. This is the formula used up to version 0.4. If the
+    /// stack costs must not change for already deployed code, keep it together with
+    /// [`ValueStack::Legacy`] in a [`DefaultStackCostModel`] passed to
+    /// [`inject_with_cost_model`](super::inject_with_cost_model).
+    DeclarationGroups,
+}
+
+impl Default for LocalsCount {
+    fn default() -> Self {
+        LocalsCount::Individual
+    }
+}
+
+/// How the height of the value stack of a function is computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueStack {
+    /// Track the type of every value, so that each one costs
+    /// [`value_cost`](StackCostModel::value_cost) of its type, and support multi-value blocks.
+    Typed,
+    /// Compute the height exactly as up to version 0.4, keep it together with
+    /// [`LocalsCount::DeclarationGroups`] if the stack costs must not change for already deployed
+    /// code. Only the values are counted: each one costs one whatever its type and
+    /// [`call_cost`](StackCostModel::call_cost) isn't charged. Multi-value blocks aren't
+    /// supported either, the `else` branch of an `if` starts with the values left by the `then`
+    /// branch, and a block with a type index is assumed to produce one value.
+    Legacy,
+}
+
+impl Default for ValueStack {
+    fn default() -> Self {
+        ValueStack::Typed
+    }
+}
+
+/// Weighs what a function consumes on the native stack of the engine executing it.
+///
+/// The stack cost of a function is the sum of [`frame_overhead`](Self::frame_overhead), the cost
+/// of its locals and the maximal weighted height of its value stack, where every value on the
+/// stack costs [`value_cost`](Self::value_cost) of its type.
+pub trait StackCostModel {
+    /// Returns the cost of a single value of the given type, either on the value stack or held
+    /// in a local variable.
+    fn value_cost(&self, ty: ValType) -> u32;
+
+    /// Returns the fixed cost of a call frame, charged once per call of every function.
+    fn frame_overhead(&self) -> u32;
+
+    /// Returns the cost of the parameters and the local variables of a function. `locals` are the
+    /// local declarations of the function body as `(count, type)` pairs.
+    ///
+    /// Returns `None` on overflow. By default every parameter and local costs its `value_cost`.
+    fn locals_cost(&self, params: &[ValType], locals: &[(u32, ValType)]) -> Option<u32> {
+        weighted_locals_cost(self, params, locals)
+    }
+
+    /// Returns the extra cost of a call site of a function with the given signature, on top of the
+    /// value stack of the caller which already holds the arguments. This is useful for engines
+    /// that copy the arguments or reserve space for the results when setting up a call.
+    ///
+    /// The default is zero.
+    fn call_cost(&self, _params: &[ValType], _results: &[ValType]) -> u32 {
+        0
+    }
+
+    /// Returns how the height of the value stack is computed.
+    ///
+    /// The default is [`ValueStack::Typed`].
+    fn value_stack(&self) -> ValueStack {
+        ValueStack::Typed
+    }
+}
+
+/// The cost model used by [`inject`](super::inject): every value costs one, each frame costs two,
+/// the locals are counted as specified by `locals_count` and the height of the value stack is
+/// computed as specified by `value_stack`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DefaultStackCostModel {
+    pub locals_count: LocalsCount,
+    pub value_stack: ValueStack,
+}
+
+impl StackCostModel for DefaultStackCostModel {
+    fn value_cost(&self, _ty: ValType) -> u32 {
+        1
+    }
+
+    fn frame_overhead(&self) -> u32 {
+        ACTIVATION_FRAME_COST
+    }
+
+    fn locals_cost(&self, params: &[ValType], locals: &[(u32, ValType)]) -> Option<u32> {
+        match self.locals_count {
+            LocalsCount::Individual => weighted_locals_cost(self, params, locals),
+            LocalsCount::DeclarationGroups => u32::try_from(locals.len()).ok(),
+        }
+    }
+
+    fn value_stack(&self) -> ValueStack {
+        self.value_stack
+    }
+}
+
+fn weighted_locals_cost<M: StackCostModel + ?Sized>(
+    model: &M,
+    params: &[ValType],
+    locals: &[(u32, ValType)],
+) -> Option<u32> {
+    let params_cost = params
+        .iter()
+        .try_fold(0u32, |acc, ty| acc.checked_add(model.value_cost(*ty)))?;
+    locals.iter().try_fold(params_cost, |acc, (count, ty)| {
+        acc.checked_add(count.checked_mul(model.value_cost(*ty))?)
+    })
+}
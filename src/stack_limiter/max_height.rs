@@ -1,15 +1,12 @@
-use alloc::vec::Vec;
+use alloc::{vec, vec::Vec};
 
-use crate::utils::ModuleInfo;
+use super::{StackCostModel, ValueStack};
+use crate::{utils::ModuleInfo, InstrumentError};
 use anyhow::{anyhow, Result};
 use wasm_encoder::SectionId;
-use wasmparser::{BlockType, CodeSectionReader, Type};
+use wasmparser::{BlockType, CodeSectionReader, Operator, Type, ValType};
 
-// The cost in stack items that should be charged per call of a function. This is
-// is a static cost that is added to each function call. This makes sense because even
-// if a function does not use any parameters or locals some stack space on the host
-// machine might be consumed to hold some context.
-const ACTIVATION_FRAME_COST: u32 = 2;
+mod legacy;
 
 /// Control stack frame.
 #[derive(Debug)]
@@ -18,32 +15,44 @@ struct Frame {
     /// never passes control further was executed.
     is_polymorphic: bool,
 
-    /// Count of values which will be pushed after the exit
+    /// Types of the parameters of the block, which are available on
+    /// the stack upon the entry to the block.
+    start_types: Vec<ValType>,
+
+    /// Types of the values which will be pushed after the exit
     /// from the current block.
-    end_arity: u32,
+    end_types: Vec<ValType>,
 
     /// Count of values which should be poped upon a branch to
     /// this frame.
     ///
-    /// This might be diffirent from `end_arity` since branch
-    /// to the loop header can't take any values.
+    /// This might be diffirent from the count of `end_types` since branch
+    /// to the loop header takes the parameters of the loop instead.
     branch_arity: u32,
 
-    /// Stack height before entering in the block.
-    start_height: u32,
+    /// Count of values on the value stack before entering in the block,
+    /// not including its parameters.
+    start_len: usize,
 }
 
 /// This is a compound stack that abstracts tracking height of the value stack
 /// and manipulation of the control stack.
-struct Stack {
+///
+/// The height of the value stack is weighted by the cost model, i.e. each value
+/// contributes the cost of its type.
+struct Stack<'a, M: ?Sized> {
+    model: &'a M,
+    values: Vec<ValType>,
     height: u32,
     control_stack: Vec<Frame>,
 }
 
-impl Stack {
-    fn new() -> Stack {
+impl<'a, M: StackCostModel + ?Sized> Stack<'a, M> {
+    fn new(model: &'a M) -> Self {
         Stack {
-            height: ACTIVATION_FRAME_COST,
+            model,
+            values: Vec::new(),
+            height: model.frame_overhead(),
             control_stack: Vec::new(),
         }
     }
@@ -53,6 +62,14 @@ impl Stack {
         self.height
     }
 
+    /// Returns the type of the value at the top of the value stack.
+    ///
+    /// The stack can only be empty in unreachable code, where the type doesn't
+    /// matter, so `i32` is returned in this case.
+    fn top_type(&self) -> ValType {
+        self.values.last().copied().unwrap_or(ValType::I32)
+    }
+
     /// Returns a reference to a frame by specified depth relative to the top of
     /// control stack.
     fn frame(&self, rel_depth: u32) -> Result<&Frame> {
@@ -92,55 +109,68 @@ impl Stack {
             .ok_or_else(|| anyhow!("stack must be non-empty"))
     }
 
-    /// Truncate the height of value stack to the specified height.
-    fn trunc(&mut self, new_height: u32) {
-        self.height = new_height;
+    /// Truncate the value stack to the specified count of values.
+    fn trunc(&mut self, new_len: usize) {
+        while self.values.len() > new_len {
+            if let Some(ty) = self.values.pop() {
+                self.height -= self.model.value_cost(ty);
+            }
+        }
     }
 
-    /// Push specified number of values into the value stack.
+    /// Push a value of the specified type into the value stack.
     ///
-    /// Returns `Err` if the height overflow usize value.
-    fn push_values(&mut self, value_count: u32) -> Result<()> {
+    /// Returns `Err` if the height overflow u32 value.
+    fn push_value(&mut self, ty: ValType) -> Result<()> {
         self.height = self
             .height
-            .checked_add(value_count)
+            .checked_add(self.model.value_cost(ty))
             .ok_or_else(|| anyhow!("stack overflow"))?;
+        self.values.push(ty);
         Ok(())
     }
 
+    /// Push values of the specified types into the value stack.
+    fn push_values(&mut self, types: &[ValType]) -> Result<()> {
+        types.iter().try_for_each(|ty| self.push_value(*ty))
+    }
+
     /// Pop specified number of values from the value stack.
     ///
-    /// Returns `Err` if the stack happen to be negative value after
-    /// values popped.
+    /// Returns `Err` if there are less values pushed in the current frame,
+    /// unless the frame became polymorphic.
     fn pop_values(&mut self, value_count: u32) -> Result<()> {
-        if value_count == 0 {
-            return Ok(());
-        }
-        {
+        for _ in 0..value_count {
             let top_frame = self.frame(0)?;
-            if self.height == top_frame.start_height {
+            if self.values.len() == top_frame.start_len {
                 // It is an error to pop more values than was pushed in the current frame
                 // (ie pop values pushed in the parent frame), unless the frame became
                 // polymorphic.
                 return if top_frame.is_polymorphic {
                     Ok(())
                 } else {
-                    return Err(anyhow!("trying to pop more values than pushed"));
+                    Err(anyhow!("trying to pop more values than pushed"))
                 };
             }
+            let ty = self
+                .values
+                .pop()
+                .ok_or_else(|| anyhow!("stack underflow"))?;
+            self.height -= self.model.value_cost(ty);
         }
-
-        self.height = self
-            .height
-            .checked_sub(value_count)
-            .ok_or_else(|| anyhow!("stack underflow"))?;
-
         Ok(())
     }
 }
 
+/// Compute the maximal height of the value stack of the function, weighted by `model`
+/// and including the frame overhead.
+///
 /// This function expects the function to be validated. func_idx have sub import funcs num
-pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
+pub fn compute<M: StackCostModel + ?Sized>(
+    func_idx: u32,
+    module: &ModuleInfo,
+    model: &M,
+) -> Result<u32> {
     use wasmparser::Operator::*;
 
     let code_section = CodeSectionReader::new(
@@ -158,8 +188,35 @@ pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
         .into_iter()
         .nth(func_idx as usize)
         .ok_or_else(|| anyhow!("function body for the index isn't found"))??;
+    if model.value_stack() == ValueStack::Legacy {
+        return legacy::compute(func_signature, &body, module, model.frame_overhead());
+    }
+
+    // Types of the locals (including the arguments) as a list of exclusive upper bounds
+    // of the local indices together with their type.
+    let mut local_ranges: Vec<(u32, ValType)> = Vec::new();
+    let mut locals_end: u32 = 0;
+    for ty in func_signature.params() {
+        locals_end += 1;
+        local_ranges.push((locals_end, *ty));
+    }
+    for local in body.get_locals_reader()? {
+        let (count, ty) = local?;
+        locals_end = locals_end
+            .checked_add(count)
+            .ok_or_else(|| anyhow!("too many locals"))?;
+        local_ranges.push((locals_end, ty));
+    }
+    let local_type = |local_index: u32| -> Result<ValType> {
+        let idx = local_ranges.partition_point(|(end, _)| *end <= local_index);
+        local_ranges
+            .get(idx)
+            .map(|(_, ty)| *ty)
+            .ok_or_else(|| anyhow!("local index is out of bounds"))
+    };
+
     let mut body_reader = body.get_operators_reader()?;
-    let mut stack = Stack::new();
+    let mut stack = Stack::new(model);
     let mut max_height: u32 = 0;
 
     // Add implicit frame for the function. Breaks to this frame and execution of
@@ -167,9 +224,10 @@ pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
     let func_arity = func_signature.results().len() as u32;
     stack.push_frame(Frame {
         is_polymorphic: false,
-        end_arity: func_arity,
+        start_types: Vec::new(),
+        end_types: func_signature.results().to_vec(),
         branch_arity: func_arity,
-        start_height: 0,
+        start_len: 0,
     });
 
     while !body_reader.eof() {
@@ -183,31 +241,56 @@ pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
         match opcode {
             Nop => {}
             Block { blockty } | Loop { blockty } | If { blockty } => {
-                let end_arity = if blockty == BlockType::Empty { 0 } else { 1 };
+                let (start_types, end_types) = match blockty {
+                    BlockType::Empty => (vec![], vec![]),
+                    BlockType::Type(ty) => (vec![], vec![ty]),
+                    BlockType::FuncType(type_index) => {
+                        let Type::Func(ty) = module
+                            .types_map
+                            .get(type_index as usize)
+                            .ok_or_else(|| anyhow!("Type not found"))?;
+                        (ty.params().to_vec(), ty.results().to_vec())
+                    }
+                };
+                // A branch to the loop header takes the parameters of the loop,
+                // a branch to any other block takes its results.
                 let branch_arity = if let Loop { .. } = opcode {
-                    0
+                    start_types.len() as u32
                 } else {
-                    end_arity
+                    end_types.len() as u32
                 };
                 if let If { .. } = opcode {
                     stack.pop_values(1)?;
                 }
-                let height = stack.height();
+
+                // The parameters of the block are moved from the enclosing frame
+                // into the new one.
+                stack.pop_values(start_types.len() as u32)?;
+                let start_len = stack.values.len();
+                stack.push_values(&start_types)?;
                 stack.push_frame(Frame {
                     is_polymorphic: false,
-                    end_arity,
+                    start_types,
+                    end_types,
                     branch_arity,
-                    start_height: height,
+                    start_len,
                 });
             }
             Else => {
-                // The frame at the top should be pushed by `If`. So we leave
-                // it as is.
+                // The frame at the top should be pushed by `If`. The `else` branch
+                // starts again with just the parameters of the block on the stack.
+                let frame = stack.pop_frame()?;
+                stack.trunc(frame.start_len);
+                stack.push_values(&frame.start_types)?;
+                stack.push_frame(Frame {
+                    is_polymorphic: false,
+                    ..frame
+                });
             }
             End => {
                 let frame = stack.pop_frame()?;
-                stack.trunc(frame.start_height);
-                stack.push_values(frame.end_arity)?;
+                stack.trunc(frame.start_len);
+                stack.push_values(&frame.end_types)?;
             }
             Unreachable => {
                 stack.mark_unreachable()?;
@@ -222,15 +305,12 @@ pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
                 stack.mark_unreachable()?;
             }
             BrIf { relative_depth } => {
-                // Pop values for the destination block result.
-                let target_arity = stack.frame(relative_depth)?.branch_arity;
-                stack.pop_values(target_arity)?;
-
                 // Pop condition value.
                 stack.pop_values(1)?;
 
-                // Push values back.
-                stack.push_values(target_arity)?;
+                // The values for the destination block result stay on the stack
+                // when the branch is not taken, so only check that the target exists.
+                stack.frame(relative_depth)?;
             }
             BrTable { targets } => {
                 let arity_of_default = stack.frame(targets.default())?.branch_arity;
@@ -260,12 +340,20 @@ pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
             Call { function_index } => {
                 let Type::Func(ty) = module.get_functype_idx(function_index)?;
 
+                // The arguments are still on the stack while the call is being set up.
+                let call_height = stack
+                    .height()
+                    .checked_add(model.call_cost(ty.params(), ty.results()))
+                    .ok_or_else(|| anyhow!("stack overflow"))?;
+                if call_height > max_height && !stack.frame(0)?.is_polymorphic {
+                    max_height = call_height;
+                }
+
                 // Pop values for arguments of the function.
                 stack.pop_values(ty.params().len() as u32)?;
 
                 // Push result of the function execution to the stack.
-                let callee_arity = ty.results().len() as u32;
-                stack.push_values(callee_arity)?;
+                stack.push_values(ty.results())?;
             }
             CallIndirect { type_index, .. } => {
                 let Type::Func(ty) = module
@@ -275,38 +363,52 @@ pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
                 // Pop the offset into the function table.
                 stack.pop_values(1)?;
 
+                // The arguments are still on the stack while the call is being set up.
+                let call_height = stack
+                    .height()
+                    .checked_add(model.call_cost(ty.params(), ty.results()))
+                    .ok_or_else(|| anyhow!("stack overflow"))?;
+                if call_height > max_height && !stack.frame(0)?.is_polymorphic {
+                    max_height = call_height;
+                }
+
                 // Pop values for arguments of the function.
                 stack.pop_values(ty.params().len() as u32)?;
 
                 // Push result of the function execution to the stack.
-                let callee_arity = ty.results().len() as u32;
-                stack.push_values(callee_arity)?;
+                stack.push_values(ty.results())?;
             }
             Drop => {
                 stack.pop_values(1)?;
             }
             Select => {
-                // Pop two values and one condition.
-                stack.pop_values(2)?;
+                // Pop the condition and two values.
                 stack.pop_values(1)?;
+                let ty = stack.top_type();
+                stack.pop_values(2)?;
 
                 // Push the selected value.
-                stack.push_values(1)?;
+                stack.push_value(ty)?;
             }
-            LocalGet { .. } => {
-                stack.push_values(1)?;
+            LocalGet { local_index } => {
+                stack.push_value(local_type(local_index)?)?;
             }
             LocalSet { .. } => {
                 stack.pop_values(1)?;
             }
-            LocalTee { .. } => {
+            LocalTee { local_index } => {
                 // This instruction pops and pushes the value, so
                 // effectively it doesn't modify the stack height.
                 stack.pop_values(1)?;
-                stack.push_values(1)?;
+                stack.push_value(local_type(local_index)?)?;
             }
-            GlobalGet { .. } => {
-                stack.push_values(1)?;
+            GlobalGet { global_index } => {
+                let ty = module
+                    .global_types
+                    .get(global_index as usize)
+                    .ok_or_else(|| anyhow!("global index is out of bounds"))?
+                    .content_type;
+                stack.push_value(ty)?;
             }
             GlobalSet { .. } => {
                 stack.pop_values(1)?;
@@ -325,10 +427,19 @@ pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
             | I64Load16U { .. }
             | I64Load32S { .. }
             | I64Load32U { .. } => {
-                // These instructions pop the address and pushes the result,
-                // which effictively don't modify the stack height.
+                // These instructions pop the address and pushes the result.
+                let ty = match opcode {
+                    I32Load { .. }
+                    | I32Load8S { .. }
+                    | I32Load8U { .. }
+                    | I32Load16S { .. }
+                    | I32Load16U { .. } => ValType::I32,
+                    F32Load { .. } => ValType::F32,
+                    F64Load { .. } => ValType::F64,
+                    _ => ValType::I64,
+                };
                 stack.pop_values(1)?;
-                stack.push_values(1)?;
+                stack.push_value(ty)?;
             }
 
             I32Store { .. }
@@ -346,24 +457,24 @@ pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
 
             MemorySize { .. } => {
                 // Pushes current memory size
-                stack.push_values(1)?;
+                stack.push_value(ValType::I32)?;
             }
             MemoryGrow { .. } => {
                 // Grow memory takes the value of pages to grow and pushes
                 stack.pop_values(1)?;
-                stack.push_values(1)?;
+                stack.push_value(ValType::I32)?;
             }
 
-            I32Const { .. } | I64Const { .. } | F32Const { .. } | F64Const { .. } => {
-                // These instructions just push the single literal value onto the stack.
-                stack.push_values(1)?;
-            }
+            I32Const { .. } => stack.push_value(ValType::I32)?,
+            I64Const { .. } => stack.push_value(ValType::I64)?,
+            F32Const { .. } => stack.push_value(ValType::F32)?,
+            F64Const { .. } => stack.push_value(ValType::F64)?,
 
             I32Eqz | I64Eqz => {
                 // These instructions pop the value and compare it against zero, and pushes
                 // the result of the comparison.
                 stack.pop_values(1)?;
-                stack.push_values(1)?;
+                stack.push_value(ValType::I32)?;
             }
 
             I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS
@@ -372,15 +483,16 @@ pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
             | F64Lt | F64Gt | F64Le | F64Ge => {
                 // Comparison operations take two operands and produce one result.
                 stack.pop_values(2)?;
-                stack.push_values(1)?;
+                stack.push_value(ValType::I32)?;
             }
 
             I32Clz | I32Ctz | I32Popcnt | I64Clz | I64Ctz | I64Popcnt | F32Abs | F32Neg
             | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt | F64Abs | F64Neg | F64Ceil
             | F64Floor | F64Trunc | F64Nearest | F64Sqrt => {
-                // Unary operators take one operand and produce one result.
+                // Unary operators take one operand and produce one result of the same type.
+                let ty = stack.top_type();
                 stack.pop_values(1)?;
-                stack.push_values(1)?;
+                stack.push_value(ty)?;
             }
 
             I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or
@@ -389,28 +501,40 @@ pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
             | I64ShrS | I64ShrU | I64Rotl | I64Rotr | F32Add | F32Sub | F32Mul | F32Div
             | F32Min | F32Max | F32Copysign | F64Add | F64Sub | F64Mul | F64Div | F64Min
             | F64Max | F64Copysign => {
-                // Binary operators take two operands and produce one result.
+                // Binary operators take two operands and produce one result of the same type.
+                let ty = stack.top_type();
                 stack.pop_values(2)?;
-                stack.push_values(1)?;
+                stack.push_value(ty)?;
             }
 
             I32WrapI64 | I32TruncSatF32S | I32TruncSatF32U | I32TruncSatF64S | I32TruncSatF64U
-            | I64TruncSatF32S | I64TruncSatF32U | I64TruncSatF64S | I64TruncSatF64U
-            | I32TruncF32S | I32TruncF32U | I32TruncF64S | I32TruncF64U | I64TruncF32S
-            | I64TruncF32U | I64TruncF64S | I64TruncF64U | I64ExtendI32U | I64ExtendI32S
-            | F32ConvertI32S | F32ConvertI32U | F32ConvertI64S | F32ConvertI64U
-            | F64ConvertI32S | F64ConvertI32U | F64ConvertI64S | F64ConvertI64U | F32DemoteF64
-            | F64PromoteF32 | I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32
-            | F64ReinterpretI64 => {
+            | I32TruncF32S | I32TruncF32U | I32TruncF64S | I32TruncF64U | I32ReinterpretF32 => {
                 // Conversion operators take one value and produce one result.
                 stack.pop_values(1)?;
-                stack.push_values(1)?;
+                stack.push_value(ValType::I32)?;
+            }
+            I64TruncSatF32S | I64TruncSatF32U | I64TruncSatF64S | I64TruncSatF64U
+            | I64TruncF32S | I64TruncF32U | I64TruncF64S | I64TruncF64U | I64ExtendI32U
+            | I64ExtendI32S | I64ReinterpretF64 => {
+                stack.pop_values(1)?;
+                stack.push_value(ValType::I64)?;
+            }
+            F32ConvertI32S | F32ConvertI32U | F32ConvertI64S | F32ConvertI64U | F32DemoteF64
+            | F32ReinterpretI32 => {
+                stack.pop_values(1)?;
+                stack.push_value(ValType::F32)?;
+            }
+            F64ConvertI32S | F64ConvertI32U | F64ConvertI64S | F64ConvertI64U | F64PromoteF32
+            | F64ReinterpretI64 => {
+                stack.pop_values(1)?;
+                stack.push_value(ValType::F64)?;
             }
 
             //#[cfg(feature = "sign_ext")]
             I32Extend8S | I32Extend16S | I64Extend8S | I64Extend16S | I64Extend32S => {
+                let ty = stack.top_type();
                 stack.pop_values(1)?;
-                stack.push_values(1)?;
+                stack.push_value(ty)?;
             }
 
             //#[cfg(feature = "bulk")]
@@ -424,14 +548,19 @@ pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
             }
             TableGrow { .. } => {
                 stack.pop_values(2)?;
-                stack.push_values(1)?;
+                stack.push_value(ValType::I32)?;
             }
             TableSize { .. } => {
-                stack.push_values(1)?;
+                stack.push_value(ValType::I32)?;
             }
-            TableGet { .. } => {
+            TableGet { table } => {
+                let ty = module
+                    .table_elem_types
+                    .get(table as usize)
+                    .ok_or_else(|| anyhow!("table index is out of bounds"))?
+                    .element_type;
                 stack.pop_values(1)?;
-                stack.push_values(1)?;
+                stack.push_value(ty)?;
             }
             TableSet { .. } => {
                 stack.pop_values(2)?;
@@ -439,356 +568,10 @@ pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
 
             ElemDrop { .. } | DataDrop { .. } => {}
 
-            // Exception instruction
-            Try { .. }
-            | Catch { .. }
-            | Throw { .. }
-            | Rethrow { .. }
-            | Delegate { .. }
-            | CatchAll { .. } => {
-                return Err(anyhow!("exception instructions are not supported"));
-            }
-
-            // Reference types instructions
-            TypedSelect { .. } | RefNull { .. } | RefIsNull { .. } | RefFunc { .. } => {
-                return Err(anyhow!("exception instructions are not supported"));
-            }
-
-            // SIMD instructions
-            V128Load { .. }
-            | V128Load8x8S { .. }
-            | V128Load8x8U { .. }
-            | V128Load16x4S { .. }
-            | V128Load16x4U { .. }
-            | V128Load32x2S { .. }
-            | V128Load32x2U { .. }
-            | V128Load8Splat { .. }
-            | V128Load16Splat { .. }
-            | V128Load32Splat { .. }
-            | V128Load64Splat { .. }
-            | V128Load32Zero { .. }
-            | V128Load64Zero { .. }
-            | V128Store { .. }
-            | V128Load8Lane { .. }
-            | V128Load16Lane { .. }
-            | V128Load32Lane { .. }
-            | V128Load64Lane { .. }
-            | V128Store8Lane { .. }
-            | V128Store16Lane { .. }
-            | V128Store32Lane { .. }
-            | V128Store64Lane { .. }
-            | V128Const { .. }
-            | I8x16Shuffle { .. }
-            | I8x16ExtractLaneS { .. }
-            | I8x16ExtractLaneU { .. }
-            | I8x16ReplaceLane { .. }
-            | I16x8ExtractLaneS { .. }
-            | I16x8ExtractLaneU { .. }
-            | I16x8ReplaceLane { .. }
-            | I32x4ExtractLane { .. }
-            | I32x4ReplaceLane { .. }
-            | I64x2ExtractLane { .. }
-            | I64x2ReplaceLane { .. }
-            | F32x4ExtractLane { .. }
-            | F32x4ReplaceLane { .. }
-            | F64x2ExtractLane { .. }
-            | F64x2ReplaceLane { .. }
-            | I8x16Swizzle { .. }
-            | I8x16Splat { .. }
-            | I16x8Splat { .. }
-            | I32x4Splat { .. }
-            | I64x2Splat { .. }
-            | F32x4Splat { .. }
-            | F64x2Splat { .. }
-            | I8x16Eq { .. }
-            | I8x16Ne { .. }
-            | I8x16LtS { .. }
-            | I8x16LtU { .. }
-            | I8x16GtS { .. }
-            | I8x16GtU { .. }
-            | I8x16LeS { .. }
-            | I8x16LeU { .. }
-            | I8x16GeS { .. }
-            | I8x16GeU { .. }
-            | I16x8Eq { .. }
-            | I16x8Ne { .. }
-            | I16x8LtS { .. }
-            | I16x8LtU { .. }
-            | I16x8GtS { .. }
-            | I16x8GtU { .. }
-            | I16x8LeS { .. }
-            | I16x8LeU { .. }
-            | I16x8GeS { .. }
-            | I16x8GeU { .. }
-            | I32x4Eq { .. }
-            | I32x4Ne { .. }
-            | I32x4LtS { .. }
-            | I32x4LtU { .. }
-            | I32x4GtS { .. }
-            | I32x4GtU { .. }
-            | I32x4LeS { .. }
-            | I32x4LeU { .. }
-            | I32x4GeS { .. }
-            | I32x4GeU { .. }
-            | I64x2Eq { .. }
-            | I64x2Ne { .. }
-            | I64x2LtS { .. }
-            | I64x2GtS { .. }
-            | I64x2LeS { .. }
-            | I64x2GeS { .. }
-            | F32x4Eq { .. }
-            | F32x4Ne { .. }
-            | F32x4Lt { .. }
-            | F32x4Gt { .. }
-            | F32x4Le { .. }
-            | F32x4Ge { .. }
-            | F64x2Eq { .. }
-            | F64x2Ne { .. }
-            | F64x2Lt { .. }
-            | F64x2Gt { .. }
-            | F64x2Le { .. }
-            | F64x2Ge { .. }
-            | V128Not { .. }
-            | V128And { .. }
-            | V128AndNot { .. }
-            | V128Or { .. }
-            | V128Xor { .. }
-            | V128Bitselect { .. }
-            | V128AnyTrue { .. }
-            | I8x16Abs { .. }
-            | I8x16Neg { .. }
-            | I8x16Popcnt { .. }
-            | I8x16AllTrue { .. }
-            | I8x16Bitmask { .. }
-            | I8x16NarrowI16x8S { .. }
-            | I8x16NarrowI16x8U { .. }
-            | I8x16Shl { .. }
-            | I8x16ShrS { .. }
-            | I8x16ShrU { .. }
-            | I8x16Add { .. }
-            | I8x16AddSatS { .. }
-            | I8x16AddSatU { .. }
-            | I8x16Sub { .. }
-            | I8x16SubSatS { .. }
-            | I8x16SubSatU { .. }
-            | I8x16MinS { .. }
-            | I8x16MinU { .. }
-            | I8x16MaxS { .. }
-            | I8x16MaxU { .. }
-            | I8x16AvgrU { .. }
-            | I16x8ExtAddPairwiseI8x16S { .. }
-            | I16x8ExtAddPairwiseI8x16U { .. }
-            | I16x8Abs { .. }
-            | I16x8Neg { .. }
-            | I16x8Q15MulrSatS { .. }
-            | I16x8AllTrue { .. }
-            | I16x8Bitmask { .. }
-            | I16x8NarrowI32x4S { .. }
-            | I16x8NarrowI32x4U { .. }
-            | I16x8ExtendLowI8x16S { .. }
-            | I16x8ExtendHighI8x16S { .. }
-            | I16x8ExtendLowI8x16U { .. }
-            | I16x8ExtendHighI8x16U { .. }
-            | I16x8Shl { .. }
-            | I16x8ShrS { .. }
-            | I16x8ShrU { .. }
-            | I16x8Add { .. }
-            | I16x8AddSatS { .. }
-            | I16x8AddSatU { .. }
-            | I16x8Sub { .. }
-            | I16x8SubSatS { .. }
-            | I16x8SubSatU { .. }
-            | I16x8Mul { .. }
-            | I16x8MinS { .. }
-            | I16x8MinU { .. }
-            | I16x8MaxS { .. }
-            | I16x8MaxU { .. }
-            | I16x8AvgrU { .. }
-            | I16x8ExtMulLowI8x16S { .. }
-            | I16x8ExtMulHighI8x16S { .. }
-            | I16x8ExtMulLowI8x16U { .. }
-            | I16x8ExtMulHighI8x16U { .. }
-            | I32x4ExtAddPairwiseI16x8S { .. }
-            | I32x4ExtAddPairwiseI16x8U { .. }
-            | I32x4Abs { .. }
-            | I32x4Neg { .. }
-            | I32x4AllTrue { .. }
-            | I32x4Bitmask { .. }
-            | I32x4ExtendLowI16x8S { .. }
-            | I32x4ExtendHighI16x8S { .. }
-            | I32x4ExtendLowI16x8U { .. }
-            | I32x4ExtendHighI16x8U { .. }
-            | I32x4Shl { .. }
-            | I32x4ShrS { .. }
-            | I32x4ShrU { .. }
-            | I32x4Add { .. }
-            | I32x4Sub { .. }
-            | I32x4Mul { .. }
-            | I32x4MinS { .. }
-            | I32x4MinU { .. }
-            | I32x4MaxS { .. }
-            | I32x4MaxU { .. }
-            | I32x4DotI16x8S { .. }
-            | I32x4ExtMulLowI16x8S { .. }
-            | I32x4ExtMulHighI16x8S { .. }
-            | I32x4ExtMulLowI16x8U { .. }
-            | I32x4ExtMulHighI16x8U { .. }
-            | I64x2Abs { .. }
-            | I64x2Neg { .. }
-            | I64x2AllTrue { .. }
-            | I64x2Bitmask { .. }
-            | I64x2ExtendLowI32x4S { .. }
-            | I64x2ExtendHighI32x4S { .. }
-            | I64x2ExtendLowI32x4U { .. }
-            | I64x2ExtendHighI32x4U { .. }
-            | I64x2Shl { .. }
-            | I64x2ShrS { .. }
-            | I64x2ShrU { .. }
-            | I64x2Add { .. }
-            | I64x2Sub { .. }
-            | I64x2Mul { .. }
-            | I64x2ExtMulLowI32x4S { .. }
-            | I64x2ExtMulHighI32x4S { .. }
-            | I64x2ExtMulLowI32x4U { .. }
-            | I64x2ExtMulHighI32x4U { .. }
-            | F32x4Ceil { .. }
-            | F32x4Floor { .. }
-            | F32x4Trunc { .. }
-            | F32x4Nearest { .. }
-            | F32x4Abs { .. }
-            | F32x4Neg { .. }
-            | F32x4Sqrt { .. }
-            | F32x4Add { .. }
-            | F32x4Sub { .. }
-            | F32x4Mul { .. }
-            | F32x4Div { .. }
-            | F32x4Min { .. }
-            | F32x4Max { .. }
-            | F32x4PMin { .. }
-            | F32x4PMax { .. }
-            | F64x2Ceil { .. }
-            | F64x2Floor { .. }
-            | F64x2Trunc { .. }
-            | F64x2Nearest { .. }
-            | F64x2Abs { .. }
-            | F64x2Neg { .. }
-            | F64x2Sqrt { .. }
-            | F64x2Add { .. }
-            | F64x2Sub { .. }
-            | F64x2Mul { .. }
-            | F64x2Div { .. }
-            | F64x2Min { .. }
-            | F64x2Max { .. }
-            | F64x2PMin { .. }
-            | F64x2PMax { .. }
-            | I32x4TruncSatF32x4S { .. }
-            | I32x4TruncSatF32x4U { .. }
-            | F32x4ConvertI32x4S { .. }
-            | F32x4ConvertI32x4U { .. }
-            | I32x4TruncSatF64x2SZero { .. }
-            | I32x4TruncSatF64x2UZero { .. }
-            | F64x2ConvertLowI32x4S { .. }
-            | F64x2ConvertLowI32x4U { .. }
-            | F32x4DemoteF64x2Zero { .. }
-            | F64x2PromoteLowF32x4 { .. }
-            | I8x16RelaxedSwizzle { .. }
-            | I32x4RelaxedTruncSatF32x4S { .. }
-            | I32x4RelaxedTruncSatF32x4U { .. }
-            | I32x4RelaxedTruncSatF64x2SZero { .. }
-            | I32x4RelaxedTruncSatF64x2UZero { .. }
-            | F32x4RelaxedFma { .. }
-            | F32x4RelaxedFnma { .. }
-            | F64x2RelaxedFma { .. }
-            | F64x2RelaxedFnma { .. }
-            | I8x16RelaxedLaneselect { .. }
-            | I16x8RelaxedLaneselect { .. }
-            | I32x4RelaxedLaneselect { .. }
-            | I64x2RelaxedLaneselect { .. }
-            | F32x4RelaxedMin { .. }
-            | F32x4RelaxedMax { .. }
-            | F64x2RelaxedMin { .. }
-            | F64x2RelaxedMax { .. }
-            | I16x8RelaxedQ15mulrS { .. }
-            | I16x8DotI8x16I7x16S { .. }
-            | I32x4DotI8x16I7x16AddS { .. }
-            | F32x4RelaxedDotBf16x8AddF32x4 { .. } => {
-                return Err(anyhow!("simd instructions are not supported"))
-            }
-
-            // Atomic instructions
-            MemoryAtomicNotify { .. }
-            | MemoryAtomicWait32 { .. }
-            | MemoryAtomicWait64 { .. }
-            | I32AtomicLoad { .. }
-            | I64AtomicLoad { .. }
-            | I32AtomicLoad8U { .. }
-            | I32AtomicLoad16U { .. }
-            | I64AtomicLoad8U { .. }
-            | I64AtomicLoad16U { .. }
-            | I64AtomicLoad32U { .. }
-            | I32AtomicStore { .. }
-            | I64AtomicStore { .. }
-            | I32AtomicStore8 { .. }
-            | I32AtomicStore16 { .. }
-            | I64AtomicStore8 { .. }
-            | I64AtomicStore16 { .. }
-            | I64AtomicStore32 { .. }
-            | I32AtomicRmwAdd { .. }
-            | I64AtomicRmwAdd { .. }
-            | I32AtomicRmw8AddU { .. }
-            | I32AtomicRmw16AddU { .. }
-            | I64AtomicRmw8AddU { .. }
-            | I64AtomicRmw16AddU { .. }
-            | I64AtomicRmw32AddU { .. }
-            | I32AtomicRmwSub { .. }
-            | I64AtomicRmwSub { .. }
-            | I32AtomicRmw8SubU { .. }
-            | I32AtomicRmw16SubU { .. }
-            | I64AtomicRmw8SubU { .. }
-            | I64AtomicRmw16SubU { .. }
-            | I64AtomicRmw32SubU { .. }
-            | I32AtomicRmwAnd { .. }
-            | I64AtomicRmwAnd { .. }
-            | I32AtomicRmw8AndU { .. }
-            | I32AtomicRmw16AndU { .. }
-            | I64AtomicRmw8AndU { .. }
-            | I64AtomicRmw16AndU { .. }
-            | I64AtomicRmw32AndU { .. }
-            | I32AtomicRmwOr { .. }
-            | I64AtomicRmwOr { .. }
-            | I32AtomicRmw8OrU { .. }
-            | I32AtomicRmw16OrU { .. }
-            | I64AtomicRmw8OrU { .. }
-            | I64AtomicRmw16OrU { .. }
-            | I64AtomicRmw32OrU { .. }
-            | I32AtomicRmwXor { .. }
-            | I64AtomicRmwXor { .. }
-            | I32AtomicRmw8XorU { .. }
-            | I32AtomicRmw16XorU { .. }
-            | I64AtomicRmw8XorU { .. }
-            | I64AtomicRmw16XorU { .. }
-            | I64AtomicRmw32XorU { .. }
-            | I32AtomicRmwXchg { .. }
-            | I64AtomicRmwXchg { .. }
-            | I32AtomicRmw8XchgU { .. }
-            | I32AtomicRmw16XchgU { .. }
-            | I64AtomicRmw8XchgU { .. }
-            | I64AtomicRmw16XchgU { .. }
-            | I64AtomicRmw32XchgU { .. }
-            | I32AtomicRmwCmpxchg { .. }
-            | I64AtomicRmwCmpxchg { .. }
-            | I32AtomicRmw8CmpxchgU { .. }
-            | I32AtomicRmw16CmpxchgU { .. }
-            | I64AtomicRmw8CmpxchgU { .. }
-            | I64AtomicRmw16CmpxchgU { .. }
-            | AtomicFence { .. }
-            | I64AtomicRmw32CmpxchgU { .. } => {
-                return Err(anyhow!("atomic instructions are not supported"))
-            }
-
-            // Tail-call instructions
-            ReturnCall { .. } | ReturnCallIndirect { .. } => {
-                return Err(anyhow!("exception instructions are not supported"));
+            op => {
+                return Err(unsupported_proposal(&op)
+                    .ok_or_else(|| anyhow!("unknown instruction {:?}", op))?
+                    .into());
             }
         }
     }
@@ -796,9 +579,366 @@ pub fn compute(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
     Ok(max_height)
 }
 
+/// Returns the error for an instruction of a proposal which isn't supported by the stack height
+/// analysis, or `None` if the instruction is supported.
+fn unsupported_proposal(op: &Operator) -> Option<InstrumentError> {
+    use wasmparser::Operator::*;
+
+    let proposal = match op {
+        // Exception instruction
+        Try { .. }
+        | Catch { .. }
+        | Throw { .. }
+        | Rethrow { .. }
+        | Delegate { .. }
+        | CatchAll { .. } => "exception handling",
+
+        // Reference types instructions
+        TypedSelect { .. } | RefNull { .. } | RefIsNull { .. } | RefFunc { .. } => {
+            "reference types"
+        }
+
+        // SIMD instructions
+        V128Load { .. }
+        | V128Load8x8S { .. }
+        | V128Load8x8U { .. }
+        | V128Load16x4S { .. }
+        | V128Load16x4U { .. }
+        | V128Load32x2S { .. }
+        | V128Load32x2U { .. }
+        | V128Load8Splat { .. }
+        | V128Load16Splat { .. }
+        | V128Load32Splat { .. }
+        | V128Load64Splat { .. }
+        | V128Load32Zero { .. }
+        | V128Load64Zero { .. }
+        | V128Store { .. }
+        | V128Load8Lane { .. }
+        | V128Load16Lane { .. }
+        | V128Load32Lane { .. }
+        | V128Load64Lane { .. }
+        | V128Store8Lane { .. }
+        | V128Store16Lane { .. }
+        | V128Store32Lane { .. }
+        | V128Store64Lane { .. }
+        | V128Const { .. }
+        | I8x16Shuffle { .. }
+        | I8x16ExtractLaneS { .. }
+        | I8x16ExtractLaneU { .. }
+        | I8x16ReplaceLane { .. }
+        | I16x8ExtractLaneS { .. }
+        | I16x8ExtractLaneU { .. }
+        | I16x8ReplaceLane { .. }
+        | I32x4ExtractLane { .. }
+        | I32x4ReplaceLane { .. }
+        | I64x2ExtractLane { .. }
+        | I64x2ReplaceLane { .. }
+        | F32x4ExtractLane { .. }
+        | F32x4ReplaceLane { .. }
+        | F64x2ExtractLane { .. }
+        | F64x2ReplaceLane { .. }
+        | I8x16Swizzle { .. }
+        | I8x16Splat { .. }
+        | I16x8Splat { .. }
+        | I32x4Splat { .. }
+        | I64x2Splat { .. }
+        | F32x4Splat { .. }
+        | F64x2Splat { .. }
+        | I8x16Eq { .. }
+        | I8x16Ne { .. }
+        | I8x16LtS { .. }
+        | I8x16LtU { .. }
+        | I8x16GtS { .. }
+        | I8x16GtU { .. }
+        | I8x16LeS { .. }
+        | I8x16LeU { .. }
+        | I8x16GeS { .. }
+        | I8x16GeU { .. }
+        | I16x8Eq { .. }
+        | I16x8Ne { .. }
+        | I16x8LtS { .. }
+        | I16x8LtU { .. }
+        | I16x8GtS { .. }
+        | I16x8GtU { .. }
+        | I16x8LeS { .. }
+        | I16x8LeU { .. }
+        | I16x8GeS { .. }
+        | I16x8GeU { .. }
+        | I32x4Eq { .. }
+        | I32x4Ne { .. }
+        | I32x4LtS { .. }
+        | I32x4LtU { .. }
+        | I32x4GtS { .. }
+        | I32x4GtU { .. }
+        | I32x4LeS { .. }
+        | I32x4LeU { .. }
+        | I32x4GeS { .. }
+        | I32x4GeU { .. }
+        | I64x2Eq { .. }
+        | I64x2Ne { .. }
+        | I64x2LtS { .. }
+        | I64x2GtS { .. }
+        | I64x2LeS { .. }
+        | I64x2GeS { .. }
+        | F32x4Eq { .. }
+        | F32x4Ne { .. }
+        | F32x4Lt { .. }
+        | F32x4Gt { .. }
+        | F32x4Le { .. }
+        | F32x4Ge { .. }
+        | F64x2Eq { .. }
+        | F64x2Ne { .. }
+        | F64x2Lt { .. }
+        | F64x2Gt { .. }
+        | F64x2Le { .. }
+        | F64x2Ge { .. }
+        | V128Not { .. }
+        | V128And { .. }
+        | V128AndNot { .. }
+        | V128Or { .. }
+        | V128Xor { .. }
+        | V128Bitselect { .. }
+        | V128AnyTrue { .. }
+        | I8x16Abs { .. }
+        | I8x16Neg { .. }
+        | I8x16Popcnt { .. }
+        | I8x16AllTrue { .. }
+        | I8x16Bitmask { .. }
+        | I8x16NarrowI16x8S { .. }
+        | I8x16NarrowI16x8U { .. }
+        | I8x16Shl { .. }
+        | I8x16ShrS { .. }
+        | I8x16ShrU { .. }
+        | I8x16Add { .. }
+        | I8x16AddSatS { .. }
+        | I8x16AddSatU { .. }
+        | I8x16Sub { .. }
+        | I8x16SubSatS { .. }
+        | I8x16SubSatU { .. }
+        | I8x16MinS { .. }
+        | I8x16MinU { .. }
+        | I8x16MaxS { .. }
+        | I8x16MaxU { .. }
+        | I8x16AvgrU { .. }
+        | I16x8ExtAddPairwiseI8x16S { .. }
+        | I16x8ExtAddPairwiseI8x16U { .. }
+        | I16x8Abs { .. }
+        | I16x8Neg { .. }
+        | I16x8Q15MulrSatS { .. }
+        | I16x8AllTrue { .. }
+        | I16x8Bitmask { .. }
+        | I16x8NarrowI32x4S { .. }
+        | I16x8NarrowI32x4U { .. }
+        | I16x8ExtendLowI8x16S { .. }
+        | I16x8ExtendHighI8x16S { .. }
+        | I16x8ExtendLowI8x16U { .. }
+        | I16x8ExtendHighI8x16U { .. }
+        | I16x8Shl { .. }
+        | I16x8ShrS { .. }
+        | I16x8ShrU { .. }
+        | I16x8Add { .. }
+        | I16x8AddSatS { .. }
+        | I16x8AddSatU { .. }
+        | I16x8Sub { .. }
+        | I16x8SubSatS { .. }
+        | I16x8SubSatU { .. }
+        | I16x8Mul { .. }
+        | I16x8MinS { .. }
+        | I16x8MinU { .. }
+        | I16x8MaxS { .. }
+        | I16x8MaxU { .. }
+        | I16x8AvgrU { .. }
+        | I16x8ExtMulLowI8x16S { .. }
+        | I16x8ExtMulHighI8x16S { .. }
+        | I16x8ExtMulLowI8x16U { .. }
+        | I16x8ExtMulHighI8x16U { .. }
+        | I32x4ExtAddPairwiseI16x8S { .. }
+        | I32x4ExtAddPairwiseI16x8U { .. }
+        | I32x4Abs { .. }
+        | I32x4Neg { .. }
+        | I32x4AllTrue { .. }
+        | I32x4Bitmask { .. }
+        | I32x4ExtendLowI16x8S { .. }
+        | I32x4ExtendHighI16x8S { .. }
+        | I32x4ExtendLowI16x8U { .. }
+        | I32x4ExtendHighI16x8U { .. }
+        | I32x4Shl { .. }
+        | I32x4ShrS { .. }
+        | I32x4ShrU { .. }
+        | I32x4Add { .. }
+        | I32x4Sub { .. }
+        | I32x4Mul { .. }
+        | I32x4MinS { .. }
+        | I32x4MinU { .. }
+        | I32x4MaxS { .. }
+        | I32x4MaxU { .. }
+        | I32x4DotI16x8S { .. }
+        | I32x4ExtMulLowI16x8S { .. }
+        | I32x4ExtMulHighI16x8S { .. }
+        | I32x4ExtMulLowI16x8U { .. }
+        | I32x4ExtMulHighI16x8U { .. }
+        | I64x2Abs { .. }
+        | I64x2Neg { .. }
+        | I64x2AllTrue { .. }
+        | I64x2Bitmask { .. }
+        | I64x2ExtendLowI32x4S { .. }
+        | I64x2ExtendHighI32x4S { .. }
+        | I64x2ExtendLowI32x4U { .. }
+        | I64x2ExtendHighI32x4U { .. }
+        | I64x2Shl { .. }
+        | I64x2ShrS { .. }
+        | I64x2ShrU { .. }
+        | I64x2Add { .. }
+        | I64x2Sub { .. }
+        | I64x2Mul { .. }
+        | I64x2ExtMulLowI32x4S { .. }
+        | I64x2ExtMulHighI32x4S { .. }
+        | I64x2ExtMulLowI32x4U { .. }
+        | I64x2ExtMulHighI32x4U { .. }
+        | F32x4Ceil { .. }
+        | F32x4Floor { .. }
+        | F32x4Trunc { .. }
+        | F32x4Nearest { .. }
+        | F32x4Abs { .. }
+        | F32x4Neg { .. }
+        | F32x4Sqrt { .. }
+        | F32x4Add { .. }
+        | F32x4Sub { .. }
+        | F32x4Mul { .. }
+        | F32x4Div { .. }
+        | F32x4Min { .. }
+        | F32x4Max { .. }
+        | F32x4PMin { .. }
+        | F32x4PMax { .. }
+        | F64x2Ceil { .. }
+        | F64x2Floor { .. }
+        | F64x2Trunc { .. }
+        | F64x2Nearest { .. }
+        | F64x2Abs { .. }
+        | F64x2Neg { .. }
+        | F64x2Sqrt { .. }
+        | F64x2Add { .. }
+        | F64x2Sub { .. }
+        | F64x2Mul { .. }
+        | F64x2Div { .. }
+        | F64x2Min { .. }
+        | F64x2Max { .. }
+        | F64x2PMin { .. }
+        | F64x2PMax { .. }
+        | I32x4TruncSatF32x4S { .. }
+        | I32x4TruncSatF32x4U { .. }
+        | F32x4ConvertI32x4S { .. }
+        | F32x4ConvertI32x4U { .. }
+        | I32x4TruncSatF64x2SZero { .. }
+        | I32x4TruncSatF64x2UZero { .. }
+        | F64x2ConvertLowI32x4S { .. }
+        | F64x2ConvertLowI32x4U { .. }
+        | F32x4DemoteF64x2Zero { .. }
+        | F64x2PromoteLowF32x4 { .. }
+        | I8x16RelaxedSwizzle { .. }
+        | I32x4RelaxedTruncSatF32x4S { .. }
+        | I32x4RelaxedTruncSatF32x4U { .. }
+        | I32x4RelaxedTruncSatF64x2SZero { .. }
+        | I32x4RelaxedTruncSatF64x2UZero { .. }
+        | F32x4RelaxedFma { .. }
+        | F32x4RelaxedFnma { .. }
+        | F64x2RelaxedFma { .. }
+        | F64x2RelaxedFnma { .. }
+        | I8x16RelaxedLaneselect { .. }
+        | I16x8RelaxedLaneselect { .. }
+        | I32x4RelaxedLaneselect { .. }
+        | I64x2RelaxedLaneselect { .. }
+        | F32x4RelaxedMin { .. }
+        | F32x4RelaxedMax { .. }
+        | F64x2RelaxedMin { .. }
+        | F64x2RelaxedMax { .. }
+        | I16x8RelaxedQ15mulrS { .. }
+        | I16x8DotI8x16I7x16S { .. }
+        | I32x4DotI8x16I7x16AddS { .. }
+        | F32x4RelaxedDotBf16x8AddF32x4 { .. } => "simd",
+
+        // Atomic instructions
+        MemoryAtomicNotify { .. }
+        | MemoryAtomicWait32 { .. }
+        | MemoryAtomicWait64 { .. }
+        | I32AtomicLoad { .. }
+        | I64AtomicLoad { .. }
+        | I32AtomicLoad8U { .. }
+        | I32AtomicLoad16U { .. }
+        | I64AtomicLoad8U { .. }
+        | I64AtomicLoad16U { .. }
+        | I64AtomicLoad32U { .. }
+        | I32AtomicStore { .. }
+        | I64AtomicStore { .. }
+        | I32AtomicStore8 { .. }
+        | I32AtomicStore16 { .. }
+        | I64AtomicStore8 { .. }
+        | I64AtomicStore16 { .. }
+        | I64AtomicStore32 { .. }
+        | I32AtomicRmwAdd { .. }
+        | I64AtomicRmwAdd { .. }
+        | I32AtomicRmw8AddU { .. }
+        | I32AtomicRmw16AddU { .. }
+        | I64AtomicRmw8AddU { .. }
+        | I64AtomicRmw16AddU { .. }
+        | I64AtomicRmw32AddU { .. }
+        | I32AtomicRmwSub { .. }
+        | I64AtomicRmwSub { .. }
+        | I32AtomicRmw8SubU { .. }
+        | I32AtomicRmw16SubU { .. }
+        | I64AtomicRmw8SubU { .. }
+        | I64AtomicRmw16SubU { .. }
+        | I64AtomicRmw32SubU { .. }
+        | I32AtomicRmwAnd { .. }
+        | I64AtomicRmwAnd { .. }
+        | I32AtomicRmw8AndU { .. }
+        | I32AtomicRmw16AndU { .. }
+        | I64AtomicRmw8AndU { .. }
+        | I64AtomicRmw16AndU { .. }
+        | I64AtomicRmw32AndU { .. }
+        | I32AtomicRmwOr { .. }
+        | I64AtomicRmwOr { .. }
+        | I32AtomicRmw8OrU { .. }
+        | I32AtomicRmw16OrU { .. }
+        | I64AtomicRmw8OrU { .. }
+        | I64AtomicRmw16OrU { .. }
+        | I64AtomicRmw32OrU { .. }
+        | I32AtomicRmwXor { .. }
+        | I64AtomicRmwXor { .. }
+        | I32AtomicRmw8XorU { .. }
+        | I32AtomicRmw16XorU { .. }
+        | I64AtomicRmw8XorU { .. }
+        | I64AtomicRmw16XorU { .. }
+        | I64AtomicRmw32XorU { .. }
+        | I32AtomicRmwXchg { .. }
+        | I64AtomicRmwXchg { .. }
+        | I32AtomicRmw8XchgU { .. }
+        | I32AtomicRmw16XchgU { .. }
+        | I64AtomicRmw8XchgU { .. }
+        | I64AtomicRmw16XchgU { .. }
+        | I64AtomicRmw32XchgU { .. }
+        | I32AtomicRmwCmpxchg { .. }
+        | I64AtomicRmwCmpxchg { .. }
+        | I32AtomicRmw8CmpxchgU { .. }
+        | I32AtomicRmw16CmpxchgU { .. }
+        | I64AtomicRmw8CmpxchgU { .. }
+        | I64AtomicRmw16CmpxchgU { .. }
+        | AtomicFence { .. }
+        | I64AtomicRmw32CmpxchgU { .. } => "threads",
+
+        // Tail-call instructions
+        ReturnCall { .. } | ReturnCallIndirect { .. } => "tail calls",
+        _ => return None,
+    };
+    Some(InstrumentError::UnsupportedProposal(proposal.into()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stack_limiter::{
+        cost_model::ACTIVATION_FRAME_COST, DefaultStackCostModel, LocalsCount,
+    };
 
     fn parse_wat(source: &str) -> ModuleInfo {
         let module_bytes = wat::parse_str(source).unwrap();
@@ -822,7 +962,7 @@ mod tests {
 "#,
         );
 
-        let height = compute(0, &module).unwrap();
+        let height = compute(0, &module, &DefaultStackCostModel::default()).unwrap();
         assert_eq!(height, 3 + ACTIVATION_FRAME_COST);
     }
 
@@ -839,7 +979,7 @@ mod tests {
 "#,
         );
 
-        let height = compute(0, &module).unwrap();
+        let height = compute(0, &module, &DefaultStackCostModel::default()).unwrap();
         assert_eq!(height, 1 + ACTIVATION_FRAME_COST);
     }
 
@@ -857,7 +997,7 @@ mod tests {
 "#,
         );
 
-        let height = compute(0, &module).unwrap();
+        let height = compute(0, &module, &DefaultStackCostModel::default()).unwrap();
         assert_eq!(height, ACTIVATION_FRAME_COST);
     }
 
@@ -886,7 +1026,7 @@ mod tests {
 "#,
         );
 
-        let height = compute(0, &module).unwrap();
+        let height = compute(0, &module, &DefaultStackCostModel::default()).unwrap();
         assert_eq!(height, 2 + ACTIVATION_FRAME_COST);
     }
 
@@ -910,7 +1050,7 @@ mod tests {
 "#,
         );
 
-        let height = compute(0, &module).unwrap();
+        let height = compute(0, &module, &DefaultStackCostModel::default()).unwrap();
         assert_eq!(height, 1 + ACTIVATION_FRAME_COST);
     }
 
@@ -932,7 +1072,7 @@ mod tests {
 "#,
         );
 
-        let height = compute(0, &module).unwrap();
+        let height = compute(0, &module, &DefaultStackCostModel::default()).unwrap();
         assert_eq!(height, 1 + ACTIVATION_FRAME_COST);
     }
 
@@ -958,7 +1098,191 @@ mod tests {
 "#,
         );
 
-        let height = compute(0, &module).unwrap();
+        let height = compute(0, &module, &DefaultStackCostModel::default()).unwrap();
+        assert_eq!(height, 3 + ACTIVATION_FRAME_COST);
+    }
+
+    #[test]
+    fn multi_value_blocks() {
+        let module = parse_wat(
+            r#"
+(module
+	(func (param i32) (result i32 i32)
+		i32.const 1
+		i32.const 2
+		block (param i32 i32) (result i32 i32)
+			i32.const 3
+			drop
+		end
+		loop (param i32 i32) (result i32 i32)
+			local.get 0
+			br_if 0
+		end
+	)
+)
+"#,
+        );
+
+        let height = compute(0, &module, &DefaultStackCostModel::default()).unwrap();
         assert_eq!(height, 3 + ACTIVATION_FRAME_COST);
     }
+
+    #[test]
+    fn else_starts_with_block_params() {
+        let module = parse_wat(
+            r#"
+(module
+	(func (param i32) (result i64)
+		i64.const 1
+		i64.const 2
+		local.get 0
+		if (param i64 i64) (result i64)
+			i64.add
+			br 0
+		else
+			drop
+			i64.const 3
+			i64.const 4
+			i64.const 5
+			drop
+			drop
+			drop
+		end
+	)
+)
+"#,
+        );
+
+        // The `else` branch is reachable and only has the first parameter left,
+        // the values of the `then` branch are not on the stack anymore.
+        let height = compute(0, &module, &DefaultStackCostModel::default()).unwrap();
+        assert_eq!(height, 4 + ACTIVATION_FRAME_COST);
+    }
+
+    #[test]
+    fn legacy_if_else() {
+        let module = parse_wat(
+            r#"
+(module
+	(func (param i32) (result i32)
+		local.get 0
+		if (result i32)
+			i32.const 1
+		else
+			i32.const 2
+		end
+	)
+)
+"#,
+        );
+
+        let height = compute(0, &module, &DefaultStackCostModel::default()).unwrap();
+        assert_eq!(height, 1 + ACTIVATION_FRAME_COST);
+
+        // Counting the locals as up to version 0.4 doesn't change the value stack.
+        let declaration_groups = DefaultStackCostModel {
+            locals_count: LocalsCount::DeclarationGroups,
+            ..Default::default()
+        };
+        let height = compute(0, &module, &declaration_groups).unwrap();
+        assert_eq!(height, 1 + ACTIVATION_FRAME_COST);
+
+        // Up to version 0.4, the `else` branch started with the value of the `then` branch
+        let legacy = DefaultStackCostModel {
+            value_stack: ValueStack::Legacy,
+            ..Default::default()
+        };
+        let height = compute(0, &module, &legacy).unwrap();
+        assert_eq!(height, 2 + ACTIVATION_FRAME_COST);
+    }
+
+    #[test]
+    fn legacy_matches_v0_4() {
+        // The heights and errors computed by version 0.4 for these bodies, which are the
+        // last function of each module.
+        let cases: &[(&str, Result<u32, &str>)] = &[
+            // polymorphic pop below the start of the block
+            (
+                r#"(module (memory 1) (func i32.const 0 block i32.const 1 br 0 i32.store end drop))"#,
+                Ok(4),
+            ),
+            (
+                r#"(module (memory 1) (func (result i32) i32.const 0 i32.const 0 block i32.const 1 br 0 i32.store end i32.const 2 i32.add))"#,
+                Ok(5),
+            ),
+            // br_if with and without results
+            (
+                r#"(module (func (result i32) block (result i32) i32.const 1 i32.const 0 br_if 0 i32.const 3 i32.add end))"#,
+                Ok(4),
+            ),
+            (
+                r#"(module (func i32.const 7 block i32.const 0 br_if 0 i32.const 1 i32.const 2 drop drop end drop))"#,
+                Ok(5),
+            ),
+            (
+                r#"(module (func (result i32) block (result i32) unreachable br_if 0 end))"#,
+                Ok(3),
+            ),
+            // select
+            (
+                r#"(module (func (result i32) i32.const 1 i32.const 2 i32.const 0 select))"#,
+                Ok(5),
+            ),
+            (
+                r#"(module (func (result i64) i64.const 5 block (result i64) unreachable select end i64.add))"#,
+                Ok(4),
+            ),
+            (
+                r#"(module (func (result i32) block (result i32) i32.const 4 unreachable select end))"#,
+                Ok(3),
+            ),
+            // br out of nested blocks and loops
+            (
+                r#"(module (func (result i32) block (result i32) loop i32.const 1 i32.const 2 br 1 end i32.const 3 end))"#,
+                Ok(4),
+            ),
+            (
+                r#"(module (func (param i32) (result i32) block (result i32) block (result i32) i32.const 9 local.get 0 br_table 0 1 1 end end))"#,
+                Ok(4),
+            ),
+            (
+                r#"(module (func (result i32) i32.const 1 block (result i32) i32.const 2 return end drop))"#,
+                Ok(4),
+            ),
+            // if/else with results
+            (
+                r#"(module (func (param i32) (result i32) local.get 0 if (result i32) i32.const 1 i32.const 2 i32.add else i32.const 3 end))"#,
+                Ok(4),
+            ),
+            (
+                r#"(module (func (param i32) local.get 0 if i32.const 1 drop else i32.const 2 i32.const 3 drop drop end))"#,
+                Ok(4),
+            ),
+            // multi-value blocks
+            (
+                r#"(module (type (func (param i32) (result i32 i32))) (func (result i32) i32.const 1 block (type 0) (param i32) (result i32 i32) i32.const 2 end i32.add))"#,
+                Ok(4),
+            ),
+            (
+                r#"(module (type (func (param i32 i32) (result i32))) (func (result i32) i32.const 1 i32.const 2 loop (type 0) (param i32 i32) (result i32) i32.add end))"#,
+                Err("trying to pop more values than pushed"),
+            ),
+            // calls
+            (
+                r#"(module (func $f (param i32 i32) (result i32) local.get 0) (func (result i32) i32.const 1 block (result i32) unreachable call $f end call $f))"#,
+                Ok(4),
+            ),
+        ];
+
+        let legacy = DefaultStackCostModel {
+            value_stack: ValueStack::Legacy,
+            ..Default::default()
+        };
+        for (source, expected) in cases {
+            let module = parse_wat(source);
+            let func_idx = module.function_map.len() as u32 - 1;
+            let height = compute(func_idx, &module, &legacy).map_err(|e| e.to_string());
+            assert_eq!(height, expected.map_err(String::from), "{}", source);
+        }
+    }
 }
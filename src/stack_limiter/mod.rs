@@ -1,16 +1,19 @@
 //! Contains the code for the stack height limiter instrumentation.
 
-use crate::utils::{
-    copy_locals,
-    translator::{DefaultTranslator, Translator},
-    ModuleInfo,
+use crate::{
+    utils::{
+        copy_locals,
+        translator::{DefaultTranslator, Translator},
+        ModuleInfo,
+    },
+    InstrumentError,
 };
-use alloc::vec::Vec;
+use alloc::{format, vec::Vec};
 use anyhow::{anyhow, Result};
 use wasm_encoder::{
     CodeSection, ConstExpr, Function, GlobalSection, GlobalType, SectionId, ValType,
 };
-use wasmparser::{CodeSectionReader, FunctionBody, GlobalSectionReader, Operator};
+use wasmparser::{CodeSectionReader, FunctionBody, GlobalSectionReader, Operator, Type};
 
 /// Macro to generate preamble and postamble.
 macro_rules! instrument_call {
@@ -40,9 +43,12 @@ macro_rules! instrument_call {
     }};
 }
 
+mod cost_model;
 mod max_height;
 mod thunk;
 
+pub use cost_model::{DefaultStackCostModel, LocalsCount, StackCostModel, ValueStack};
+
 struct Context {
     stack_height_global_idx: u32,
     func_stack_costs: Vec<u32>,
@@ -102,8 +108,12 @@ impl Context {
 ///
 /// # Stack cost
 ///
-/// Stack cost of the function is calculated as a sum of it's locals
-/// and the maximal height of the value stack.
+/// Stack cost of the function is calculated as a sum of it's locals (including the arguments)
+/// and the maximal height of the value stack, plus a fixed overhead per frame. See
+/// [`inject_with_locals_count`] to count the locals as older versions of this crate did, and
+/// [`inject_with_cost_model`] to weigh the values differently. The stack costs of version 0.4 are
+/// kept by [`inject_with_cost_model`] with a [`DefaultStackCostModel`] setting both
+/// [`LocalsCount::DeclarationGroups`] and [`ValueStack::Legacy`].
 ///
 /// All values are treated equally, as they have the same size.
 ///
@@ -116,11 +126,55 @@ impl Context {
 /// - arguments pushed by the caller are copied into callee stack rather than shared between the
 ///   frames.
 /// - upon entry into the function entire stack frame is allocated.
-pub fn inject(raw_wasm: &[u8], stack_limit: u32) -> Result<Vec<u8>> {
+///
+/// # Errors
+///
+/// Fails with [`InstrumentError::UnsupportedProposal`] if the module uses instructions from a
+/// proposal the stack height of which can't be computed, e.g. SIMD or exception handling.
+pub fn inject(raw_wasm: &[u8], stack_limit: u32) -> Result<Vec<u8>, InstrumentError> {
+    inject_with_locals_count(raw_wasm, stack_limit, LocalsCount::default())
+}
+
+/// Same as [`inject`], but counts the locals of each function as specified by `locals_count`.
+///
+/// The height of the value stack is still computed with [`ValueStack::Typed`], so this alone
+/// doesn't keep the stack costs of version 0.4, see [`inject`].
+pub fn inject_with_locals_count(
+    raw_wasm: &[u8],
+    stack_limit: u32,
+    locals_count: LocalsCount,
+) -> Result<Vec<u8>, InstrumentError> {
+    inject_with_cost_model(
+        raw_wasm,
+        stack_limit,
+        &DefaultStackCostModel {
+            locals_count,
+            ..Default::default()
+        },
+    )
+}
+
+/// Same as [`inject`], but computes the stack cost of each function with the given `model`,
+/// which allows to match the native stack consumption of a particular engine more closely.
+///
+/// `stack_limit` is expressed in the units of the model.
+pub fn inject_with_cost_model<M: StackCostModel + ?Sized>(
+    raw_wasm: &[u8],
+    stack_limit: u32,
+    model: &M,
+) -> Result<Vec<u8>, InstrumentError> {
+    inject_stack_limiter(raw_wasm, stack_limit, model).map_err(InstrumentError::from)
+}
+
+fn inject_stack_limiter<M: StackCostModel + ?Sized>(
+    raw_wasm: &[u8],
+    stack_limit: u32,
+    model: &M,
+) -> Result<Vec<u8>> {
     let mut module_info = ModuleInfo::new(raw_wasm)?;
     let mut ctx = Context {
         stack_height_global_idx: generate_stack_height_global(&mut module_info)?,
-        func_stack_costs: compute_stack_costs(&module_info)?,
+        func_stack_costs: compute_stack_costs(&module_info, model)?,
         stack_limit,
     };
 
@@ -152,13 +206,17 @@ fn generate_stack_height_global(module: &mut ModuleInfo) -> Result<u32> {
         &ConstExpr::i32_const(0),
     );
     module.replace_section(SectionId::Global.into(), &global_sec_builder)?;
-    Ok(index)
+    // Imported globals come first in the global index space.
+    Ok(module.num_imported_globals() + index)
 }
 
 /// Calculate stack costs for all functions.
 ///
 /// Returns a vector with a stack cost for each function, including imports.
-fn compute_stack_costs(module: &ModuleInfo) -> Result<Vec<u32>> {
+fn compute_stack_costs<M: StackCostModel + ?Sized>(
+    module: &ModuleInfo,
+    model: &M,
+) -> Result<Vec<u32>> {
     let func_imports = module.num_imported_functions();
 
     // TODO: optimize!
@@ -168,16 +226,20 @@ fn compute_stack_costs(module: &ModuleInfo) -> Result<Vec<u32>> {
                 // We can't calculate stack_cost of the import functions.
                 Ok(0)
             } else {
-                compute_stack_cost(func_idx as u32, module)
+                compute_stack_cost(func_idx as u32, module, model)
             }
         })
         .collect()
 }
 
-/// Stack cost of the given *defined* function is the sum of it's locals count (that is,
-/// number of arguments plus number of local variables) and the maximal stack
-/// height.
-fn compute_stack_cost(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
+/// Stack cost of the given *defined* function is the sum of the cost of it's locals (that is,
+/// arguments plus local variables, see [`StackCostModel::locals_cost`]) and the maximal
+/// stack height, both weighted by the `model`.
+fn compute_stack_cost<M: StackCostModel + ?Sized>(
+    func_idx: u32,
+    module: &ModuleInfo,
+    model: &M,
+) -> Result<u32> {
     // To calculate the cost of a function we need to convert index from
     // function index space to defined function spaces.
     let func_imports = module.num_imported_functions();
@@ -194,19 +256,24 @@ fn compute_stack_cost(func_idx: u32, module: &ModuleInfo) -> Result<u32> {
         0,
     )?;
 
-    let local_reader = code_section_reader
+    let locals = code_section_reader
         .into_iter()
         .collect::<wasmparser::Result<Vec<FunctionBody>>>()?
         .get(defined_func_idx as usize)
         .ok_or_else(|| anyhow!("function body is out of bounds"))?
-        .get_locals_reader()?;
+        .get_locals_reader()?
+        .into_iter()
+        .collect::<wasmparser::Result<Vec<_>>>()?;
 
-    let locals_count: u32 = local_reader.get_count();
-    let max_stack_height = max_height::compute(defined_func_idx, module)?;
+    let Type::Func(func_type) = module.get_functype_idx(func_idx)?;
+    let locals_cost = model
+        .locals_cost(func_type.params(), &locals)
+        .ok_or_else(|| anyhow!("overflow in counting locals"))?;
+    let max_stack_height = max_height::compute(defined_func_idx, module, model)?;
 
-    locals_count
+    locals_cost
         .checked_add(max_stack_height)
-        .ok_or_else(|| anyhow!("overflow in adding locals_count and max_stack_height"))
+        .ok_or_else(|| anyhow!("overflow in adding locals_cost and max_stack_height"))
 }
 
 fn instrument_functions(ctx: &mut Context, module: &mut ModuleInfo) -> Result<()> {
@@ -312,7 +379,11 @@ fn instrument_function(ctx: &mut Context, func: FunctionBody) -> Result<wasm_enc
         if did_instrument {
             call_peeker.next();
         } else {
-            func_code_builder.instruction(&DefaultTranslator.translate_op(&instr)?);
+            func_code_builder.instruction(
+                &DefaultTranslator
+                    .translate_op(&instr)
+                    .map_err(|_| InstrumentError::UnsupportedProposal(format!("{:?}", instr)))?,
+            );
         }
     }
 
@@ -348,4 +419,112 @@ mod tests {
         let inject_raw_wasm = inject(&raw_wasm, 1024).expect("Failed to inject stack counter");
         wasmparser::validate(&inject_raw_wasm).expect("Invalid module");
     }
+
+    #[test]
+    fn test_imported_globals() {
+        let raw_wasm = parse_wat(
+            r#"(module
+                (import "env" "a" (global i64))
+                (import "env" "b" (global (mut i32)))
+                (global i64 (i64.const 0))
+                (func $f)
+                (func call $f)
+            )"#,
+        )
+        .bytes();
+
+        let inject_raw_wasm = inject(&raw_wasm, 1024).expect("Failed to inject stack counter");
+        wasmparser::validate(&inject_raw_wasm).expect("Invalid module");
+
+        // The stack height global comes after the two imported globals and the defined one. Counting
+        // only the defined globals would give index 1, the mutable `i32` import "b", which passes
+        // validation but would be clobbered.
+        let module = ModuleInfo::new(&inject_raw_wasm).unwrap();
+        let code_sec = module.raw_sections.get(&SectionId::Code.into()).unwrap();
+        let mut global_indices = Vec::new();
+        for body in CodeSectionReader::new(&code_sec.data, 0).unwrap() {
+            for op in body.unwrap().get_operators_reader().unwrap() {
+                match op.unwrap() {
+                    Operator::GlobalGet { global_index } | Operator::GlobalSet { global_index } => {
+                        global_indices.push(global_index)
+                    }
+                    _ => (),
+                }
+            }
+        }
+        assert!(!global_indices.is_empty());
+        assert!(global_indices.iter().all(|&index| index == 3));
+    }
+
+    #[test]
+    fn test_locals_count() {
+        let module = parse_wat(
+            r#"(module
+                (import "env" "f" (func))
+                (func (param i32 i64)
+                    (local i64 i64 i64) (local i32)
+                )
+            )"#,
+        );
+
+        // Both include the activation frame cost of 2.
+        let model = |locals_count| DefaultStackCostModel {
+            locals_count,
+            ..Default::default()
+        };
+        assert_eq!(
+            compute_stack_cost(1, &module, &model(LocalsCount::Individual)).unwrap(),
+            8
+        );
+        assert_eq!(
+            compute_stack_cost(1, &module, &model(LocalsCount::DeclarationGroups)).unwrap(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_custom_cost_model() {
+        struct Model;
+        impl StackCostModel for Model {
+            fn value_cost(&self, ty: wasmparser::ValType) -> u32 {
+                match ty {
+                    wasmparser::ValType::I64 | wasmparser::ValType::F64 => 2,
+                    wasmparser::ValType::V128 => 4,
+                    _ => 1,
+                }
+            }
+
+            fn frame_overhead(&self) -> u32 {
+                10
+            }
+
+            fn call_cost(&self, params: &[wasmparser::ValType], _: &[wasmparser::ValType]) -> u32 {
+                params.iter().map(|ty| self.value_cost(*ty)).sum()
+            }
+        }
+
+        let module = parse_wat(
+            r#"(module
+                (func (param i32 i64))
+                (func (param i64) (local v128)
+                    i64.const 1
+                    local.get 0
+                    i64.add
+                    drop
+                )
+                (func
+                    i32.const 1
+                    i64.const 2
+                    call 0
+                )
+            )"#,
+        );
+
+        // Params and locals: 1 + 2.
+        assert_eq!(compute_stack_cost(0, &module, &Model).unwrap(), 3 + 10);
+        // Locals: 2 + 4, two i64 values on the stack.
+        assert_eq!(compute_stack_cost(1, &module, &Model).unwrap(), 6 + 4 + 10);
+        // The arguments on the stack plus the call cost of the same size.
+        assert_eq!(compute_stack_cost(2, &module, &Model).unwrap(), 3 + 3 + 10);
+    }
 }
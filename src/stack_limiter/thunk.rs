@@ -5,7 +5,7 @@ use crate::utils::{
 };
 #[cfg(not(features = "std"))]
 use alloc::collections::BTreeMap as Map;
-use alloc::vec::Vec;
+use alloc::{format, string::String, vec::Vec};
 use anyhow::{anyhow, Result};
 #[cfg(features = "std")]
 use std::collections::HashMap as Map;
@@ -120,6 +120,7 @@ pub(super) fn generate_thunks(ctx: &mut Context, module: &mut ModuleInfo) -> Res
     }
 
     let mut next_func_idx = module.function_map.len() as u32;
+    let mut thunk_names: Vec<(u32, String)> = Vec::new();
     for (func_idx, thunk) in replacement_map.iter_mut() {
         // Thunk body consist of:
         //  - argument pushing
@@ -150,6 +151,13 @@ pub(super) fn generate_thunks(ctx: &mut Context, module: &mut ModuleInfo) -> Res
         func_body_sec_builder.function(&thunk_body); //add thunk body
 
         thunk.idx = Some(next_func_idx);
+        thunk_names.push((
+            next_func_idx,
+            match module.func_name(*func_idx) {
+                Some(name) => format!("{}_thunk", name),
+                None => format!("thunk_{}", func_idx),
+            },
+        ));
         next_func_idx += 1;
     }
 
@@ -231,6 +239,8 @@ pub(super) fn generate_thunks(ctx: &mut Context, module: &mut ModuleInfo) -> Res
     module.replace_section(SectionId::Code.into(), &func_body_sec_builder)?;
     module.replace_section(SectionId::Export.into(), &export_sec_builder)?;
     module.replace_section(SectionId::Element.into(), &ele_sec_builder)?;
+    thunk_names.sort_by_key(|(idx, _)| *idx);
+    module.update_func_names(|idx| idx, &thunk_names)?;
     if let Some(start_idx) = module.start_function {
         let mut new_func_idx = start_idx;
         if let Some(thunk) = replacement_map.get(&start_idx) {
@@ -8,9 +8,19 @@ use std::{
 
 use wasm_encoder::{Encode, SectionId};
 use wasmparser::{
-    Chunk, ExternalKind, GlobalType, MemoryType, Parser, Payload, SectionReader, TableType, Type,
+    BinaryReader, Chunk, ExternalKind, GlobalType, MemoryType, Parser, Payload, SectionReader,
+    TableType, Type,
 };
 
+/// Name of the custom section holding debug names.
+const NAME_SECTION: &str = "name";
+/// Id of the function names subsection of the `name` section.
+const FUNCTION_NAMES: u8 = 1;
+/// Ids of the subsections of the `name` section which map function indices to name maps.
+const INDIRECT_FUNCTION_NAMES: [u8; 2] = [2, 3];
+/// Id of the global names subsection of the `name` section.
+const GLOBAL_NAMES: u8 = 7;
+
 #[derive(Clone, Debug)]
 pub struct RawSection {
     /// The id for this section.
@@ -43,6 +53,26 @@ impl wasm_encoder::ComponentSection for RawSection {
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct CustomSection {
+    /// Id of the non-custom section this section followed in the input Wasm, `None` if it
+    /// preceded all of them.
+    pub after: Option<u8>,
+    /// The name of this section.
+    pub name: String,
+    /// The contents of this section, without the name.
+    pub data: Vec<u8>,
+}
+
+impl CustomSection {
+    fn raw(&self) -> RawSection {
+        let mut data = Vec::new();
+        self.name.encode(&mut data);
+        data.extend_from_slice(&self.data);
+        RawSection::new(SectionId::Custom.into(), data)
+    }
+}
+
 /// Provides module information for future usage during mutation
 /// an instance of ModuleInfo could be user to determine which mutation could be applied
 #[derive(Default, Clone, Debug)]
@@ -79,6 +109,10 @@ pub struct ModuleInfo {
 
     // raw_sections
     pub raw_sections: BTreeMap<u8, RawSection>,
+    // custom sections in the order of the input Wasm
+    pub custom_sections: Vec<CustomSection>,
+    // id of the last non-custom section registered
+    last_section_id: Option<u8>,
 }
 
 impl ModuleInfo {
@@ -210,7 +244,11 @@ impl ModuleInfo {
                     info.section(SectionId::Data.into(), reader.range(), input_wasm);
                 }
                 Payload::CustomSection(c) => {
-                    info.section(SectionId::Custom.into(), c.range(), input_wasm);
+                    info.custom_sections.push(CustomSection {
+                        after: info.last_section_id,
+                        name: c.name().into(),
+                        data: c.data().to_vec(),
+                    });
                 }
                 Payload::UnknownSection {
                     id,
@@ -236,6 +274,7 @@ impl ModuleInfo {
     pub fn section(&mut self, id: u8, range: Range<usize>, full_wasm: &[u8]) {
         self.raw_sections
             .insert(id, RawSection::new(id, full_wasm[range].to_vec()));
+        self.last_section_id = Some(id);
     }
 
     /// Returns the function type based on the index of the function type
@@ -337,7 +376,6 @@ impl ModuleInfo {
         let mut module = wasm_encoder::Module::new();
 
         let section_order = [
-            SectionId::Custom,
             SectionId::Type,
             SectionId::Import,
             SectionId::Function,
@@ -353,14 +391,136 @@ impl ModuleInfo {
             SectionId::Tag,
         ];
 
+        // Custom sections are emitted right after the section they followed in the input Wasm.
+        let emit_custom_sections = |module: &mut wasm_encoder::Module, after: Option<u8>| {
+            for custom in self.custom_sections.iter().filter(|c| c.after == after) {
+                module.section(&custom.raw());
+            }
+        };
+
+        emit_custom_sections(&mut module, None);
         for s in section_order {
             if let Some(sec) = self.raw_sections.get(&s.into()) {
                 module.section(sec);
             }
+            emit_custom_sections(&mut module, Some(s.into()));
+        }
+        // Sections we don't know about are dropped, keep what followed them at the end.
+        for custom in self.custom_sections.iter().filter(|c| {
+            c.after.map_or(false, |id| {
+                !section_order.iter().any(|s| u8::from(*s) == id)
+            })
+        }) {
+            module.section(&custom.raw());
         }
         module.finish()
     }
 
+    /// Returns the name of the function `idx` recorded in the `name` section, if any.
+    pub fn func_name(&self, idx: u32) -> Option<String> {
+        let names = self
+            .custom_sections
+            .iter()
+            .find(|c| c.name == NAME_SECTION)?;
+        let subsections = read_name_subsections(&names.data).ok()?;
+        let (_, data) = subsections.iter().find(|(id, _)| *id == FUNCTION_NAMES)?;
+        let name_map = read_name_map(&mut BinaryReader::new(data)).ok()?;
+        name_map
+            .into_iter()
+            .find(|(func_idx, _)| *func_idx == idx)
+            .map(|(_, name)| name)
+    }
+
+    /// Updates the function indices used in the `name` section after functions have been
+    /// inserted: the names of function `idx` are moved to `remap(idx)`. The `new_names` of the
+    /// inserted functions are recorded as well.
+    ///
+    /// Does nothing if the module has no `name` section.
+    pub fn update_func_names(
+        &mut self,
+        remap: impl Fn(u32) -> u32,
+        new_names: &[(u32, String)],
+    ) -> Result<()> {
+        self.update_names(FUNCTION_NAMES, &INDIRECT_FUNCTION_NAMES, remap, new_names)
+    }
+
+    /// Same as [`ModuleInfo::update_func_names`], for globals.
+    pub fn update_global_names(
+        &mut self,
+        remap: impl Fn(u32) -> u32,
+        new_names: &[(u32, String)],
+    ) -> Result<()> {
+        self.update_names(GLOBAL_NAMES, &[], remap, new_names)
+    }
+
+    /// Remaps the indices of the name map subsection `direct` and the indirect name map
+    /// subsections `indirect` of the `name` section, and adds `new_names` to `direct`.
+    fn update_names(
+        &mut self,
+        direct: u8,
+        indirect: &[u8],
+        remap: impl Fn(u32) -> u32,
+        new_names: &[(u32, String)],
+    ) -> Result<()> {
+        let names = match self
+            .custom_sections
+            .iter_mut()
+            .find(|c| c.name == NAME_SECTION)
+        {
+            Some(names) => names,
+            None => return Ok(()),
+        };
+
+        let mut subsections = read_name_subsections(&names.data)?;
+        if !new_names.is_empty() && !subsections.iter().any(|(id, _)| *id == direct) {
+            let pos = subsections
+                .iter()
+                .position(|(id, _)| *id > direct)
+                .unwrap_or(subsections.len());
+            subsections.insert(pos, (direct, Vec::new()));
+        }
+
+        for (id, data) in subsections.iter_mut() {
+            if *id == direct {
+                let mut name_map = if data.is_empty() {
+                    Vec::new()
+                } else {
+                    read_name_map(&mut BinaryReader::new(data))?
+                };
+                for (idx, _) in name_map.iter_mut() {
+                    *idx = remap(*idx);
+                }
+                name_map.extend(new_names.iter().cloned());
+                name_map.sort_by_key(|(idx, _)| *idx);
+
+                data.clear();
+                encode_name_map(&name_map, data);
+            } else if indirect.contains(id) {
+                let mut reader = BinaryReader::new(data);
+                let mut indirect_map = Vec::new();
+                for _ in 0..reader.read_var_u32()? {
+                    let idx = reader.read_var_u32()?;
+                    indirect_map.push((remap(idx), read_name_map(&mut reader)?));
+                }
+                indirect_map.sort_by_key(|(idx, _)| *idx);
+
+                data.clear();
+                (indirect_map.len() as u32).encode(data);
+                for (idx, name_map) in indirect_map {
+                    idx.encode(data);
+                    encode_name_map(&name_map, data);
+                }
+            }
+        }
+
+        names.data.clear();
+        for (id, data) in subsections {
+            names.data.push(id);
+            data.encode(&mut names.data);
+        }
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub fn num_functions(&self) -> u32 {
         self.function_map.len() as u32
@@ -442,6 +602,35 @@ impl ModuleInfo {
     }
 }
 
+/// Splits the contents of a `name` section into its subsections.
+fn read_name_subsections(data: &[u8]) -> Result<Vec<(u8, Vec<u8>)>> {
+    let mut reader = BinaryReader::new(data);
+    let mut subsections = Vec::new();
+    while !reader.eof() {
+        let id = reader.read_u8()?;
+        let size = reader.read_var_u32()?;
+        subsections.push((id, reader.read_bytes(size as usize)?.to_vec()));
+    }
+    Ok(subsections)
+}
+
+fn read_name_map(reader: &mut BinaryReader) -> Result<Vec<(u32, String)>> {
+    let mut name_map = Vec::new();
+    for _ in 0..reader.read_var_u32()? {
+        let idx = reader.read_var_u32()?;
+        name_map.push((idx, reader.read_string()?.into()));
+    }
+    Ok(name_map)
+}
+
+fn encode_name_map(name_map: &[(u32, String)], sink: &mut Vec<u8>) {
+    (name_map.len() as u32).encode(sink);
+    for (idx, name) in name_map {
+        idx.encode(sink);
+        name.encode(sink);
+    }
+}
+
 // Then insert metering calls into a sequence of instructions given the block locations and costs.
 pub fn copy_locals(
     func_body: &wasmparser::FunctionBody,
@@ -466,3 +655,79 @@ pub fn truncate_len_from_encoder(func_builder: &dyn wasm_encoder::Encode) -> Res
     let size = r.read_var_u32()?;
     Ok(r.read_bytes(size as usize)?.to_vec())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_encoder::{CodeSection, Function, FunctionSection, Instruction, TypeSection};
+
+    fn custom(name: &str, data: &[u8]) -> RawSection {
+        CustomSection {
+            after: None,
+            name: name.into(),
+            data: data.to_vec(),
+        }
+        .raw()
+    }
+
+    #[test]
+    fn custom_sections_keep_their_position() {
+        let mut module = wasm_encoder::Module::new();
+        module.section(&custom("a", b"1"));
+        let mut types = TypeSection::new();
+        types.function([], []);
+        module.section(&types);
+        module.section(&custom("b", b"2"));
+        let mut funcs = FunctionSection::new();
+        funcs.function(0);
+        module.section(&funcs);
+        let mut code = CodeSection::new();
+        let mut func = Function::new(vec![]);
+        func.instruction(&Instruction::End);
+        code.function(&func);
+        module.section(&code);
+        module.section(&custom("c", b"3"));
+        module.section(&custom("b", b"4"));
+        let wasm = module.finish();
+
+        let info = ModuleInfo::new(&wasm).unwrap();
+        assert_eq!(info.custom_sections.len(), 4);
+        assert_eq!(info.bytes(), wasm);
+    }
+
+    #[test]
+    fn update_func_names() {
+        let wasm = wat::parse_str(
+            r#"(module
+                (func $f (param $x i32))
+                (func $g (param $y i32) (local $z i32))
+            )"#,
+        )
+        .unwrap();
+        let mut info = ModuleInfo::new(&wasm).unwrap();
+        assert_eq!(info.func_name(0).as_deref(), Some("f"));
+
+        info.update_func_names(|idx| idx + 1, &[(0, "h".into())])
+            .unwrap();
+        assert_eq!(info.func_name(0).as_deref(), Some("h"));
+        assert_eq!(info.func_name(1).as_deref(), Some("f"));
+        assert_eq!(info.func_name(2).as_deref(), Some("g"));
+
+        let names = info
+            .custom_sections
+            .iter()
+            .find(|c| c.name == NAME_SECTION)
+            .unwrap();
+        let subsections = read_name_subsections(&names.data).unwrap();
+        let (_, locals) = subsections.iter().find(|(id, _)| *id == 2).unwrap();
+        let mut reader = BinaryReader::new(locals);
+        assert_eq!(reader.read_var_u32().unwrap(), 2);
+        assert_eq!(reader.read_var_u32().unwrap(), 1);
+        assert_eq!(read_name_map(&mut reader).unwrap(), [(0, "x".into())]);
+        assert_eq!(reader.read_var_u32().unwrap(), 2);
+        assert_eq!(
+            read_name_map(&mut reader).unwrap(),
+            [(0, "y".into()), (1, "z".into())]
+        );
+    }
+}
@@ -115,6 +115,11 @@ pub trait Translator {
     fn translate_memarg(&self, arg: &wasmparser::MemArg) -> Result<MemArg> {
         memarg(self.as_obj(), arg)
     }
+
+    /// Maps the index of an item referenced by the module to its index in the output module.
+    fn remap(&self, _item: Item, idx: u32) -> Result<u32> {
+        Ok(idx)
+    }
 }
 
 pub struct DefaultTranslator;
@@ -234,7 +239,18 @@ pub fn export(
     e: &wasmparser::Export<'_>,
     sec: &mut wasm_encoder::ExportSection,
 ) -> Result<()> {
-    sec.export(e.name, t.translate_export_kind(e.kind)?, e.index);
+    let item = match e.kind {
+        ExternalKind::Func => Item::Function,
+        ExternalKind::Table => Item::Table,
+        ExternalKind::Memory => Item::Memory,
+        ExternalKind::Global => Item::Global,
+        ExternalKind::Tag => Item::Tag,
+    };
+    sec.export(
+        e.name,
+        t.translate_export_kind(e.kind)?,
+        t.remap(item, e.index)?,
+    );
     Ok(())
 }
 
@@ -245,7 +261,7 @@ pub fn const_expr(
 ) -> Result<wasm_encoder::ConstExpr> {
     let mut e = e.get_operators_reader();
     let mut offset_bytes = Vec::new();
-    let op = e.read()?;
+    let mut op = e.read()?;
     if let ConstExprKind::ElementFunction = ctx {
         match op {
             Operator::RefFunc { .. }
@@ -257,10 +273,13 @@ pub fn const_expr(
             _ => return Err(anyhow!("no_mutations_applicable")),
         }
     }
-    t.translate_op(&op)?.encode(&mut offset_bytes);
-    match e.read()? {
-        Operator::End if e.eof() => {}
-        _ => return Err(anyhow!("no_mutations_applicable")),
+    // Copy everything up to the final `end`, which `ConstExpr` adds back on its own.
+    while !matches!(op, Operator::End) {
+        t.translate_op(&op)?.encode(&mut offset_bytes);
+        op = e.read()?;
+    }
+    if !e.eof() {
+        return Err(anyhow!("no_mutations_applicable"));
     }
     Ok(wasm_encoder::ConstExpr::raw(offset_bytes))
 }
@@ -297,7 +316,7 @@ pub fn element(
     for _ in 0..reader.get_count() {
         match reader.read()? {
             ElementItem::Func(idx) => {
-                functions.push(idx);
+                functions.push(t.remap(Item::Function, idx)?);
             }
             ElementItem::Expr(expr) => {
                 exprs.push(t.translate_const_expr(
@@ -352,7 +371,7 @@ pub fn op(t: &dyn Translator, op: &Operator<'_>) -> Result<Instruction<'static>>
         ),
 
         O::Return => I::Return,
-        O::Call { function_index } => I::Call(*function_index),
+        O::Call { function_index } => I::Call(t.remap(Item::Function, *function_index)?),
         O::CallIndirect {
             type_index,
             table_index,
@@ -371,8 +390,8 @@ pub fn op(t: &dyn Translator, op: &Operator<'_>) -> Result<Instruction<'static>>
         O::LocalSet { local_index } => I::LocalSet(*local_index),
         O::LocalTee { local_index } => I::LocalTee(*local_index),
 
-        O::GlobalGet { global_index } => I::GlobalGet(*global_index),
-        O::GlobalSet { global_index } => I::GlobalSet(*global_index),
+        O::GlobalGet { global_index } => I::GlobalGet(t.remap(Item::Global, *global_index)?),
+        O::GlobalSet { global_index } => I::GlobalSet(t.remap(Item::Global, *global_index)?),
 
         O::I32Load { memarg } => I::I32Load(t.translate_memarg(memarg)?),
         O::I64Load { memarg } => I::I64Load(t.translate_memarg(memarg)?),
@@ -408,7 +427,7 @@ pub fn op(t: &dyn Translator, op: &Operator<'_>) -> Result<Instruction<'static>>
 
         O::RefNull { ty } => I::RefNull(t.translate_ty(ty)?),
         O::RefIsNull => I::RefIsNull,
-        O::RefFunc { function_index } => I::RefFunc(*function_index),
+        O::RefFunc { function_index } => I::RefFunc(t.remap(Item::Function, *function_index)?),
 
         O::I32Eqz => I::I32Eqz,
         O::I32Eq => I::I32Eq,
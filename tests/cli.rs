@@ -0,0 +1,54 @@
+#![cfg(feature = "cli")]
+
+use std::{path::PathBuf, process::Command};
+
+fn fixture_path() -> PathBuf {
+    let mut fixture_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    fixture_path.push("tests");
+    fixture_path.push("fixtures");
+    fixture_path.push("gas");
+    fixture_path.push("call.wat");
+    fixture_path
+}
+
+#[test]
+fn instrument_all() {
+    let fixture_path = fixture_path();
+    let output_path = std::env::temp_dir().join(format!(
+        "fvm-wasm-instrument-cli-test-{}.wasm",
+        std::process::id()
+    ));
+    let output = Command::new(env!("CARGO_BIN_EXE_fvm-wasm-instrument"))
+        .arg("all")
+        .arg(&fixture_path)
+        .args(["--stack-limit", "1024", "--report", "-", "--output"])
+        .arg(&output_path)
+        .output()
+        .expect("Failed to run the command line tool");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let instrumented = std::fs::read(&output_path).unwrap();
+    std::fs::remove_file(&output_path).unwrap();
+    wasmparser::validate(&instrumented).expect("Invalid module");
+
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["output_size"], instrumented.len());
+    assert_eq!(report["stack_limit"], 1024);
+    assert_eq!(report["gas"]["functions"].as_array().unwrap().len(), 2);
+}
+
+#[test]
+fn report_and_module_to_stdout() {
+    let output = Command::new(env!("CARGO_BIN_EXE_fvm-wasm-instrument"))
+        .arg("gas")
+        .arg(fixture_path())
+        .args(["--report", "-"])
+        .output()
+        .expect("Failed to run the command line tool");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
@@ -89,6 +89,50 @@ mod stack_height {
     def_stack_height_test!(empty_functions);
 }
 
+mod stack_height_legacy {
+    use super::*;
+    use stack_limiter::{DefaultStackCostModel, LocalsCount, ValueStack};
+
+    // The expected modules were instrumented by version 0.4, the cost model of version 0.4
+    // must reproduce them byte for byte.
+    macro_rules! def_stack_height_legacy_test {
+        ( $name:ident ) => {
+            #[test]
+            fn $name() {
+                let name = concat!(stringify!($name), ".wasm");
+                let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+                let fixture = slurp(root.join("benches").join("fixtures").join(name))
+                    .expect("Failed to read fixture");
+                let expected = slurp(
+                    root.join("tests")
+                        .join("expectations")
+                        .join("stack-height-legacy")
+                        .join(name),
+                )
+                .expect("Failed to read expected");
+
+                let model = DefaultStackCostModel {
+                    locals_count: LocalsCount::DeclarationGroups,
+                    value_stack: ValueStack::Legacy,
+                };
+                let actual = stack_limiter::inject_with_cost_model(&fixture, 1024, &model).unwrap();
+                assert!(actual == expected, "{} differs from version 0.4", name);
+            }
+        };
+    }
+
+    def_stack_height_legacy_test!(contract_terminate);
+    def_stack_height_legacy_test!(contract_transfer);
+    def_stack_height_legacy_test!(dns);
+    def_stack_height_legacy_test!(erc1155);
+    def_stack_height_legacy_test!(erc20);
+    def_stack_height_legacy_test!(erc721);
+    def_stack_height_legacy_test!(multisig);
+    def_stack_height_legacy_test!(proxy);
+    def_stack_height_legacy_test!(rand_extension);
+    def_stack_height_legacy_test!(trait_erc20);
+}
+
 mod gas {
     use super::*;
 
@@ -109,4 +153,5 @@ mod gas {
     def_gas_test!(start);
     def_gas_test!(call);
     def_gas_test!(branch);
+    def_gas_test!(br_table);
 }
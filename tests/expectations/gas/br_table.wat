@@ -0,0 +1,31 @@
+(module
+  (type (;0;) (func (param i32) (result i32)))
+  (type (;1;) (func (param i64)))
+  (import "env" "gas_counter" (global $gas_counter (;0;) (mut i64)))
+  (func $select (;0;) (type 0) (param $x i32) (result i32)
+    (local $y i32)
+    i64.const 7
+    call $charge_gas
+    block ;; label = @1
+      block ;; label = @2
+        local.get $x
+        br_table 0 (;@2;) 1 (;@1;)
+      end
+      i32.const 10
+      local.set $y
+    end
+    local.get $y
+  )
+  (func $charge_gas (;1;) (type 1) (param i64)
+    global.get $gas_counter
+    local.get 0
+    i64.sub
+    global.set $gas_counter
+    global.get $gas_counter
+    i64.const 0
+    i64.lt_s
+    if ;; label = @1
+      unreachable
+    end
+  )
+)
\ No newline at end of file
@@ -1,11 +1,11 @@
 (module
   (type (;0;) (func (result i32)))
   (type (;1;) (func (param i64)))
-  (import "env" "gas_counter" (global (;0;) (mut i64)))
+  (import "env" "gas_counter" (global $gas_counter (;0;) (mut i64)))
   (func $fibonacci_with_break (;0;) (type 0) (result i32)
     (local $x i32) (local $y i32)
     i64.const 13
-    call 1
+    call $charge_gas
     block $unrolled_loop ;; label = @1
       i32.const 0
       local.set $x
@@ -19,7 +19,7 @@
       i32.const 1
       br_if 0 (;@1;)
       i64.const 5
-      call 1
+      call $charge_gas
       local.get $x
       local.get $y
       local.tee $x
@@ -28,12 +28,12 @@
     end
     local.get $y
   )
-  (func (;1;) (type 1) (param i64)
-    global.get 0
+  (func $charge_gas (;1;) (type 1) (param i64)
+    global.get $gas_counter
     local.get 0
     i64.sub
-    global.set 0
-    global.get 0
+    global.set $gas_counter
+    global.get $gas_counter
     i64.const 0
     i64.lt_s
     if ;; label = @1
@@ -1,11 +1,11 @@
 (module
   (type (;0;) (func (param i32 i32) (result i32)))
   (type (;1;) (func (param i64)))
-  (import "env" "gas_counter" (global (;0;) (mut i64)))
+  (import "env" "gas_counter" (global $gas_counter (;0;) (mut i64)))
   (func $add_locals (;0;) (type 0) (param $x i32) (param $y i32) (result i32)
     (local $t i32)
     i64.const 5
-    call 2
+    call $charge_gas
     local.get $x
     local.get $y
     call $add
@@ -14,17 +14,17 @@
   )
   (func $add (;1;) (type 0) (param $x i32) (param $y i32) (result i32)
     i64.const 3
-    call 2
+    call $charge_gas
     local.get $x
     local.get $y
     i32.add
   )
-  (func (;2;) (type 1) (param i64)
-    global.get 0
+  (func $charge_gas (;2;) (type 1) (param i64)
+    global.get $gas_counter
     local.get 0
     i64.sub
-    global.set 0
-    global.get 0
+    global.set $gas_counter
+    global.get $gas_counter
     i64.const 0
     i64.lt_s
     if ;; label = @1
@@ -4,22 +4,22 @@
   (type (;2;) (func (param i64)))
   (import "env" "ext_return" (func $ext_return (;0;) (type 0)))
   (import "env" "memory" (memory (;0;) 1 1))
-  (import "env" "gas_counter" (global (;0;) (mut i64)))
+  (import "env" "gas_counter" (global $gas_counter (;0;) (mut i64)))
   (func $start (;1;) (type 1)
     i64.const 4
-    call 3
+    call $charge_gas
     i32.const 8
     i32.const 4
     call $ext_return
     unreachable
   )
   (func (;2;) (type 1))
-  (func (;3;) (type 2) (param i64)
-    global.get 0
+  (func $charge_gas (;3;) (type 2) (param i64)
+    global.get $gas_counter
     local.get 0
     i64.sub
-    global.set 0
-    global.get 0
+    global.set $gas_counter
+    global.get $gas_counter
     i64.const 0
     i64.lt_s
     if ;; label = @1
@@ -18,7 +18,7 @@
     local.get $tmp
     local.get $arg
     global.get 1
-    i32.const 4
+    i32.const 6
     i32.add
     global.set 1
     global.get 1
@@ -29,16 +29,16 @@
     end
     call $i32.add
     global.get 1
-    i32.const 4
+    i32.const 6
     i32.sub
     global.set 1
     drop
   )
-  (func (;3;) (type 1) (param i32 i32) (result i32)
+  (func $i32.add_thunk (;3;) (type 1) (param i32 i32) (result i32)
     local.get 0
     local.get 1
     global.get 1
-    i32.const 4
+    i32.const 6
     i32.add
     global.set 1
     global.get 1
@@ -49,11 +49,11 @@
     end
     call $i32.add
     global.get 1
-    i32.const 4
+    i32.const 6
     i32.sub
     global.set 1
   )
   (global $counter (;0;) (mut i32) i32.const 1)
   (global (;1;) (mut i32) i32.const 0)
-  (export "i32.add" (func 3))
+  (export "i32.add" (func $i32.add_thunk))
 )
\ No newline at end of file
@@ -10,11 +10,11 @@
     local.get 1
     i32.add
   )
-  (func (;3;) (type 1) (param i32 i32) (result i32)
+  (func $thunk_2 (;3;) (type 1) (param i32 i32) (result i32)
     local.get 0
     local.get 1
     global.get 0
-    i32.const 4
+    i32.const 6
     i32.add
     global.set 0
     global.get 0
@@ -25,10 +25,10 @@
     end
     call 2
     global.get 0
-    i32.const 4
+    i32.const 6
     i32.sub
     global.set 0
   )
   (global (;0;) (mut i32) i32.const 0)
-  (export "i32.add" (func 3))
+  (export "i32.add" (func $thunk_2))
 )
\ No newline at end of file
@@ -7,7 +7,7 @@
     (local i32)
   )
   (func (;2;) (type 1))
-  (func (;3;) (type 1)
+  (func $start_thunk (;3;) (type 1)
     global.get 0
     i32.const 3
     i32.add
@@ -24,7 +24,7 @@
     i32.sub
     global.set 0
   )
-  (func (;4;) (type 1)
+  (func $thunk_2 (;4;) (type 1)
     global.get 0
     i32.const 2
     i32.add
@@ -42,6 +42,6 @@
     global.set 0
   )
   (global (;0;) (mut i32) i32.const 0)
-  (export "call" (func 4))
-  (start 3)
+  (export "call" (func $thunk_2))
+  (start $start_thunk)
 )
\ No newline at end of file
@@ -7,7 +7,7 @@
     local.get 0
     i32.const 0
     global.get 0
-    i32.const 4
+    i32.const 6
     i32.add
     global.set 0
     global.get 0
@@ -18,7 +18,7 @@
     end
     call $i32.add
     global.get 0
-    i32.const 4
+    i32.const 6
     i32.sub
     global.set 0
     drop
@@ -28,10 +28,10 @@
     local.get 1
     i32.add
   )
-  (func (;3;) (type 1) (param i32)
+  (func $thunk_1 (;3;) (type 1) (param i32)
     local.get 0
     global.get 0
-    i32.const 4
+    i32.const 5
     i32.add
     global.set 0
     global.get 0
@@ -42,15 +42,15 @@
     end
     call 1
     global.get 0
-    i32.const 4
+    i32.const 5
     i32.sub
     global.set 0
   )
-  (func (;4;) (type 2) (param i32 i32) (result i32)
+  (func $i32.add_thunk (;4;) (type 2) (param i32 i32) (result i32)
     local.get 0
     local.get 1
     global.get 0
-    i32.const 4
+    i32.const 6
     i32.add
     global.set 0
     global.get 0
@@ -61,12 +61,12 @@
     end
     call $i32.add
     global.get 0
-    i32.const 4
+    i32.const 6
     i32.sub
     global.set 0
   )
   (table (;0;) 10 funcref)
   (global (;0;) (mut i32) i32.const 0)
-  (export "i32.add" (func 4))
-  (elem (;0;) (i32.const 0) func $foo 3 4)
+  (export "i32.add" (func $i32.add_thunk))
+  (elem (;0;) (i32.const 0) func $foo $thunk_1 $i32.add_thunk)
 )
\ No newline at end of file
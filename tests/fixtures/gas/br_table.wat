@@ -0,0 +1,18 @@
+(module
+	(func $select (param $x i32) (result i32)
+		(local $y i32)
+
+		(block
+			(block
+				get_local $x
+				br_table 0 1
+			)
+
+			;; only reached through the first target, the default target skips it
+			i32.const 10
+			set_local $y
+		)
+
+		get_local $y
+	)
+)